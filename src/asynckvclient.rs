@@ -0,0 +1,411 @@
+use crate::{
+    cp::*,
+    kvclient::{get_result, rm_result, set_result},
+    KvClientError,
+};
+use mio::{net::TcpStream, Events, Interest, Poll, Token, Waker};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::{self, prelude::*},
+    mem,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{self, Context},
+    thread,
+};
+
+const WAKER_TOKEN: Token = Token(0);
+const CONNECTION_TOKEN: Token = Token(1);
+/// Number of bytes read from the connection at once
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Asynchronous KVS store system tcp client.
+/// The commands are sent over a single non-blocking connection to the server, served by an I/O thread running
+/// a mio event loop, so that any number of commands are waiting for their responses at the same time without
+/// holding a thread each. The futures of the commands do not depend on any runtime, and can be run by any executor.
+/// The connection is opened by the first command, and opened again by the next one once it is closed,
+/// which fails the commands waiting for their responses.
+///
+/// # Examples
+///
+/// ```no_run
+/// use kvs::AsyncKvClient;
+/// # async fn run() {
+/// let client = AsyncKvClient::new("127.0.0.1:4000").unwrap();
+/// client.set("key".to_owned(), "value".to_owned()).await.unwrap();
+/// assert_eq!(client.get("key".to_owned()).await.unwrap(), Some("value".to_owned()));
+/// # }
+/// ```
+pub struct AsyncKvClient {
+    submissions: Arc<Mutex<Submissions>>,
+    waker: Arc<Waker>,
+    io_thread: Option<thread::JoinHandle<()>>,
+}
+
+/// The requests of the commands not yet taken by the I/O thread
+#[derive(Default)]
+struct Submissions {
+    requests: Vec<(Message, Arc<ResponseSlot>)>,
+    /// Set once the I/O thread stopped, or must stop
+    closed: bool,
+}
+
+/// Where the I/O thread puts the response of a command for its future
+#[derive(Default)]
+struct ResponseSlot(Mutex<SlotState>);
+
+#[derive(Default)]
+struct SlotState {
+    result: Option<Result<MessagePayload, KvClientError<'static>>>,
+    waker: Option<task::Waker>,
+}
+
+/// The future of the response to a request, resolved by the I/O thread
+struct ResponseFuture(Arc<ResponseSlot>);
+
+/// The state of the I/O thread
+struct IoLoop {
+    poll: Poll,
+    server_address: SocketAddr,
+    submissions: Arc<Mutex<Submissions>>,
+    connection: Option<Connection>,
+}
+
+/// The open connection to the server, along with the bytes not yet written and the ones not yet framed
+struct Connection {
+    stream: TcpStream,
+    /// Tells if the connection was established, which happens after `TcpStream::connect` returns
+    connected: bool,
+    write_buf: Vec<u8>,
+    read_buf: Vec<u8>,
+    next_request_id: u32,
+    /// The requests sent, or about to be, waiting for their responses
+    in_flight: HashMap<u32, Arc<ResponseSlot>>,
+}
+
+impl AsyncKvClient {
+    /// Creates a new instance of an AsyncKvClient given the server address, and starts its I/O thread.
+    /// No connection is opened until the first command.
+    pub fn new<'a>(server_addr: &'a str) -> Result<Self, KvClientError<'a>> {
+        let server_address = match server_addr.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(err) => {
+                return Err(KvClientError::InvalidServerAddress {
+                    addr: server_addr,
+                    cause: err.to_string(),
+                })
+            }
+        };
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+        let submissions = Arc::new(Mutex::new(Submissions::default()));
+        let io_loop = IoLoop {
+            poll,
+            server_address,
+            submissions: submissions.clone(),
+            connection: None,
+        };
+        let io_thread = thread::Builder::new()
+            .name("kvs-client-io".to_owned())
+            .spawn(move || io_loop.run())?;
+        Ok(Self {
+            submissions,
+            waker,
+            io_thread: Some(io_thread),
+        })
+    }
+
+    /// Sets the `key` to the `value`
+    pub async fn set(&self, key: String, value: String) -> Result<(), KvClientError<'static>> {
+        self.set_bytes(key.into_bytes(), value.into_bytes()).await
+    }
+
+    /// Sets the byte string `key` to the `value`
+    pub async fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), KvClientError<'static>> {
+        set_result(
+            self.send_request(RequestSet::new_message(key, value))
+                .await?,
+        )
+    }
+
+    /// Gets the value of the `key`, or `None` if the key was not found.
+    /// The value must be a valid UTF-8 string, otherwise `KvClientError::InvalidUtf8Value` is returned
+    pub async fn get(&self, key: String) -> Result<Option<String>, KvClientError<'static>> {
+        match self.get_bytes(key.into_bytes()).await? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the value of the byte string `key`, or `None` if the key was not found
+    pub async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, KvClientError<'static>> {
+        get_result(self.send_request(RequestGet::new_message(key)).await?)
+    }
+
+    /// Removes the `key`, or returns `KvClientError::KeyNotFound` if it does not exist
+    pub async fn remove(&self, key: String) -> Result<(), KvClientError<'static>> {
+        self.remove_bytes(key.into_bytes()).await
+    }
+
+    /// Removes the byte string `key`, or returns `KvClientError::KeyNotFound` if it does not exist
+    pub async fn remove_bytes(&self, key: Vec<u8>) -> Result<(), KvClientError<'static>> {
+        rm_result(self.send_request(RequestRemove::new_message(key)).await?)
+    }
+
+    /// Hands the request `msg` to the I/O thread and returns the future of its response
+    fn send_request(&self, msg: Message) -> ResponseFuture {
+        let slot = Arc::new(ResponseSlot::default());
+        {
+            let mut submissions = self.submissions.lock();
+            if submissions.closed {
+                slot.complete(Err(client_closed_error()));
+                return ResponseFuture(slot);
+            }
+            submissions.requests.push((msg, slot.clone()));
+        }
+        if let Err(err) = self.waker.wake() {
+            self.submissions
+                .lock()
+                .requests
+                .retain(|(_, s)| !Arc::ptr_eq(s, &slot));
+            slot.complete(Err(err.into()));
+        }
+        ResponseFuture(slot)
+    }
+}
+
+impl Drop for AsyncKvClient {
+    fn drop(&mut self) {
+        self.submissions.lock().closed = true;
+        let _ = self.waker.wake();
+        if let Some(io_thread) = self.io_thread.take() {
+            let _ = io_thread.join();
+        }
+    }
+}
+
+impl ResponseSlot {
+    /// Stores the `result` and wakes the task waiting for it
+    fn complete(&self, result: Result<MessagePayload, KvClientError<'static>>) {
+        let waker = {
+            let mut state = self.0.lock();
+            state.result = Some(result);
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<MessagePayload, KvClientError<'static>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> task::Poll<Self::Output> {
+        let mut state = (self.0).0.lock();
+        match state.result.take() {
+            Some(result) => task::Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                task::Poll::Pending
+            }
+        }
+    }
+}
+
+impl IoLoop {
+    /// Sends the submitted requests and resolves the futures of the responses received, until the client is dropped
+    fn run(mut self) {
+        let mut events = Events::with_capacity(64);
+        loop {
+            if let Err(err) = self.poll.poll(&mut events, None) {
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                self.stop(&KvClientError::IoError(err));
+                return;
+            }
+            let (requests, closed) = {
+                let mut submissions = self.submissions.lock();
+                (mem::take(&mut submissions.requests), submissions.closed)
+            };
+            if closed {
+                self.stop(&client_closed_error());
+                return;
+            }
+            // Whatever the events, the connection is served until its reads and writes would block
+            if !requests.is_empty() {
+                self.submit(requests);
+            }
+            if let Some(conn) = self.connection.as_mut() {
+                if let Err(err) = conn.serve() {
+                    self.close_connection(&err);
+                }
+            }
+        }
+    }
+
+    /// Queues the `requests` on the connection, opening it first if needed
+    fn submit(&mut self, requests: Vec<(Message, Arc<ResponseSlot>)>) {
+        if self.connection.is_none() {
+            match self.connect() {
+                Ok(conn) => self.connection = Some(conn),
+                Err(err) => {
+                    for (_, slot) in requests {
+                        slot.complete(Err(copy_error(&err)));
+                    }
+                    return;
+                }
+            }
+        }
+        let conn = self.connection.as_mut().expect("the connection is open");
+        for (msg, slot) in requests {
+            let request_id = conn.next_request_id;
+            conn.next_request_id = conn.next_request_id.wrapping_add(1);
+            match conn.queue(&msg.with_request_id(request_id)) {
+                Ok(()) => {
+                    conn.in_flight.insert(request_id, slot);
+                }
+                Err(err) => slot.complete(Err(err.into())),
+            }
+        }
+    }
+
+    /// Starts connecting to the server. The connection is established once the stream is reported writable.
+    fn connect(&mut self) -> Result<Connection, KvClientError<'static>> {
+        let mut stream = TcpStream::connect(self.server_address)?;
+        stream.set_nodelay(true)?;
+        self.poll.registry().register(
+            &mut stream,
+            CONNECTION_TOKEN,
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+        Ok(Connection {
+            stream,
+            connected: false,
+            write_buf: Vec::new(),
+            read_buf: Vec::new(),
+            next_request_id: 0,
+            in_flight: HashMap::new(),
+        })
+    }
+
+    /// Closes the connection, failing with `err` the commands waiting for their responses
+    fn close_connection(&mut self, err: &KvClientError<'static>) {
+        if let Some(mut conn) = self.connection.take() {
+            let _ = self.poll.registry().deregister(&mut conn.stream);
+            for (_, slot) in conn.in_flight.drain() {
+                slot.complete(Err(copy_error(err)));
+            }
+        }
+    }
+
+    /// Fails with `err` every command not yet answered, and the ones submitted from now on
+    fn stop(&mut self, err: &KvClientError<'static>) {
+        self.close_connection(err);
+        let requests = {
+            let mut submissions = self.submissions.lock();
+            submissions.closed = true;
+            mem::take(&mut submissions.requests)
+        };
+        for (_, slot) in requests {
+            slot.complete(Err(copy_error(err)));
+        }
+    }
+}
+
+impl Connection {
+    /// Appends the serialized `msg` to the bytes to write
+    fn queue(&mut self, msg: &Message) -> Result<(), error::Error> {
+        let start = self.write_buf.len();
+        let len = ser::calc_len(msg)?;
+        self.write_buf.resize(start + len, 0);
+        if let Err(err) = ser::to_bytes(msg, &mut self.write_buf[start..]) {
+            self.write_buf.truncate(start);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Writes the queued bytes and reads the available ones until the stream would block,
+    /// then resolves the futures of the complete responses read
+    fn serve(&mut self) -> Result<(), KvClientError<'static>> {
+        if !self.connected {
+            if let Some(err) = self.stream.take_error()? {
+                return Err(err.into());
+            }
+            match self.stream.peer_addr() {
+                Ok(_) => self.connected = true,
+                Err(err) if err.kind() == io::ErrorKind::NotConnected => return Ok(()),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => self.read_buf.extend_from_slice(&chunk[..n]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        while self.read_buf.len() >= HEADER_SIZE {
+            let header: Header = de::from_bytes(&self.read_buf[..HEADER_SIZE])?;
+            let frame_len = HEADER_SIZE + header.payload_length() as usize;
+            if self.read_buf.len() < frame_len {
+                break;
+            }
+            let payload: Result<MessagePayload, error::Error> =
+                de::from_bytes(&self.read_buf[HEADER_SIZE..frame_len]);
+            self.read_buf.drain(..frame_len);
+            match self.in_flight.remove(&header.request_id()) {
+                Some(slot) => slot.complete(payload.map_err(KvClientError::from)),
+                None => return Err(KvClientError::UnexpectedRequestId(header.request_id())),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The error of the commands of a client dropped before they were answered
+fn client_closed_error() -> KvClientError<'static> {
+    KvClientError::IoError(io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "the client was dropped",
+    ))
+}
+
+/// Copies the error `err` of the connection, which fails every command waiting for its response
+fn copy_error(err: &KvClientError<'static>) -> KvClientError<'static> {
+    match err {
+        KvClientError::IoError(err) => {
+            KvClientError::IoError(io::Error::new(err.kind(), err.to_string()))
+        }
+        KvClientError::CommunicationProtocolError(err) => {
+            KvClientError::CommunicationProtocolError(err.clone())
+        }
+        KvClientError::UnexpectedRequestId(request_id) => {
+            KvClientError::UnexpectedRequestId(*request_id)
+        }
+        err => KvClientError::IoError(io::Error::other(err.to_string())),
+    }
+}
@@ -61,6 +61,23 @@ fn main() {
                      .default_value(DEFAULT_SERVER_IP_PORT)
                      .validator(is_valid_address)),
         )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .author(crate_authors!())
+                .version(crate_version!())
+                .about("Write a consistent copy of the server database to a directory of the server, while it keeps running")
+                .arg(Arg::with_name("DIR")
+                     .required(true)
+                     .index(1)
+                     .help("Sets the directory of the copy, which must not exist or be empty. It is resolved relative to the backup directory of the server, and must not be absolute nor reach out of it with '..'"))
+                .arg(Arg::with_name("addr")
+                     .long("addr")
+                     .value_name("IP-PORT")
+                     .help("Sets the server IP address, either v4 or v6, and port number, with the format IP:PORT")
+                     .takes_value(true)
+                     .default_value(DEFAULT_SERVER_IP_PORT)
+                     .validator(is_valid_address)),
+        )
         .get_matches();
 
     if matches.subcommand.is_none() {
@@ -111,6 +128,18 @@ fn main() {
                     std::process::exit(1);
                 });
         }
+        ("backup", Some(m)) => {
+            let client = KvClient::new(m.value_of("addr").unwrap()).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            });
+            client
+                .send_cmd_backup(m.value_of("DIR").unwrap().to_owned())
+                .unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                });
+        }
         _ => std::process::exit(1),
     };
 }
@@ -6,19 +6,24 @@ extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
 
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 use kvs::{
+    load_dump, now_millis,
     thread_pool::{SharedQueueThreadPool, ThreadPool},
-    unwrap_or_return_code1_on_err, KvServer, KvStore, SledKvsEngine,
+    unwrap_or_return_code1_on_err, CompactionLimits, CompactionScheduler, DumpIterator, DumpRecord,
+    Durability, GarbageRatioPolicy, KvServer, KvStore, KvStoreError, KvStoreOptions, KvsEngine,
+    SledKvsEngine, TimeWindow,
 };
 use serde::{Deserialize, Serialize};
 use slog::{Drain, Logger};
-use std::net::SocketAddr;
+use std::{io::Write, net::SocketAddr, path::Path, time::Duration};
 
 use mio_signals::{Signal, Signals};
 
 const DEFAULT_SERVER_IP_PORT: &'static str = "127.0.0.1:4000";
 const DEFAULT_CONF_FILE_PATH: &'static str = "./.kvs-server-conf.json";
+/// File that sled writes to the directory of every database
+const SLED_CONF_FILE_NAME: &str = "conf";
 
 fn is_valid_address(addr: String) -> Result<(), String> {
     addr.parse::<SocketAddr>()
@@ -26,6 +31,30 @@ fn is_valid_address(addr: String) -> Result<(), String> {
         .map_err(|e| e.to_string())
 }
 
+fn is_valid_durability(durability: String) -> Result<(), String> {
+    durability.parse::<Durability>().map(|_| ())
+}
+
+fn is_positive_integer(value: String) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(v) if v > 0 => Ok(()),
+        Ok(_) => Err("value must be greater than zero".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn is_valid_ratio(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(v) if v > 0.0 && v <= 1.0 => Ok(()),
+        Ok(_) => Err("ratio must be greater than 0 and at most 1".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn is_valid_time_window(window: String) -> Result<(), String> {
+    window.parse::<TimeWindow>().map(|_| ())
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct ServerConfiguration {
     engine: String,
@@ -65,37 +94,165 @@ fn check_engine(engine: &str, config_file_path: &str, log_server: Logger) -> Res
             }
         }
     }
-    let config_file_writer = std::io::BufWriter::new(unwrap_or_return_code1_on_err!(
-        std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(config_file_path),
-        log_server,
-        "open configuration file for writting"
-    ));
     unwrap_or_return_code1_on_err!(
+        write_config(engine, config_file_path),
+        log_server,
+        "write the json configuration to the file"
+    );
+    Ok(())
+}
+
+/// Writes the configuration file naming the `engine`. The file is written to a temporary file first
+/// and then renamed, so that a crash never leaves it half-written.
+fn write_config(engine: &str, config_file_path: &str) -> std::io::Result<()> {
+    let tmp_file_path = format!("{}.tmp", config_file_path);
+    {
+        let mut config_file_writer =
+            std::io::BufWriter::new(std::fs::File::create(&tmp_file_path)?);
         serde_json::to_writer(
-            config_file_writer,
+            &mut config_file_writer,
             &ServerConfiguration {
-                engine: engine.to_string()
-            }
+                engine: engine.to_string(),
+            },
+        )?;
+        config_file_writer.flush()?;
+        config_file_writer.get_ref().sync_all()?;
+    }
+    std::fs::rename(&tmp_file_path, config_file_path)?;
+    let config_dir_path = Path::new(config_file_path)
+        .parent()
+        .filter(|path| !path.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    std::fs::File::open(config_dir_path)?.sync_all()
+}
+
+/// Reads the engine named by the configuration file, or `None` if there is no configuration file yet
+fn read_config_engine(config_file_path: &str) -> Result<Option<String>, String> {
+    match std::fs::File::open(config_file_path) {
+        Ok(file) => serde_json::from_reader(std::io::BufReader::new(file))
+            .map(|config: ServerConfiguration| Some(config.engine))
+            .map_err(|e| e.to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Tells if the data directory `dir` already holds a database of the `engine`
+fn has_engine_data(engine: &str, dir: &Path) -> std::io::Result<bool> {
+    match engine {
+        "kvs" => Ok(std::fs::read_dir(dir)?.filter_map(|r| r.ok()).any(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|s| s.starts_with("db") && s.ends_with(".log"))
+        })),
+        "sled" => Ok(dir.join(SLED_CONF_FILE_NAME).is_file()),
+        _ => unreachable!(),
+    }
+}
+
+/// Number of keys of a dump that are still alive at `now`, given the number of keys that never expire
+/// and the expiry times of the others
+fn live_keys(never_expiring: u64, expiry_times: &[u64], now: u64) -> u64 {
+    never_expiring + expiry_times.iter().filter(|t| **t > now).count() as u64
+}
+
+/// Streams every live key of the `source` records into the `target` engine, then checks that the target engine
+/// holds as many keys as were copied. The keys expiring meanwhile are accounted for.
+/// Returns the number of keys copied.
+fn copy_records<E: KvsEngine>(source: DumpIterator, target: &E) -> kvs::Result<u64> {
+    let mut never_expiring = 0;
+    let mut expiry_times = Vec::new();
+    let records = source.inspect(|record| match record {
+        Ok(DumpRecord {
+            expires_at: Some(expires_at),
+            ..
+        }) => expiry_times.push(*expires_at),
+        Ok(_) => never_expiring += 1,
+        Err(_) => {}
+    });
+    let copied = load_dump(target, records)?;
+
+    let now_before = now_millis();
+    let target_keys = target.scan_bytes(..)?.count() as u64;
+    let now_after = now_millis();
+    let expected_keys = live_keys(never_expiring, &expiry_times, now_after)
+        ..=live_keys(never_expiring, &expiry_times, now_before);
+    if !expected_keys.contains(&target_keys) {
+        return Err(KvStoreError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "the target engine holds {} keys, but {} were copied",
+                target_keys, copied
+            ),
+        )));
+    }
+    Ok(copied)
+}
+
+/// Copies the database of the data directory from the engine `from` to the engine `to`, and names the new engine
+/// in the configuration file once the copy is checked. The data of the engine `from` is left in place.
+fn migrate(from: &str, to: &str, config_file_path: &str, log: Logger) -> Result<(), i32> {
+    let log_migrate = log.new(o!("from" => from.to_owned(), "to" => to.to_owned()));
+    info!(log_migrate, "migrating");
+    let data_dir = Path::new("./");
+
+    if let Some(engine) = unwrap_or_return_code1_on_err!(
+        read_config_engine(config_file_path),
+        log_migrate,
+        "get the json configuration from file"
+    ) {
+        if engine != from {
+            error!(log_migrate, "The server was running earlier with another engine"; "old_engine" => engine);
+            return Err(1);
+        }
+    }
+    if unwrap_or_return_code1_on_err!(
+        has_engine_data(to, data_dir),
+        log_migrate,
+        "list the data directory"
+    ) {
+        error!(
+            log_migrate,
+            "The data directory already holds a database of the target engine, which must be removed first"
+        );
+        return Err(1);
+    }
+
+    let copied = match (from, to) {
+        ("kvs", "sled") => unwrap_or_return_code1_on_err!(
+            // Opened first, since the dump keeps the data directory locked until it is over
+            SledKvsEngine::open_with_durability(data_dir, Durability::SyncEachWrite)
+                .and_then(|target| copy_records(KvStore::dump(data_dir)?, &target)),
+            log_migrate,
+            "copy the keys to the target engine"
         ),
-        log_server,
+        ("sled", "kvs") => unwrap_or_return_code1_on_err!(
+            SledKvsEngine::open(data_dir).and_then(|source| {
+                let options = KvStoreOptions::new().durability(Durability::SyncEachWrite);
+                let target = KvStore::open_with(data_dir, options)?;
+                copy_records(source.dump()?, &target)
+            }),
+            log_migrate,
+            "copy the keys to the target engine"
+        ),
+        _ => {
+            error!(log_migrate, "The source and target engines must differ");
+            return Err(1);
+        }
+    };
+
+    unwrap_or_return_code1_on_err!(
+        write_config(to, config_file_path),
+        log_migrate,
         "write the json configuration to the file"
     );
+    info!(log_migrate, "migrated"; "keys" => copied);
     Ok(())
 }
 
-fn run_server_logging(engine: String, server_addr: String) -> Result<(), i32> {
-    let signals =
-        Signals::new(Signal::Interrupt | Signal::Terminate | Signal::Quit).map_err(|e| {
-            eprintln!(
-                "Could not setup signal handlers for kvs server. Operation failed with error: {}",
-                e
-            );
-            1i32
-        })?;
+/// Builds the logger writing to the standard error, from which the loggers of the server are derived
+fn root_logger() -> Logger {
     let decorator = slog_term::TermDecorator::new().stderr().build();
     let drain = slog_term::FullFormat::new(decorator)
         .use_file_location()
@@ -105,7 +262,28 @@ fn run_server_logging(engine: String, server_addr: String) -> Result<(), i32> {
         .fuse();
     let drain = slog_async::Async::new(drain).build().fuse();
 
-    let log = slog::Logger::root(drain, o!("version" => crate_version!()));
+    slog::Logger::root(drain, o!("version" => crate_version!()))
+}
+
+fn run_server_logging(
+    engine: String,
+    server_addr: String,
+    durability: Option<Durability>,
+    kvs_options: KvStoreOptions,
+    compaction_scheduler: CompactionScheduler,
+    idle_timeout: Option<Duration>,
+    backup_dir: Option<&str>,
+) -> Result<(), i32> {
+    let signals =
+        Signals::new(Signal::Interrupt | Signal::Terminate | Signal::Quit | Signal::User1)
+            .map_err(|e| {
+                eprintln!(
+                "Could not setup signal handlers for kvs server. Operation failed with error: {}",
+                e
+            );
+                1i32
+            })?;
+    let log = root_logger();
     let log_server =
         log.new(o!("address" => server_addr.to_owned(), "engine" => engine.to_owned()));
     info!(log_server, "starting");
@@ -116,7 +294,7 @@ fn run_server_logging(engine: String, server_addr: String) -> Result<(), i32> {
         "kvs" => {
             let mut server = KvServer::new(
                 unwrap_or_return_code1_on_err!(
-                    KvStore::open("./"),
+                    KvStore::open_with("./", kvs_options),
                     log_server,
                     "open database file"
                 ),
@@ -129,14 +307,24 @@ fn run_server_logging(engine: String, server_addr: String) -> Result<(), i32> {
                 log_server,
                 Some(signals),
             )
-            .unwrap();
+            .unwrap()
+            .compaction_scheduler(compaction_scheduler);
+            if let Some(idle_timeout) = idle_timeout {
+                server = server.idle_timeout(idle_timeout);
+            }
+            if let Some(backup_dir) = backup_dir {
+                server = server.backup_dir(backup_dir);
+            }
 
             server.run()?;
         }
         "sled" => {
             let mut server = KvServer::new(
                 unwrap_or_return_code1_on_err!(
-                    SledKvsEngine::open("./"),
+                    match durability {
+                        Some(durability) => SledKvsEngine::open_with_durability("./", durability),
+                        None => SledKvsEngine::open("./"),
+                    },
                     log_server,
                     "open database file"
                 ),
@@ -149,7 +337,14 @@ fn run_server_logging(engine: String, server_addr: String) -> Result<(), i32> {
                 log_server,
                 Some(signals),
             )
-            .unwrap();
+            .unwrap()
+            .compaction_scheduler(compaction_scheduler);
+            if let Some(idle_timeout) = idle_timeout {
+                server = server.idle_timeout(idle_timeout);
+            }
+            if let Some(backup_dir) = backup_dir {
+                server = server.backup_dir(backup_dir);
+            }
 
             server.run()?;
         }
@@ -176,12 +371,198 @@ fn main() {
             .possible_values(&["kvs", "sled"])
             .help("Sets the engine to be used if it is the first run. That is, if there is no data previously persisted")
             .takes_value(true)
-            .default_value("kvs")]);
+            .default_value("kvs"),
+               Arg::with_name("durability")
+            .long("durability")
+            .value_name("MODE")
+            .help("Sets when writes are synced to the disk: buffered, sync (every write), group (group commit) or interval:<milliseconds>. Defaults to buffered for kvs and sync for sled")
+            .takes_value(true)
+            .validator(is_valid_durability),
+               Arg::with_name("max-file-size")
+            .long("max-file-size")
+            .value_name("BYTES")
+            .help("Sets the size a log file must exceed to be replaced by a new one (kvs engine only)")
+            .takes_value(true)
+            .validator(is_positive_integer),
+               Arg::with_name("max-file-cmds")
+            .long("max-file-cmds")
+            .value_name("COUNT")
+            .help("Sets the number of commands a log file must exceed to be replaced by a new one (kvs engine only)")
+            .takes_value(true)
+            .validator(is_positive_integer),
+               Arg::with_name("reader-cache-capacity")
+            .long("reader-cache-capacity")
+            .value_name("COUNT")
+            .help("Sets the number of log files kept open for reading (kvs engine only)")
+            .takes_value(true)
+            .validator(is_positive_integer),
+               Arg::with_name("value-cache-capacity")
+            .long("value-cache-capacity")
+            .value_name("BYTES")
+            .help("Sets the number of bytes of values kept in memory to serve the reads of hot keys (kvs engine only)")
+            .takes_value(true)
+            .validator(is_positive_integer),
+               Arg::with_name("compaction-min-dead-bytes")
+            .long("compaction-min-dead-bytes")
+            .value_name("BYTES")
+            .help("Sets the number of dead bytes the log files to be compacted must hold altogether for a compaction to run (kvs engine only)")
+            .takes_value(true)
+            .validator(is_positive_integer),
+               Arg::with_name("compaction-garbage-ratio")
+            .long("compaction-garbage-ratio")
+            .value_name("RATIO")
+            .help("Sets the fraction of dead bytes a log file must have to be compacted (kvs engine only)")
+            .takes_value(true)
+            .validator(is_valid_ratio),
+               Arg::with_name("compaction-interval")
+            .long("compaction-interval")
+            .value_name("MILLISECONDS")
+            .help("Sets the time between two scheduled compactions. Defaults to 5000")
+            .takes_value(true)
+            .validator(is_positive_integer),
+               Arg::with_name("idle-timeout")
+            .long("idle-timeout")
+            .value_name("MILLISECONDS")
+            .help("Sets the time a client connection is kept open without receiving any request. Defaults to 60000")
+            .takes_value(true)
+            .validator(is_positive_integer),
+               Arg::with_name("backup-dir")
+            .long("backup-dir")
+            .value_name("DIR")
+            .help("Sets the directory the backups asked for by the clients are written to, relative to which their directory is resolved. Defaults to the working directory")
+            .takes_value(true),
+               Arg::with_name("manual-compaction")
+            .long("manual-compaction")
+            .help("Only runs a compaction when the server receives a SIGUSR1")
+            .conflicts_with("compaction-interval"),
+               Arg::with_name("compaction-window")
+            .long("compaction-window")
+            .value_name("HH:MM-HH:MM")
+            .help("Adds a daily window of time, in UTC, out of which scheduled compactions do not start. May be given more than once")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(is_valid_time_window),
+               Arg::with_name("compaction-max-bytes")
+            .long("compaction-max-bytes")
+            .value_name("BYTES")
+            .help("Sets the number of bytes of log files a single compaction may pick (kvs engine only)")
+            .takes_value(true)
+            .validator(is_positive_integer),
+               Arg::with_name("compaction-max-duration")
+            .long("compaction-max-duration")
+            .value_name("MILLISECONDS")
+            .help("Sets the time after which a compaction stops picking up new log files (kvs engine only)")
+            .takes_value(true)
+            .validator(is_positive_integer),
+               Arg::with_name("compaction-rate")
+            .long("compaction-rate")
+            .value_name("BYTES-PER-SEC")
+            .help("Sets the number of bytes a compaction may read and write per second (kvs engine only)")
+            .takes_value(true)
+            .validator(is_positive_integer)])
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .author(crate_authors!())
+                .version(crate_version!())
+                .about("Copy the database of the current directory to another engine, which the server runs with from then on. The server must not be running")
+                .arg(Arg::with_name("from")
+                     .long("from")
+                     .value_name("ENGINE-NAME")
+                     .possible_values(&["kvs", "sled"])
+                     .help("Sets the engine the database is copied from")
+                     .takes_value(true)
+                     .required(true))
+                .arg(Arg::with_name("to")
+                     .long("to")
+                     .value_name("ENGINE-NAME")
+                     .possible_values(&["kvs", "sled"])
+                     .help("Sets the engine the database is copied to")
+                     .takes_value(true)
+                     .required(true)),
+        );
     let matches = app.get_matches();
 
+    if let ("migrate", Some(m)) = matches.subcommand() {
+        let log = root_logger();
+        migrate(
+            m.value_of("from").unwrap(),
+            m.value_of("to").unwrap(),
+            DEFAULT_CONF_FILE_PATH,
+            log,
+        )
+        .unwrap_or_else(|code| std::process::exit(code));
+        return;
+    }
+
     let server_addr = matches.value_of("addr").unwrap().to_string();
 
     let engine = matches.value_of("engine").unwrap().to_string();
 
-    run_server_logging(engine, server_addr).unwrap_or_else(|code| std::process::exit(code));
+    let durability = matches
+        .value_of("durability")
+        .map(|d| d.parse::<Durability>().unwrap());
+
+    let mut kvs_options = KvStoreOptions::new();
+    if let Some(durability) = durability {
+        kvs_options = kvs_options.durability(durability);
+    }
+    if let Some(v) = matches.value_of("max-file-size") {
+        kvs_options = kvs_options.max_file_size(v.parse().unwrap());
+    }
+    if let Some(v) = matches.value_of("max-file-cmds") {
+        kvs_options = kvs_options.max_file_cmds(v.parse().unwrap());
+    }
+    if let Some(v) = matches.value_of("reader-cache-capacity") {
+        kvs_options = kvs_options.reader_cache_capacity(v.parse().unwrap());
+    }
+    if let Some(v) = matches.value_of("value-cache-capacity") {
+        kvs_options = kvs_options.value_cache_capacity(v.parse().unwrap());
+    }
+    let mut compaction_policy = GarbageRatioPolicy::new();
+    if let Some(v) = matches.value_of("compaction-min-dead-bytes") {
+        compaction_policy = compaction_policy.min_dead_bytes(v.parse().unwrap());
+    }
+    if let Some(v) = matches.value_of("compaction-garbage-ratio") {
+        compaction_policy = compaction_policy.min_garbage_ratio(v.parse().unwrap());
+    }
+    kvs_options = kvs_options.compaction_policy(compaction_policy);
+
+    let mut compaction_limits = CompactionLimits::new();
+    if let Some(v) = matches.value_of("compaction-max-bytes") {
+        compaction_limits = compaction_limits.max_bytes(v.parse().unwrap());
+    }
+    if let Some(v) = matches.value_of("compaction-max-duration") {
+        compaction_limits =
+            compaction_limits.max_duration(Duration::from_millis(v.parse().unwrap()));
+    }
+    if let Some(v) = matches.value_of("compaction-rate") {
+        compaction_limits = compaction_limits.max_bytes_per_sec(v.parse().unwrap());
+    }
+    let mut compaction_scheduler = CompactionScheduler::new().limits(compaction_limits);
+    if let Some(v) = matches.value_of("compaction-interval") {
+        compaction_scheduler =
+            compaction_scheduler.interval(Duration::from_millis(v.parse().unwrap()));
+    }
+    if matches.is_present("manual-compaction") {
+        compaction_scheduler = compaction_scheduler.manual_only();
+    }
+    for window in matches.values_of("compaction-window").into_iter().flatten() {
+        compaction_scheduler = compaction_scheduler.off_peak_window(window.parse().unwrap());
+    }
+
+    let idle_timeout = matches
+        .value_of("idle-timeout")
+        .map(|v| Duration::from_millis(v.parse().unwrap()));
+
+    run_server_logging(
+        engine,
+        server_addr,
+        durability,
+        kvs_options,
+        compaction_scheduler,
+        idle_timeout,
+        matches.value_of("backup-dir"),
+    )
+    .unwrap_or_else(|code| std::process::exit(code));
 }
@@ -0,0 +1,229 @@
+#[macro_use]
+extern crate clap;
+
+use clap::{App, Arg, SubCommand};
+use kvs::{
+    load_dump, DumpIterator, DumpRecord, Durability, KvStore, KvStoreError, KvsEngine,
+    RepairAction, SledKvsEngine,
+};
+use serde::Deserialize;
+use std::{
+    fs,
+    io::{self, prelude::*, BufWriter},
+    path::Path,
+};
+
+/// Name of the configuration file written by `kvs-server` to its data directory
+const SERVER_CONF_FILE_NAME: &str = ".kvs-server-conf.json";
+/// File that sled writes to the directory of every database
+const SLED_CONF_FILE_NAME: &str = "conf";
+
+#[derive(Deserialize, Debug)]
+struct ServerConfiguration {
+    engine: String,
+}
+
+/// Tells which engine the database found at `dir` was written by, or `None` if there is no database in it.
+/// The configuration file of the server is trusted first, then the files of each engine are looked for.
+fn detect_engine(dir: &Path) -> Result<Option<String>, String> {
+    if let Ok(file) = fs::File::open(dir.join(SERVER_CONF_FILE_NAME)) {
+        let config: ServerConfiguration = serde_json::from_reader(io::BufReader::new(file))
+            .map_err(|e| format!("Invalid {}: {}", SERVER_CONF_FILE_NAME, e))?;
+        return Ok(Some(config.engine));
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    };
+    let has_log_files = entries.filter_map(|r| r.ok()).any(|entry| {
+        entry
+            .file_name()
+            .to_str()
+            .is_some_and(|s| s.starts_with("db") && s.ends_with(".log"))
+    });
+    if has_log_files {
+        Ok(Some("kvs".to_owned()))
+    } else if dir.join(SLED_CONF_FILE_NAME).is_file() {
+        Ok(Some("sled".to_owned()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Picks the engine given by the user, which must be the one of the database found at `dir` if any
+fn choose_engine(dir: &Path, engine: Option<&str>) -> Result<Option<String>, String> {
+    match (detect_engine(dir)?, engine) {
+        (Some(detected), Some(engine)) if detected != engine => Err(format!(
+            "The directory holds a database of the {} engine",
+            detected
+        )),
+        (detected, engine) => Ok(detected.or(engine.map(str::to_owned))),
+    }
+}
+
+fn dump(dir: &Path, engine: Option<&str>) -> Result<(), String> {
+    let records: DumpIterator = match choose_engine(dir, engine)?.as_deref() {
+        Some("kvs") => KvStore::dump(dir).map_err(|e| e.to_string())?,
+        Some("sled") => SledKvsEngine::open(dir)
+            .and_then(|engine| engine.dump())
+            .map_err(|e| e.to_string())?,
+        Some(engine) => return Err(format!("Unknown engine: {}", engine)),
+        None => return Err("No database found in the directory".to_owned()),
+    };
+    let stdout = io::stdout();
+    let mut writer = BufWriter::new(stdout.lock());
+    for record in records {
+        let record = record.map_err(|e| e.to_string())?;
+        serde_json::to_writer(&mut writer, &record).map_err(|e| e.to_string())?;
+        writer.write_all(b"\n").map_err(|e| e.to_string())?;
+    }
+    writer.flush().map_err(|e| e.to_string())
+}
+
+/// Writes the records read from the standard input to the `engine`, returning the number of records written
+fn load_records<E: KvsEngine>(engine: &E) -> Result<u64, String> {
+    let stdin = io::stdin();
+    let records = stdin.lock().lines().enumerate().filter_map(|(i, line)| {
+        let record = line.map_err(KvStoreError::from).and_then(|line| {
+            if line.trim().is_empty() {
+                return Ok(None);
+            }
+            serde_json::from_str::<DumpRecord>(&line)
+                .map(Some)
+                .map_err(|e| {
+                    let message = format!("Invalid record at line {}: {}", i + 1, e);
+                    KvStoreError::Io(io::Error::new(io::ErrorKind::InvalidData, message))
+                })
+        });
+        record.transpose()
+    });
+    load_dump(engine, records).map_err(|e| e.to_string())
+}
+
+fn load(dir: &Path, engine: Option<&str>) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    let count = match choose_engine(dir, engine)?.as_deref().unwrap_or("kvs") {
+        "kvs" => load_records(&KvStore::open(dir).map_err(|e| e.to_string())?)?,
+        // Every record is flushed to the disk when the engine is dropped
+        "sled" => load_records(
+            &SledKvsEngine::open_with_durability(dir, Durability::Buffered)
+                .map_err(|e| e.to_string())?,
+        )?,
+        engine => return Err(format!("Unknown engine: {}", engine)),
+    };
+    eprintln!("Loaded {} keys", count);
+    Ok(())
+}
+
+/// Prints the problems found in the log files of the store at `dir`, and the log files lost.
+/// Fails if any of them keeps the store from being opened without losing commands, or if a log file was lost.
+fn verify(dir: &Path) -> Result<(), String> {
+    let report = KvStore::verify(dir).map_err(|e| e.to_string())?;
+    let mut cmds = 0;
+    for log_file in report.log_files.iter() {
+        cmds += log_file.cmds;
+        for problem in log_file.problems.iter() {
+            println!("{}: {}", log_file.path.display(), problem);
+        }
+    }
+    for path in report.unknown_files.iter() {
+        if path.file_name().and_then(|name| name.to_str()) != Some(SERVER_CONF_FILE_NAME) {
+            println!("{}: unknown file", path.display());
+        }
+    }
+    for log_id in report.missing_log_ids.iter() {
+        println!("missing log file {}", log_id);
+    }
+    println!(
+        "Checked {} log files with {} commands ({} log ids left unused by compactions)",
+        report.log_files.len(),
+        cmds,
+        report.unused_log_ids.len()
+    );
+    if !report.missing_log_ids.is_empty() {
+        Err("The store lost log files, restore it from a backup".to_owned())
+    } else if report.is_ok() {
+        Ok(())
+    } else {
+        Err("The store has corrupted log files, run repair to fix them".to_owned())
+    }
+}
+
+fn repair(dir: &Path) -> Result<(), String> {
+    let actions = KvStore::repair(dir).map_err(|e| e.to_string())?;
+    for action in actions.iter() {
+        match action {
+            RepairAction::Truncated { log_id, len } => {
+                println!("log file {}: truncated to {} bytes", log_id, len)
+            }
+            RepairAction::Quarantined { log_id, len, path } => println!(
+                "log file {}: truncated to {} bytes, moved the rest to {}",
+                log_id,
+                len,
+                path.display()
+            ),
+        }
+    }
+    println!("Repaired {} log files", actions.len());
+    Ok(())
+}
+
+fn main() {
+    let engine_arg = Arg::with_name("engine")
+        .long("engine")
+        .value_name("ENGINE-NAME")
+        .possible_values(&["kvs", "sled"])
+        .takes_value(true);
+    let matches = App::new("kvs-tool")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Offline tools for the data directories of the key-value store")
+        .subcommand(
+            SubCommand::with_name("dump")
+                .author(crate_authors!())
+                .version(crate_version!())
+                .about("Write all the live keys and values of a data directory to the standard output, one JSON object per line")
+                .arg(Arg::with_name("DIR").required(true).index(1))
+                .arg(engine_arg.clone().help(
+                    "Sets the engine of the data directory. Detected from its files if not given",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("load")
+                .author(crate_authors!())
+                .version(crate_version!())
+                .about("Write the keys and values read from the standard input, as written by dump, to a data directory")
+                .arg(Arg::with_name("DIR").required(true).index(1))
+                .arg(engine_arg.help(
+                    "Sets the engine of the data directory if it holds no database yet. Defaults to kvs",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .author(crate_authors!())
+                .version(crate_version!())
+                .about("Check every record of the log files of a kvs data directory, which must not be in use")
+                .arg(Arg::with_name("DIR").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .author(crate_authors!())
+                .version(crate_version!())
+                .about("Cut off the torn and corrupted records of the log files of a kvs data directory, which must not be in use")
+                .arg(Arg::with_name("DIR").required(true).index(1)),
+        )
+        .get_matches();
+
+    let res = match matches.subcommand() {
+        ("dump", Some(m)) => dump(Path::new(m.value_of("DIR").unwrap()), m.value_of("engine")),
+        ("load", Some(m)) => load(Path::new(m.value_of("DIR").unwrap()), m.value_of("engine")),
+        ("verify", Some(m)) => verify(Path::new(m.value_of("DIR").unwrap())),
+        ("repair", Some(m)) => repair(Path::new(m.value_of("DIR").unwrap())),
+        _ => std::process::exit(1),
+    };
+    res.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+}
@@ -0,0 +1,105 @@
+use super::{kvsengine::now_millis, KvStoreError, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+use walkdir::WalkDir;
+
+/// Name of the file describing a checkpoint, written last to its directory
+pub const CHECKPOINT_MANIFEST_FILE: &str = "MANIFEST.json";
+/// Extension of the manifest file while it is being written
+const CHECKPOINT_MANIFEST_TMP_EXTENSION: &str = "json.tmp";
+
+/// Describes the copy of a database written by `KvsEngine::checkpoint`.
+/// A checkpoint directory without a manifest is incomplete and should not be used.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckpointManifest {
+    /// Name of the engine the checkpoint was taken from and can be opened with, either "kvs" or "sled"
+    pub engine: String,
+    /// Time the checkpoint was taken, in milliseconds since the UNIX epoch
+    pub created_at: u64,
+    /// The files of the checkpoint, sorted by path
+    pub files: Vec<CheckpointFile>,
+}
+
+/// A file of a checkpoint, as listed by its manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckpointFile {
+    /// Path of the file, relative to the checkpoint directory
+    pub path: String,
+    /// Length of the file in bytes
+    pub len: u64,
+}
+
+impl CheckpointManifest {
+    /// Reads the manifest of the checkpoint found at `dir_path`.
+    /// Return an error if the directory holds no complete checkpoint.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::CheckpointManifest;
+    /// let manifest = CheckpointManifest::read("./backup").unwrap();
+    /// println!("{} checkpoint with {} files", manifest.engine, manifest.files.len());
+    /// ```
+    pub fn read<P: AsRef<Path>>(dir_path: P) -> Result<Self> {
+        let reader = BufReader::new(File::open(
+            dir_path.as_ref().join(CHECKPOINT_MANIFEST_FILE),
+        )?);
+        serde_json::from_reader(reader).map_err(|err| KvStoreError::Io(err.into()))
+    }
+}
+
+/// Creates the directory of a checkpoint, which must not exist or be empty,
+/// so that a checkpoint never mixes its files with the ones of another
+pub(crate) fn create_checkpoint_dir(dir_path: &Path) -> Result<()> {
+    fs::create_dir_all(dir_path)?;
+    if fs::read_dir(dir_path)?.next().is_some() {
+        return Err(KvStoreError::CheckpointDirNotEmpty {
+            path: dir_path.display().to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Lists the files written to the checkpoint directory and syncs them, then writes the manifest of the checkpoint.
+/// The manifest is written to a temporary file first, so that it is only found once complete.
+pub(crate) fn write_checkpoint_manifest(
+    dir_path: &Path,
+    engine: &str,
+) -> Result<CheckpointManifest> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(dir_path).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        File::open(entry.path())?.sync_all()?;
+        let path = entry
+            .path()
+            .strip_prefix(dir_path)
+            .map_err(|_| KvStoreError::WrongFileNameFormat)?;
+        files.push(CheckpointFile {
+            path: path.to_string_lossy().into_owned(),
+            len: entry.metadata()?.len(),
+        });
+    }
+    let manifest = CheckpointManifest {
+        engine: engine.to_owned(),
+        created_at: now_millis(),
+        files,
+    };
+
+    let manifest_path = dir_path.join(CHECKPOINT_MANIFEST_FILE);
+    let tmp_path = manifest_path.with_extension(CHECKPOINT_MANIFEST_TMP_EXTENSION);
+    let mut writer = BufWriter::new(File::create(tmp_path.as_path())?);
+    serde_json::to_writer_pretty(&mut writer, &manifest)
+        .map_err(|err| KvStoreError::Io(err.into()))?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(tmp_path.as_path(), manifest_path.as_path())?;
+    File::open(dir_path)?.sync_all()?;
+    Ok(manifest)
+}
@@ -0,0 +1,384 @@
+use std::{
+    fmt::{self, Debug},
+    num::NonZeroU64,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Default value of `GarbageRatioPolicy::min_garbage_ratio`
+const DEFAULT_MIN_GARBAGE_RATIO: f64 = 0.5;
+/// Default value of `GarbageRatioPolicy::min_dead_bytes`
+const DEFAULT_MIN_DEAD_BYTES: u64 = 1048576;
+/// Default value of `CompactionScheduler::interval`
+const DEFAULT_COMPACTION_INTERVAL: Duration = Duration::from_secs(5);
+/// Length of a day, the period of the off-peak windows
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The live and dead bytes of a sealed log file of a `KvStore`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentStats {
+    /// Id of the log file
+    pub log_id: u64,
+    /// Bytes taken by the commands still pointed at by the storage index
+    pub live_bytes: u64,
+    /// Bytes taken by the commands overwritten, removed or expired since they were written, which compaction reclaims
+    pub dead_bytes: u64,
+}
+
+impl SegmentStats {
+    /// Returns the fraction of the bytes of the log file that are dead.
+    /// An empty log file is all garbage, since there is nothing in it worth keeping.
+    pub fn garbage_ratio(&self) -> f64 {
+        let total_bytes = self.live_bytes + self.dead_bytes;
+        if total_bytes == 0 {
+            1.0
+        } else {
+            self.dead_bytes as f64 / total_bytes as f64
+        }
+    }
+}
+
+/// Decides which sealed log files of a `KvStore` are rewritten by a compaction
+pub trait CompactionPolicy: Debug + Send + Sync {
+    /// Returns the ids of the log files to be compacted, given the stats of all the sealed log files sorted by id.
+    /// Returning no ids skips the compaction.
+    fn select(&self, segments: &[SegmentStats]) -> Vec<u64>;
+}
+
+/// Compacts the sealed log files with a garbage ratio of at least `min_garbage_ratio`,
+/// once they hold at least `min_dead_bytes` dead bytes altogether.
+/// Built with chained calls starting from `GarbageRatioPolicy::new()`
+///
+/// # Examples
+///
+/// ```no_run
+/// use kvs::{GarbageRatioPolicy, KvStore, KvStoreOptions};
+/// let policy = GarbageRatioPolicy::new()
+///     .min_garbage_ratio(0.3)
+///     .min_dead_bytes(64 * 1024 * 1024);
+/// let dictionary = KvStore::open_with("./", KvStoreOptions::new().compaction_policy(policy)).unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct GarbageRatioPolicy {
+    min_garbage_ratio: f64,
+    min_dead_bytes: u64,
+}
+
+impl GarbageRatioPolicy {
+    /// Creates the default policy:
+    ///     min_garbage_ratio: 0.5
+    ///     min_dead_bytes: 1 MiB
+    pub fn new() -> Self {
+        Self {
+            min_garbage_ratio: DEFAULT_MIN_GARBAGE_RATIO,
+            min_dead_bytes: DEFAULT_MIN_DEAD_BYTES,
+        }
+    }
+
+    /// Sets the fraction of dead bytes a sealed log file must have to be compacted.
+    pub fn min_garbage_ratio(mut self, min_garbage_ratio: f64) -> Self {
+        self.min_garbage_ratio = min_garbage_ratio;
+        self
+    }
+
+    /// Sets the number of dead bytes the log files to be compacted must hold altogether for the compaction to run.
+    pub fn min_dead_bytes(mut self, min_dead_bytes: u64) -> Self {
+        self.min_dead_bytes = min_dead_bytes;
+        self
+    }
+}
+
+impl Default for GarbageRatioPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CompactionPolicy for GarbageRatioPolicy {
+    fn select(&self, segments: &[SegmentStats]) -> Vec<u64> {
+        let selected = segments
+            .iter()
+            .filter(|segment| segment.garbage_ratio() >= self.min_garbage_ratio)
+            .collect::<Vec<_>>();
+        let dead_bytes = selected
+            .iter()
+            .map(|segment| segment.dead_bytes)
+            .sum::<u64>();
+        if dead_bytes < self.min_dead_bytes {
+            return Vec::new();
+        }
+        selected.into_iter().map(|segment| segment.log_id).collect()
+    }
+}
+
+/// What a compaction would do if it ran now, as returned by `KvsCompactor::compaction_needed`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// Number of log files that would be compacted
+    pub log_files: usize,
+    /// Bytes of garbage held by those log files, which the compaction would give back to the disk
+    pub reclaimable_bytes: u64,
+    /// Bytes of live commands held by those log files, which the compaction would have to rewrite
+    pub bytes_to_rewrite: u64,
+}
+
+impl CompactionEstimate {
+    /// Tells if running a compaction would reclaim any space
+    pub fn is_needed(&self) -> bool {
+        self.log_files > 0
+    }
+}
+
+/// Bounds the work done by a single call to `KvsCompactor::compact_with`.
+/// Built with chained calls starting from `CompactionLimits::new()`, which sets no limit at all.
+///
+/// A log file is either compacted as a whole or left for a later compaction, so the byte and time budgets
+/// are checked between log files: the first log file is always compacted, and the last one may overrun the budget.
+///
+/// # Examples
+///
+/// ```no_run
+/// use kvs::{CompactionLimits, KvStore, KvsCompactor};
+/// use std::{num::NonZeroU64, time::Duration};
+/// let limits = CompactionLimits::new()
+///     .max_bytes(256 * 1024 * 1024)
+///     .max_duration(Duration::from_secs(30))
+///     .max_bytes_per_sec(NonZeroU64::new(16 * 1024 * 1024).unwrap());
+/// let dictionary = KvStore::open("./").unwrap();
+/// let progress = dictionary.compact_with(&limits).unwrap();
+/// assert!(progress.log_files_compacted <= progress.log_files_total);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionLimits {
+    pub(crate) max_bytes: Option<u64>,
+    pub(crate) max_duration: Option<Duration>,
+    pub(crate) max_bytes_per_sec: Option<NonZeroU64>,
+}
+
+impl CompactionLimits {
+    /// Creates limits that let the compaction run to completion as fast as it can
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the number of bytes of log files a compaction may pick
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Sets the time after which a compaction stops picking up new log files
+    pub fn max_duration(mut self, max_duration: Duration) -> Self {
+        self.max_duration = Some(max_duration);
+        self
+    }
+
+    /// Sets the rate, in bytes read and written per second, a compaction must not exceed,
+    /// so that it leaves enough disk bandwidth to the requests served meanwhile
+    pub fn max_bytes_per_sec(mut self, max_bytes_per_sec: NonZeroU64) -> Self {
+        self.max_bytes_per_sec = Some(max_bytes_per_sec);
+        self
+    }
+}
+
+/// The work done by a compaction, reported while it runs by `KvsCompactor::compaction_progress`
+/// and once it is over by `KvsCompactor::compact_with`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionProgress {
+    /// Number of log files picked by the compaction
+    pub log_files_total: usize,
+    /// Number of log files compacted so far
+    pub log_files_compacted: usize,
+    /// Bytes read from the compacted log files
+    pub bytes_read: u64,
+    /// Bytes written to the new log files
+    pub bytes_written: u64,
+    /// Time spent by the compaction
+    pub elapsed: Duration,
+}
+
+impl CompactionProgress {
+    /// Tells if all the log files picked by the compaction were compacted, which is not the case when it ran
+    /// out of time
+    pub fn is_complete(&self) -> bool {
+        self.log_files_compacted == self.log_files_total
+    }
+}
+
+/// Slows down a compaction so that it does not read and write more than `CompactionLimits::max_bytes_per_sec`
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    started_at: Instant,
+    max_bytes_per_sec: Option<NonZeroU64>,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub(crate) fn new(max_bytes_per_sec: Option<NonZeroU64>) -> Self {
+        Self {
+            started_at: Instant::now(),
+            max_bytes_per_sec,
+            bytes: 0,
+        }
+    }
+
+    /// Accounts for `bytes` more bytes of I/O, sleeping until the rate falls back within the limit
+    pub(crate) fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        if let Some(max_bytes_per_sec) = self.max_bytes_per_sec {
+            let expected =
+                Duration::from_secs_f64(self.bytes as f64 / max_bytes_per_sec.get() as f64);
+            if let Some(ahead) = expected.checked_sub(self.started_at.elapsed()) {
+                thread::sleep(ahead);
+            }
+        }
+    }
+}
+
+/// A daily window of time, in UTC, during which the server is allowed to start compactions.
+/// A window whose end is before its start spans midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    start: Duration,
+    end: Duration,
+}
+
+impl TimeWindow {
+    /// Creates the window from `start` to `end`, both measured from midnight UTC
+    pub fn new(start: Duration, end: Duration) -> Self {
+        Self {
+            start: Duration::from_secs(start.as_secs() % DAY.as_secs()),
+            end: Duration::from_secs(end.as_secs() % DAY.as_secs()),
+        }
+    }
+
+    /// Tells if the time of the day of `time` is within the window
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let time_of_day = Duration::from_secs(since_epoch.as_secs() % DAY.as_secs());
+        if self.start <= self.end {
+            self.start <= time_of_day && time_of_day < self.end
+        } else {
+            self.start <= time_of_day || time_of_day < self.end
+        }
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (start, end) = (self.start.as_secs() / 60, self.end.as_secs() / 60);
+        f.write_fmt(format_args!(
+            "{:02}:{:02}-{:02}:{:02}",
+            start / 60,
+            start % 60,
+            end / 60,
+            end % 60
+        ))
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    /// Parses a window with the format `HH:MM-HH:MM`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_time = |time: &str| match time.split_once(':') {
+            Some((hours, minutes)) => match (hours.parse::<u64>(), minutes.parse::<u64>()) {
+                (Ok(hours), Ok(minutes)) if hours < 24 && minutes < 60 => {
+                    Some(Duration::from_secs((hours * 60 + minutes) * 60))
+                }
+                _ => None,
+            },
+            None => None,
+        };
+        match s.split_once('-') {
+            Some((start, end)) => match (parse_time(start), parse_time(end)) {
+                (Some(start), Some(end)) => Ok(TimeWindow::new(start, end)),
+                _ => Err(format!(
+                    "invalid time window: {}. Expected the format HH:MM-HH:MM",
+                    s
+                )),
+            },
+            None => Err(format!(
+                "invalid time window: {}. Expected the format HH:MM-HH:MM",
+                s
+            )),
+        }
+    }
+}
+
+/// Decides when a `KvServer` runs compactions, and how much work each of them may do.
+/// Built with chained calls starting from `CompactionScheduler::new()`
+///
+/// # Examples
+///
+/// ```no_run
+/// use kvs::{CompactionLimits, CompactionScheduler};
+/// use std::time::Duration;
+/// let scheduler = CompactionScheduler::new()
+///     .interval(Duration::from_secs(60))
+///     .off_peak_window("02:00-05:00".parse().unwrap())
+///     .limits(CompactionLimits::new().max_duration(Duration::from_secs(10)));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionScheduler {
+    pub(crate) interval: Option<Duration>,
+    pub(crate) off_peak_windows: Vec<TimeWindow>,
+    pub(crate) limits: CompactionLimits,
+}
+
+impl CompactionScheduler {
+    /// Creates the default scheduler:
+    ///     interval: 5 seconds
+    ///     off-peak windows: none, compactions may start at any time
+    ///     limits: none
+    pub fn new() -> Self {
+        Self {
+            interval: Some(DEFAULT_COMPACTION_INTERVAL),
+            off_peak_windows: Vec::new(),
+            limits: CompactionLimits::new(),
+        }
+    }
+
+    /// Sets the time between the end of a compaction check and the start of the next one
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Stops the server from compacting on its own. Compactions only run when fired by a
+    /// `KvServerCompactionTrigger`
+    pub fn manual_only(mut self) -> Self {
+        self.interval = None;
+        self
+    }
+
+    /// Adds a window of the day out of which scheduled compactions do not start
+    pub fn off_peak_window(mut self, window: TimeWindow) -> Self {
+        self.off_peak_windows.push(window);
+        self
+    }
+
+    /// Sets the limits of each compaction
+    pub fn limits(mut self, limits: CompactionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Tells if a scheduled compaction must start at `now`, given the time elapsed since the last one
+    pub(crate) fn is_due(&self, since_last: Duration, now: SystemTime) -> bool {
+        self.interval.is_some_and(|interval| since_last >= interval)
+            && (self.off_peak_windows.is_empty()
+                || self
+                    .off_peak_windows
+                    .iter()
+                    .any(|window| window.contains(now)))
+    }
+}
+
+impl Default for CompactionScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -2,37 +2,47 @@
 //! The messages communicated in this protocol follows the format.
 //! Byte index(es) from the MSB to LSB: Meaning
 //! 0: ProtocolHeader
-//! 1: MessageType (Bit7 => 0: Request, 1: Response; Bits[0..6] => Command)
-//! 2-: The actual payload content
+//! 1-4: Payload length
+//! 5-8: Request id, chosen by the client and copied by the server to the response
+//! 9: MessageType (Bit7 => 0: Request, 1: Response; Bits[0..6] => Command)
+//! 10-: The actual payload content
 
 //! Note: All the length fields in the protocol are read as unsigned 32 bit integers.
 //! All the numeric values are (de)serialized in big endian format.
+//! Keys and values are arbitrary byte strings, (de)serialized as a length field followed by the bytes.
+
+//! Note: A connection carries any number of messages. The client may send many requests without waiting
+//! for their responses (pipelining). The server answers them in order, and each response carries the id
+//! of its request, so that the client can match them.
 
 pub mod de;
 pub mod error;
 pub mod ser;
 
+use crate::Op;
 use num_traits::{FromPrimitive, ToPrimitive};
 use serde::ser::SerializeTuple;
 use serde::{Deserialize, Serialize};
 
 /// Every message in the protocol must start with the following byte
-pub const PROTOCOL_VERSION: u8 = 0xC1;
+pub const PROTOCOL_VERSION: u8 = 0xC3;
 
 /// The fixed size of the header for every message of the protocol
-pub const HEADER_SIZE: usize = 5;
+pub const HEADER_SIZE: usize = 9;
 
 /// Message format for commands used in communication between kvs server and client
 #[derive(Debug, PartialEq, PartialOrd, Eq, Ord)]
 pub struct Message {
+    request_id: u32,
     payload: MessagePayload,
 }
 
-/// The header of the message. It includes the version and total payloand length
+/// The header of the message. It includes the version, total payloand length and request id
 #[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord)]
 pub struct Header {
     protocol_version: u8,
     payload_length: u32,
+    request_id: u32,
 }
 
 impl Header {
@@ -41,6 +51,7 @@ impl Header {
         Header {
             protocol_version: PROTOCOL_VERSION,
             payload_length: 0,
+            request_id: 0,
         }
     }
 
@@ -53,6 +64,11 @@ impl Header {
     pub fn payload_length(&self) -> u32 {
         self.payload_length
     }
+
+    /// Get header's request id.
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
 }
 
 /// A enum to distinguish between messages sent from the client to the server (requests)
@@ -77,25 +93,63 @@ pub enum Request {
 
     /// Request of the type `Remove` Command
     Remove(RequestRemove),
+
+    /// Request of the type `Batch` Command
+    Batch(RequestBatch),
+
+    /// Request of the type `CompareAndSwap` Command
+    CompareAndSwap(RequestCompareAndSwap),
+
+    /// Request of the type `Backup` admin Command
+    Backup(RequestBackup),
 }
 
 /// A Request for a `Set` Command
 #[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord)]
 pub struct RequestSet {
-    key: String,
-    value: String,
+    key: Bytes,
+    value: Bytes,
+    /// Chosen by the client to make the request safe to send again: the server applies a set only once per key
+    idempotency_key: Option<Bytes>,
 }
 
 /// A Request for a `Get` Command
 #[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord)]
 pub struct RequestGet {
-    key: String,
+    key: Bytes,
 }
 
 /// A Request for a `Remove` Command
 #[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord)]
 pub struct RequestRemove {
-    key: String,
+    key: Bytes,
+}
+
+/// A Request for a `Batch` Command, which applies many sets and removes atomically
+#[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord)]
+pub struct RequestBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A Request for a `CompareAndSwap` Command, which sets the value of a key only if its current value is the expected one
+#[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord)]
+pub struct RequestCompareAndSwap {
+    key: Bytes,
+    expected: Option<Bytes>,
+    new: Option<Bytes>,
+}
+
+/// A Request for a `Backup` admin Command, which writes a checkpoint of the database to a directory of the server
+#[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord)]
+pub struct RequestBackup {
+    dest_dir: Bytes,
+}
+
+/// An operation carried by a `Batch` request
+#[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord)]
+enum BatchOp {
+    Set { key: Bytes, value: Bytes },
+    Remove { key: Bytes },
 }
 
 /// The payload of a `Response` message
@@ -109,6 +163,15 @@ pub enum Response {
 
     /// Response of the type `Remove` Command
     Remove(ResponseRemove),
+
+    /// Response of the type `Batch` Command
+    Batch(ResponseBatch),
+
+    /// Response of the type `CompareAndSwap` Command
+    CompareAndSwap(ResponseCompareAndSwap),
+
+    /// Response of the type `Backup` admin Command
+    Backup(ResponseBackup),
 }
 
 /// A Response for a `Set` Command
@@ -121,15 +184,39 @@ pub struct ResponseSet {
 #[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord)]
 pub struct ResponseGet {
     code: StatusCode,
-    value: Option<String>,
+    value: Option<Bytes>,
 }
 
+/// A key or value carried by a message. It is (de)serialized as a byte string, which has the same
+/// representation in the protocol as a UTF-8 string
+#[derive(Debug, PartialEq, PartialOrd, Eq, Ord)]
+struct Bytes(Vec<u8>);
+
 /// A Response for a `Remove` Command
 #[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord)]
 pub struct ResponseRemove {
     code: StatusCode,
 }
 
+/// A Response for a `Batch` Command
+#[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord)]
+pub struct ResponseBatch {
+    code: StatusCode,
+}
+
+/// A Response for a `CompareAndSwap` Command. When the precondition fails, it carries the current value of the key
+#[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord)]
+pub struct ResponseCompareAndSwap {
+    code: StatusCode,
+    current: Option<Bytes>,
+}
+
+/// A Response for a `Backup` admin Command
+#[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord)]
+pub struct ResponseBackup {
+    code: StatusCode,
+}
+
 /// A Status code to be used in response messages to indicate if the command executed sucessfully or failed with which kind of error
 #[derive(Debug, PartialEq, PartialOrd, Eq, Ord, Primitive)]
 #[repr(u8)]
@@ -142,6 +229,9 @@ pub enum StatusCode {
 
     /// The operation failed with a fatal error on the server
     FatalError = 2,
+
+    /// The operation was not applied because the current value of the key is not the expected one
+    PreconditionFailed = 3,
 }
 
 impl std::fmt::Display for StatusCode {
@@ -150,6 +240,7 @@ impl std::fmt::Display for StatusCode {
             StatusCode::Ok => f.write_str("Status Ok (code: 0)"),
             StatusCode::KeyNotFound => f.write_str("Status KeyNotFound (code: 1)"),
             StatusCode::FatalError => f.write_str("Status FatalError (code: 2)"),
+            StatusCode::PreconditionFailed => f.write_str("Status PreconditionFailed (code: 3)"),
         }
     }
 }
@@ -159,6 +250,17 @@ impl Message {
     pub fn payload(&self) -> &MessagePayload {
         &self.payload
     }
+
+    /// request id field getter
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
+
+    /// Sets the id of the request the message is, or the one it responds to. Messages are created with the id 0.
+    pub fn with_request_id(mut self, request_id: u32) -> Self {
+        self.request_id = request_id;
+        self
+    }
 }
 
 impl std::convert::From<RequestSet> for MessagePayload {
@@ -179,6 +281,24 @@ impl std::convert::From<RequestRemove> for MessagePayload {
     }
 }
 
+impl std::convert::From<RequestBatch> for MessagePayload {
+    fn from(req: RequestBatch) -> Self {
+        MessagePayload::Request(Request::Batch(req))
+    }
+}
+
+impl std::convert::From<RequestCompareAndSwap> for MessagePayload {
+    fn from(req: RequestCompareAndSwap) -> Self {
+        MessagePayload::Request(Request::CompareAndSwap(req))
+    }
+}
+
+impl std::convert::From<RequestBackup> for MessagePayload {
+    fn from(req: RequestBackup) -> Self {
+        MessagePayload::Request(Request::Backup(req))
+    }
+}
+
 impl std::convert::From<ResponseSet> for MessagePayload {
     fn from(req: ResponseSet) -> Self {
         MessagePayload::Response(Response::Set(req))
@@ -197,50 +317,196 @@ impl std::convert::From<ResponseRemove> for MessagePayload {
     }
 }
 
+impl std::convert::From<ResponseBatch> for MessagePayload {
+    fn from(req: ResponseBatch) -> Self {
+        MessagePayload::Response(Response::Batch(req))
+    }
+}
+
+impl std::convert::From<ResponseCompareAndSwap> for MessagePayload {
+    fn from(req: ResponseCompareAndSwap) -> Self {
+        MessagePayload::Response(Response::CompareAndSwap(req))
+    }
+}
+
+impl std::convert::From<ResponseBackup> for MessagePayload {
+    fn from(req: ResponseBackup) -> Self {
+        MessagePayload::Response(Response::Backup(req))
+    }
+}
+
 impl RequestSet {
     /// Instantiate a new request message for the `Set` command
-    pub fn new_message(key: String, value: String) -> Message {
+    pub fn new_message<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(key: K, value: V) -> Message {
+        Message {
+            request_id: 0,
+            payload: MessagePayload::Request(Request::Set(RequestSet {
+                key: Bytes(key.into()),
+                value: Bytes(value.into()),
+                idempotency_key: None,
+            })),
+        }
+    }
+
+    /// Instantiate a new request message for the `Set` command, carrying the `idempotency_key` that lets the server
+    /// recognize the request when it is sent again, so that it is applied only once
+    pub fn new_idempotent_message<K, V, I>(key: K, value: V, idempotency_key: I) -> Message
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+        I: Into<Vec<u8>>,
+    {
         Message {
-            payload: MessagePayload::Request(Request::Set(RequestSet { key, value })),
+            request_id: 0,
+            payload: MessagePayload::Request(Request::Set(RequestSet {
+                key: Bytes(key.into()),
+                value: Bytes(value.into()),
+                idempotency_key: Some(Bytes(idempotency_key.into())),
+            })),
         }
     }
 
     /// Get a reference to the request set's key.
-    pub fn key(&self) -> &str {
-        self.key.as_str()
+    pub fn key(&self) -> &[u8] {
+        &self.key.0
     }
 
     /// Get a reference to the request set's value.
-    pub fn value(&self) -> &str {
-        self.value.as_str()
+    pub fn value(&self) -> &[u8] {
+        &self.value.0
+    }
+
+    /// Get a reference to the request set's idempotency key, if it has one.
+    pub fn idempotency_key(&self) -> Option<&[u8]> {
+        self.idempotency_key.as_ref().map(|key| &key.0[..])
     }
 }
 
 impl RequestGet {
     /// Instantiate a new request message for the `Get` command
-    pub fn new_message(key: String) -> Message {
+    pub fn new_message<K: Into<Vec<u8>>>(key: K) -> Message {
         Message {
-            payload: MessagePayload::Request(Request::Get(RequestGet { key })),
+            request_id: 0,
+            payload: MessagePayload::Request(Request::Get(RequestGet {
+                key: Bytes(key.into()),
+            })),
         }
     }
 
     /// Get a reference to the request get's key.
-    pub fn key(&self) -> &str {
-        self.key.as_str()
+    pub fn key(&self) -> &[u8] {
+        &self.key.0
     }
 }
 
 impl RequestRemove {
     /// Instantiate a new request message for the `Remove` command
-    pub fn new_message(key: String) -> Message {
+    pub fn new_message<K: Into<Vec<u8>>>(key: K) -> Message {
         Message {
-            payload: MessagePayload::Request(Request::Remove(RequestRemove { key })),
+            request_id: 0,
+            payload: MessagePayload::Request(Request::Remove(RequestRemove {
+                key: Bytes(key.into()),
+            })),
         }
     }
 
     /// Get a reference to the request remove's key.
-    pub fn key(&self) -> &str {
-        self.key.as_str()
+    pub fn key(&self) -> &[u8] {
+        &self.key.0
+    }
+}
+
+impl RequestBatch {
+    /// Instantiate a new request message for the `Batch` command
+    pub fn new_message(ops: Vec<Op>) -> Message {
+        let ops = ops
+            .into_iter()
+            .map(|op| match op {
+                Op::Set { key, value } => BatchOp::Set {
+                    key: Bytes(key),
+                    value: Bytes(value),
+                },
+                Op::Remove { key } => BatchOp::Remove { key: Bytes(key) },
+            })
+            .collect();
+        Message {
+            request_id: 0,
+            payload: MessagePayload::Request(Request::Batch(RequestBatch { ops })),
+        }
+    }
+
+    /// Get the number of operations in the request batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Tells if the request batch has no operations.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Consumes the request batch, returning its operations.
+    pub fn into_ops(self) -> Vec<Op> {
+        self.ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => Op::Set {
+                    key: key.0,
+                    value: value.0,
+                },
+                BatchOp::Remove { key } => Op::Remove { key: key.0 },
+            })
+            .collect()
+    }
+}
+
+impl RequestCompareAndSwap {
+    /// Instantiate a new request message for the `CompareAndSwap` command
+    pub fn new_message<K: Into<Vec<u8>>>(
+        key: K,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Message {
+        Message {
+            request_id: 0,
+            payload: MessagePayload::Request(Request::CompareAndSwap(RequestCompareAndSwap {
+                key: Bytes(key.into()),
+                expected: expected.map(Bytes),
+                new: new.map(Bytes),
+            })),
+        }
+    }
+
+    /// Get a reference to the request compare and swap's key.
+    pub fn key(&self) -> &[u8] {
+        &self.key.0
+    }
+
+    /// Get a reference to the request compare and swap's expected value.
+    pub fn expected(&self) -> Option<&[u8]> {
+        self.expected.as_ref().map(|v| v.0.as_slice())
+    }
+
+    /// Get a reference to the request compare and swap's new value.
+    pub fn new_value(&self) -> Option<&[u8]> {
+        self.new.as_ref().map(|v| v.0.as_slice())
+    }
+}
+
+impl RequestBackup {
+    /// Instantiate a new request message for the `Backup` admin command
+    pub fn new_message<P: Into<Vec<u8>>>(dest_dir: P) -> Message {
+        Message {
+            request_id: 0,
+            payload: MessagePayload::Request(Request::Backup(RequestBackup {
+                dest_dir: Bytes(dest_dir.into()),
+            })),
+        }
+    }
+
+    /// Get a reference to the request backup's destination directory, a path on the server.
+    pub fn dest_dir(&self) -> &[u8] {
+        &self.dest_dir.0
     }
 }
 
@@ -248,6 +514,7 @@ impl ResponseSet {
     /// Instantiate a new reponse message for the `Set` command
     pub fn new_message(code: StatusCode) -> Message {
         Message {
+            request_id: 0,
             payload: MessagePayload::Response(Response::Set(ResponseSet { code })),
         }
     }
@@ -260,9 +527,13 @@ impl ResponseSet {
 
 impl ResponseGet {
     /// Instantiate a new reponse message for the `Get` command
-    pub fn new_message(code: StatusCode, value: Option<String>) -> Message {
+    pub fn new_message(code: StatusCode, value: Option<Vec<u8>>) -> Message {
         Message {
-            payload: MessagePayload::Response(Response::Get(ResponseGet { code, value })),
+            request_id: 0,
+            payload: MessagePayload::Response(Response::Get(ResponseGet {
+                code,
+                value: value.map(Bytes),
+            })),
         }
     }
 
@@ -272,8 +543,8 @@ impl ResponseGet {
     }
 
     /// Get a reference to the response get's value.
-    pub fn value(&self) -> Option<&String> {
-        self.value.as_ref()
+    pub fn value(&self) -> Option<&[u8]> {
+        self.value.as_ref().map(|v| v.0.as_slice())
     }
 }
 
@@ -281,6 +552,7 @@ impl ResponseRemove {
     /// Instantiate a new reponse message for the `Remove` command
     pub fn new_message(code: StatusCode) -> Message {
         Message {
+            request_id: 0,
             payload: MessagePayload::Response(Response::Remove(ResponseRemove { code })),
         }
     }
@@ -291,6 +563,59 @@ impl ResponseRemove {
     }
 }
 
+impl ResponseBatch {
+    /// Instantiate a new reponse message for the `Batch` command
+    pub fn new_message(code: StatusCode) -> Message {
+        Message {
+            request_id: 0,
+            payload: MessagePayload::Response(Response::Batch(ResponseBatch { code })),
+        }
+    }
+
+    /// Get a reference to the response batch's code.
+    pub fn code(&self) -> &StatusCode {
+        &self.code
+    }
+}
+
+impl ResponseCompareAndSwap {
+    /// Instantiate a new reponse message for the `CompareAndSwap` command
+    pub fn new_message(code: StatusCode, current: Option<Vec<u8>>) -> Message {
+        Message {
+            request_id: 0,
+            payload: MessagePayload::Response(Response::CompareAndSwap(ResponseCompareAndSwap {
+                code,
+                current: current.map(Bytes),
+            })),
+        }
+    }
+
+    /// Get a reference to the response compare and swap's code.
+    pub fn code(&self) -> &StatusCode {
+        &self.code
+    }
+
+    /// Get a reference to the current value of the key when the precondition failed.
+    pub fn current(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|v| v.0.as_slice())
+    }
+}
+
+impl ResponseBackup {
+    /// Instantiate a new reponse message for the `Backup` admin command
+    pub fn new_message(code: StatusCode) -> Message {
+        Message {
+            request_id: 0,
+            payload: MessagePayload::Response(Response::Backup(ResponseBackup { code })),
+        }
+    }
+
+    /// Get a reference to the response backup's code.
+    pub fn code(&self) -> &StatusCode {
+        &self.code
+    }
+}
+
 impl<T> std::convert::From<&std::result::Result<T, super::KvStoreError>> for StatusCode
 where
     T: std::fmt::Debug,
@@ -301,6 +626,7 @@ where
         } else {
             match res.as_ref().unwrap_err() {
                 super::KvStoreError::RemoveNonExistentKey => StatusCode::KeyNotFound,
+                super::KvStoreError::CompareAndSwapFailed { .. } => StatusCode::PreconditionFailed,
                 _ => StatusCode::FatalError,
             }
         }
@@ -313,9 +639,15 @@ enum MessageType {
     ReqSet = 0,
     ReqGet = 1,
     ReqRemove = 2,
+    ReqBatch = 3,
+    ReqCompareAndSwap = 4,
+    ReqBackup = 5,
     RespSet = 0x80,
     RespGet = 0x81,
     RespRemove = 0x82,
+    RespBatch = 0x83,
+    RespCompareAndSwap = 0x84,
+    RespBackup = 0x85,
 }
 
 fn serialize_content<T, S>(
@@ -340,9 +672,10 @@ impl serde::ser::Serialize for Message {
     {
         let payload_length =
             ser::calc_len(&self.payload).map_err(|e| serde::ser::Error::custom(e.to_string()))?;
-        let mut s = serializer.serialize_tuple(3)?;
+        let mut s = serializer.serialize_tuple(4)?;
         s.serialize_element(&PROTOCOL_VERSION)?;
         s.serialize_element(&(payload_length as u32))?;
+        s.serialize_element(&self.request_id)?;
         s.serialize_element(&self.payload)?;
         s.end()
     }
@@ -366,14 +699,18 @@ impl<'de> serde::de::Deserialize<'de> for Message {
             where
                 A: serde::de::SeqAccess<'de>,
             {
-                seq.next_element::<Header>()?
-                    .ok_or(serde::de::Error::missing_field(
-                        "an protocol version number (u8)",
-                    ))?;
+                let header =
+                    seq.next_element::<Header>()?
+                        .ok_or(serde::de::Error::missing_field(
+                            "an protocol version number (u8)",
+                        ))?;
                 let payload = seq.next_element::<MessagePayload>()?.ok_or(
                     serde::de::Error::missing_field("a payload length number (u32)"),
                 )?;
-                Ok(Message { payload })
+                Ok(Message {
+                    request_id: header.request_id(),
+                    payload,
+                })
             }
         }
 
@@ -396,6 +733,15 @@ impl serde::ser::Serialize for MessagePayload {
             MessagePayload::Request(Request::Remove(c)) => {
                 serialize_content(c, MessageType::ReqRemove, serializer)
             }
+            MessagePayload::Request(Request::Batch(c)) => {
+                serialize_content(c, MessageType::ReqBatch, serializer)
+            }
+            MessagePayload::Request(Request::CompareAndSwap(c)) => {
+                serialize_content(c, MessageType::ReqCompareAndSwap, serializer)
+            }
+            MessagePayload::Request(Request::Backup(c)) => {
+                serialize_content(c, MessageType::ReqBackup, serializer)
+            }
             MessagePayload::Response(Response::Set(c)) => {
                 serialize_content(c, MessageType::RespSet, serializer)
             }
@@ -405,6 +751,15 @@ impl serde::ser::Serialize for MessagePayload {
             MessagePayload::Response(Response::Remove(c)) => {
                 serialize_content(c, MessageType::RespRemove, serializer)
             }
+            MessagePayload::Response(Response::Batch(c)) => {
+                serialize_content(c, MessageType::RespBatch, serializer)
+            }
+            MessagePayload::Response(Response::CompareAndSwap(c)) => {
+                serialize_content(c, MessageType::RespCompareAndSwap, serializer)
+            }
+            MessagePayload::Response(Response::Backup(c)) => {
+                serialize_content(c, MessageType::RespBackup, serializer)
+            }
         }
     }
 }
@@ -464,6 +819,19 @@ impl<'de> serde::de::Deserialize<'de> for MessagePayload {
                             let val: Result<RequestRemove, _> = deserialize_payload(&self, seq);
                             Ok(MessagePayload::from(val?))
                         }
+                        MessageType::ReqBatch => {
+                            let val: Result<RequestBatch, _> = deserialize_payload(&self, seq);
+                            Ok(MessagePayload::from(val?))
+                        }
+                        MessageType::ReqCompareAndSwap => {
+                            let val: Result<RequestCompareAndSwap, _> =
+                                deserialize_payload(&self, seq);
+                            Ok(MessagePayload::from(val?))
+                        }
+                        MessageType::ReqBackup => {
+                            let val: Result<RequestBackup, _> = deserialize_payload(&self, seq);
+                            Ok(MessagePayload::from(val?))
+                        }
                         MessageType::RespSet => {
                             let val: Result<ResponseSet, _> = deserialize_payload(&self, seq);
                             Ok(MessagePayload::from(val?))
@@ -476,6 +844,19 @@ impl<'de> serde::de::Deserialize<'de> for MessagePayload {
                             let val: Result<ResponseRemove, _> = deserialize_payload(&self, seq);
                             Ok(MessagePayload::from(val?))
                         }
+                        MessageType::RespBatch => {
+                            let val: Result<ResponseBatch, _> = deserialize_payload(&self, seq);
+                            Ok(MessagePayload::from(val?))
+                        }
+                        MessageType::RespCompareAndSwap => {
+                            let val: Result<ResponseCompareAndSwap, _> =
+                                deserialize_payload(&self, seq);
+                            Ok(MessagePayload::from(val?))
+                        }
+                        MessageType::RespBackup => {
+                            let val: Result<ResponseBackup, _> = deserialize_payload(&self, seq);
+                            Ok(MessagePayload::from(val?))
+                        }
                     };
                 }
                 return Err(serde::de::Error::missing_field(
@@ -487,6 +868,48 @@ impl<'de> serde::de::Deserialize<'de> for MessagePayload {
     }
 }
 
+impl serde::ser::Serialize for Bytes {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> serde::de::Deserialize<'de> for Bytes {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct BytesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for BytesVisitor {
+            type Value = Bytes;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a byte string")
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(Bytes(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(Bytes(v))
+            }
+        }
+
+        deserializer.deserialize_byte_buf(BytesVisitor {})
+    }
+}
+
 impl serde::ser::Serialize for StatusCode {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -534,8 +957,8 @@ impl<'de> serde::de::Deserialize<'de> for StatusCode {
 fn test_serde_request_set() {
     let cmd = RequestSet::new_message("key".to_owned(), "value".to_owned());
     let expected_serialized = vec![
-        0xC1, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x03, b'k', b'e', b'y', 0x00, 0x00,
-        0x00, 0x05, b'v', b'a', b'l', b'u', b'e',
+        0xC3, 0x00, 0x00, 0x00, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, b'k',
+        b'e', b'y', 0x00, 0x00, 0x00, 0x05, b'v', b'a', b'l', b'u', b'e', 0x00,
     ];
 
     let mut write_buf = Vec::new();
@@ -556,7 +979,8 @@ fn test_serde_request_set() {
 fn test_serde_request_get() {
     let cmd = RequestGet::new_message("key".to_owned());
     let expected_serialized = vec![
-        0xC1, 0x00, 0x00, 0x00, 0x08, 0x01, 0x00, 0x00, 0x00, 0x03, b'k', b'e', b'y',
+        0xC3, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03, b'k',
+        b'e', b'y',
     ];
 
     let mut write_buf = Vec::new();
@@ -577,7 +1001,8 @@ fn test_serde_request_get() {
 fn test_serde_request_rm() {
     let cmd = RequestRemove::new_message("key".to_owned());
     let expected_serialized = vec![
-        0xC1, 0x00, 0x00, 0x00, 0x08, 0x02, 0x00, 0x00, 0x00, 0x03, b'k', b'e', b'y',
+        0xC3, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03, b'k',
+        b'e', b'y',
     ];
 
     let mut write_buf = Vec::new();
@@ -597,7 +1022,9 @@ fn test_serde_request_rm() {
 #[test]
 fn test_serde_response_set() {
     let cmd = ResponseSet::new_message(StatusCode::Ok);
-    let expected_serialized = vec![0xC1, 0x00, 0x00, 0x00, 0x02, 0x80, 0x00];
+    let expected_serialized = vec![
+        0xC3, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00,
+    ];
 
     let mut write_buf = Vec::new();
     let cmd_len = ser::calc_len(&cmd);
@@ -615,10 +1042,10 @@ fn test_serde_response_set() {
 
 #[test]
 fn test_serde_response_get() {
-    let cmd = ResponseGet::new_message(StatusCode::Ok, Some("value".to_string()));
+    let cmd = ResponseGet::new_message(StatusCode::Ok, Some(b"value".to_vec()));
     let expected_serialized = vec![
-        0xC1, 0x00, 0x00, 0x00, 0x0C, 0x81, 0x00, 0x01, 0x00, 0x00, 0x00, 0x05, b'v', b'a', b'l',
-        b'u', b'e',
+        0xC3, 0x00, 0x00, 0x00, 0x0C, 0x00, 0x00, 0x00, 0x00, 0x81, 0x00, 0x01, 0x00, 0x00, 0x00,
+        0x05, b'v', b'a', b'l', b'u', b'e',
     ];
 
     let mut write_buf = Vec::new();
@@ -638,7 +1065,144 @@ fn test_serde_response_get() {
 #[test]
 fn test_serde_response_rm() {
     let cmd = ResponseRemove::new_message(StatusCode::Ok);
-    let expected_serialized = vec![0xC1, 0x00, 0x00, 0x00, 0x02, 0x82, 0x00];
+    let expected_serialized = vec![
+        0xC3, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x82, 0x00,
+    ];
+
+    let mut write_buf = Vec::new();
+    let cmd_len = ser::calc_len(&cmd);
+    assert!(cmd_len.is_ok());
+    write_buf.resize(cmd_len.unwrap(), 0);
+
+    let write_res = ser::to_bytes(&cmd, &mut write_buf[..]);
+    assert!(write_res.is_ok());
+
+    assert_eq!(write_buf, expected_serialized);
+    let cmd_deserialized: Result<Message, _> = de::from_bytes(&write_buf[..]);
+    assert!(cmd_deserialized.is_ok());
+    assert_eq!(cmd_deserialized.unwrap(), cmd);
+}
+
+#[test]
+fn test_serde_binary_request_set() {
+    let cmd = RequestSet::new_message(vec![0xFF, 0x00], vec![0x80, 0xC1, 0x00]);
+    let expected_serialized = vec![
+        0xC3, 0x00, 0x00, 0x00, 0x0F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xFF,
+        0x00, 0x00, 0x00, 0x00, 0x03, 0x80, 0xC1, 0x00, 0x00,
+    ];
+
+    let mut write_buf = Vec::new();
+    let cmd_len = ser::calc_len(&cmd);
+    assert!(cmd_len.is_ok());
+    write_buf.resize(cmd_len.unwrap(), 0);
+
+    let write_res = ser::to_bytes(&cmd, &mut write_buf[..]);
+    assert!(write_res.is_ok());
+
+    assert_eq!(write_buf, expected_serialized);
+    let cmd_deserialized: Result<Message, _> = de::from_bytes(&write_buf[..]);
+    assert!(cmd_deserialized.is_ok());
+    assert_eq!(cmd_deserialized.unwrap(), cmd);
+}
+
+#[test]
+fn test_serde_request_batch() {
+    let cmd = RequestBatch::new_message(vec![Op::set("k", "v"), Op::remove("key")]);
+    let expected_serialized = vec![
+        0xC3, 0x00, 0x00, 0x00, 0x1E, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x02, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, b'k', 0x00, 0x00, 0x00, 0x01, b'v', 0x00, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x03, b'k', b'e', b'y',
+    ];
+
+    let mut write_buf = Vec::new();
+    let cmd_len = ser::calc_len(&cmd);
+    assert!(cmd_len.is_ok());
+    write_buf.resize(cmd_len.unwrap(), 0);
+
+    let write_res = ser::to_bytes(&cmd, &mut write_buf[..]);
+    assert!(write_res.is_ok());
+
+    assert_eq!(write_buf, expected_serialized);
+    let cmd_deserialized: Result<Message, _> = de::from_bytes(&write_buf[..]);
+    assert!(cmd_deserialized.is_ok());
+    assert_eq!(cmd_deserialized.unwrap(), cmd);
+}
+
+#[test]
+fn test_serde_response_cas() {
+    let cmd = ResponseCompareAndSwap::new_message(StatusCode::PreconditionFailed, Some(vec![0xFF]));
+    let expected_serialized = vec![
+        0xC3, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x84, 0x03, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0xFF,
+    ];
+
+    let mut write_buf = Vec::new();
+    let cmd_len = ser::calc_len(&cmd);
+    assert!(cmd_len.is_ok());
+    write_buf.resize(cmd_len.unwrap(), 0);
+
+    let write_res = ser::to_bytes(&cmd, &mut write_buf[..]);
+    assert!(write_res.is_ok());
+
+    assert_eq!(write_buf, expected_serialized);
+    let cmd_deserialized: Result<Message, _> = de::from_bytes(&write_buf[..]);
+    assert!(cmd_deserialized.is_ok());
+    assert_eq!(cmd_deserialized.unwrap(), cmd);
+}
+
+#[test]
+fn test_serde_request_backup() {
+    let cmd = RequestBackup::new_message("/bk");
+    let expected_serialized = vec![
+        0xC3, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x03, b'/',
+        b'b', b'k',
+    ];
+
+    let mut write_buf = Vec::new();
+    let cmd_len = ser::calc_len(&cmd);
+    assert!(cmd_len.is_ok());
+    write_buf.resize(cmd_len.unwrap(), 0);
+
+    let write_res = ser::to_bytes(&cmd, &mut write_buf[..]);
+    assert!(write_res.is_ok());
+
+    assert_eq!(write_buf, expected_serialized);
+    let cmd_deserialized: Result<Message, _> = de::from_bytes(&write_buf[..]);
+    assert!(cmd_deserialized.is_ok());
+    assert_eq!(cmd_deserialized.unwrap(), cmd);
+}
+
+#[test]
+fn test_serde_request_id() {
+    let cmd = RequestGet::new_message("key").with_request_id(0x01020304);
+    let expected_serialized = vec![
+        0xC3, 0x00, 0x00, 0x00, 0x08, 0x01, 0x02, 0x03, 0x04, 0x01, 0x00, 0x00, 0x00, 0x03, b'k',
+        b'e', b'y',
+    ];
+
+    let mut write_buf = Vec::new();
+    let cmd_len = ser::calc_len(&cmd);
+    assert!(cmd_len.is_ok());
+    write_buf.resize(cmd_len.unwrap(), 0);
+
+    let write_res = ser::to_bytes(&cmd, &mut write_buf[..]);
+    assert!(write_res.is_ok());
+
+    assert_eq!(write_buf, expected_serialized);
+    let cmd_deserialized: Result<Message, _> = de::from_bytes(&write_buf[..]);
+    assert!(cmd_deserialized.is_ok());
+    let cmd_deserialized = cmd_deserialized.unwrap();
+    assert_eq!(cmd_deserialized.request_id(), 0x01020304);
+    assert_eq!(cmd_deserialized, cmd);
+}
+
+#[test]
+fn test_serde_request_idempotent_set() {
+    let cmd = RequestSet::new_idempotent_message("k", "v", "id");
+    let expected_serialized = vec![
+        0xC3, 0x00, 0x00, 0x00, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, b'k',
+        0x00, 0x00, 0x00, 0x01, b'v', 0x01, 0x00, 0x00, 0x00, 0x02, b'i', b'd',
+    ];
 
     let mut write_buf = Vec::new();
     let cmd_len = ser::calc_len(&cmd);
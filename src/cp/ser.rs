@@ -229,6 +229,7 @@ impl<'ser: 'a, 'a> serde::ser::Serializer for &'a mut Serializer<'ser> {
                     l
                 )));
             }
+            self.write_bytes(&u32::to_be_bytes(l as u32))?;
         }
         Ok(self)
     }
@@ -0,0 +1,92 @@
+use super::{KvStoreError, Result};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+/// Name of the file of a data directory locked by the process that has the store open
+pub const LOCK_FILE: &str = "LOCK";
+/// Number of times the PID of the holder of a lock is read before giving up, as it is written right after locking
+const HOLDER_PID_READ_ATTEMPTS: u32 = 10;
+/// Time waited between two reads of the PID of the holder of a lock
+const HOLDER_PID_READ_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An advisory lock on a data directory, held until dropped.
+/// The lock is taken with `flock` on the `LOCK` file of the directory, which then holds the PID of the holder.
+/// The operating system releases it if the process dies, so a stale `LOCK` file never keeps a directory locked.
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    /// Locks the directory at `dir_path`, creating its `LOCK` file if needed.
+    /// Returns `KvStoreError::DirectoryLocked` if another open file of the `LOCK` file, in this process or another,
+    /// holds the lock.
+    pub(crate) fn acquire(dir_path: &Path) -> Result<DirLock> {
+        let lock_path = dir_path.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // Truncated only once locked, since the file holds the PID of the holder of the lock meanwhile
+            .truncate(false)
+            .open(lock_path.as_path())?;
+        try_lock(&file, lock_path.as_path())?;
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        file.sync_data()?;
+        Ok(DirLock { file })
+    }
+
+    /// Checks that the directory at `dir_path` is not locked, without writing to it nor keeping it locked
+    pub(crate) fn check(dir_path: &Path) -> Result<()> {
+        let lock_path = dir_path.join(LOCK_FILE);
+        match File::open(lock_path.as_path()) {
+            Ok(file) => try_lock(&file, lock_path.as_path()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        // The PID of a process that no longer holds the lock must not be reported. Closing the file releases the lock.
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Takes the lock on the open `LOCK` file, without waiting for its holder to release it
+fn try_lock(file: &File, lock_path: &Path) -> Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if err.kind() != io::ErrorKind::WouldBlock {
+        return Err(err.into());
+    }
+    Err(KvStoreError::DirectoryLocked {
+        path: lock_path
+            .parent()
+            .map_or_else(PathBuf::new, Path::to_path_buf)
+            .display()
+            .to_string(),
+        pid: read_holder_pid(lock_path).unwrap_or(0),
+    })
+}
+
+/// Reads the PID written to the `LOCK` file by the holder of the lock
+fn read_holder_pid(lock_path: &Path) -> Option<u32> {
+    for _ in 0..HOLDER_PID_READ_ATTEMPTS {
+        if let Ok(pid) = fs::read_to_string(lock_path).ok()?.trim().parse() {
+            return Some(pid);
+        }
+        thread::sleep(HOLDER_PID_READ_INTERVAL);
+    }
+    None
+}
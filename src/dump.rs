@@ -0,0 +1,115 @@
+use super::{kvsengine::now_millis, KvsEngine, Op, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Number of records that never expire written by `load_dump` in a single batch
+const LOAD_BATCH_SIZE: usize = 1000;
+
+/// An iterator over the records of a dump, in ascending order of keys
+pub type DumpIterator = Box<dyn Iterator<Item = Result<DumpRecord>> + Send>;
+
+/// A live key-value pair of a database, along with its expiry time, as exported by `KvStore::dump`
+/// and `SledKvsEngine::dump`.
+///
+/// It is (de)serialized as a JSON object, where the key and the value are JSON strings when they are valid UTF-8,
+/// and arrays of bytes otherwise, so that a dump is readable while still holding any byte string.
+///
+/// # Examples
+///
+/// ```no_run
+/// use kvs::DumpRecord;
+/// let record: DumpRecord = serde_json::from_str(r#"{"key":"name","value":[255,0]}"#).unwrap();
+/// assert_eq!(record.key, b"name".to_vec());
+/// assert_eq!(record.value, vec![0xFF, 0x00]);
+/// assert_eq!(record.expires_at, None);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpRecord {
+    /// The key
+    #[serde(with = "json_bytes")]
+    pub key: Vec<u8>,
+    /// The value of the key
+    #[serde(with = "json_bytes")]
+    pub value: Vec<u8>,
+    /// The time, in milliseconds since the UNIX epoch, after which the key expires, or `None` if it never does
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+/// (De)serializes a byte string as a string when it is valid UTF-8, and as a sequence of bytes otherwise
+mod json_bytes {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::result::Result;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum JsonBytes {
+        Utf8(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub(super) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => bytes.serialize(serializer),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        Ok(match JsonBytes::deserialize(deserializer)? {
+            JsonBytes::Utf8(s) => s.into_bytes(),
+            JsonBytes::Bytes(bytes) => bytes,
+        })
+    }
+}
+
+/// Writes the `records` to the `engine`, batching the ones that never expire, and returns the number of records
+/// written. The records already expired are skipped, and the others expire at the same time as they did before.
+/// The records are not written atomically: on error, the ones before it may have been written.
+///
+/// # Examples
+///
+/// ```no_run
+/// use kvs::{load_dump, KvStore, SledKvsEngine};
+/// let source = KvStore::dump("./kvs").unwrap();
+/// let target = SledKvsEngine::open("./sled").unwrap();
+/// let count = load_dump(&target, source).unwrap();
+/// println!("{} keys copied", count);
+/// ```
+pub fn load_dump<E, I>(engine: &E, records: I) -> Result<u64>
+where
+    E: KvsEngine,
+    I: IntoIterator<Item = Result<DumpRecord>>,
+{
+    let mut batch = Vec::with_capacity(LOAD_BATCH_SIZE);
+    let mut count = 0;
+    for record in records {
+        let record = record?;
+        match record.expires_at {
+            None => {
+                batch.push(Op::set(record.key, record.value));
+                if batch.len() == LOAD_BATCH_SIZE {
+                    engine.write_batch(std::mem::take(&mut batch))?;
+                }
+            }
+            Some(expires_at) => {
+                let now = now_millis();
+                if expires_at <= now {
+                    continue;
+                }
+                engine.set_bytes_with_ttl(
+                    record.key,
+                    record.value,
+                    Duration::from_millis(expires_at - now),
+                )?;
+            }
+        }
+        count += 1;
+    }
+    if !batch.is_empty() {
+        engine.write_batch(batch)?;
+    }
+    Ok(count)
+}
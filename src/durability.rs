@@ -0,0 +1,64 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+/// Defines when the writes acknowledged by a database engine are forced to the stable storage.
+/// Each variant trades write latency for the amount of acknowledged writes that a crash of the machine may lose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Writes are left in the buffers of the operating system (or of the engine), which flushes them at its own pace.
+    /// It gives the lowest latency, but a crash may lose any write that was not flushed yet.
+    Buffered,
+
+    /// Every write is synced to the disk before returning.
+    /// No acknowledged write is ever lost, at the cost of one disk sync per write.
+    SyncEachWrite,
+
+    /// Every write is synced to the disk before returning, but concurrent writers waiting for a sync
+    /// share a single one. No acknowledged write is ever lost, and the cost of the sync is amortized under load.
+    GroupCommit,
+
+    /// Writes are synced to the disk periodically, in the background.
+    /// A crash may lose the writes acknowledged during the last interval.
+    Interval(Duration),
+}
+
+impl Durability {
+    /// Tells if writes must wait for a disk sync before returning
+    pub fn syncs_on_write(&self) -> bool {
+        matches!(self, Durability::SyncEachWrite | Durability::GroupCommit)
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::Buffered => f.write_str("buffered"),
+            Durability::SyncEachWrite => f.write_str("sync"),
+            Durability::GroupCommit => f.write_str("group"),
+            Durability::Interval(interval) => {
+                f.write_fmt(format_args!("interval:{}", interval.as_millis()))
+            }
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    /// Parses one of: `buffered`, `sync`, `group` or `interval:<milliseconds>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "buffered" => Ok(Durability::Buffered),
+            "sync" => Ok(Durability::SyncEachWrite),
+            "group" => Ok(Durability::GroupCommit),
+            _ => match s.strip_prefix("interval:").map(str::parse::<u64>) {
+                Some(Ok(millis)) if millis > 0 => {
+                    Ok(Durability::Interval(Duration::from_millis(millis)))
+                }
+                _ => Err(format!(
+                    "invalid durability mode: {}. Expected one of: buffered, sync, group, interval:<milliseconds>",
+                    s
+                )),
+            },
+        }
+    }
+}
@@ -14,6 +14,12 @@ pub enum KvStoreError {
     /// An error returned when the user tried to remove a key not found in the database
     #[fail(display = "Tried to remove a non existent key in the database.")]
     RemoveNonExistentKey,
+    /// An error returned when a compare and swap found a value different from the expected one
+    #[fail(display = "Compare and swap failed: the current value is not the expected one.")]
+    CompareAndSwapFailed {
+        /// The value of the key found by the compare and swap, or `None` if the key did not exist
+        current: Option<Vec<u8>>,
+    },
     /// An error returned when tried to read the file at an invalid offset
     #[fail(display = "Wrong file offset. File must have been corrupted.")]
     WrongFileOffset,
@@ -23,12 +29,66 @@ pub enum KvStoreError {
     /// An error that came from the sled crate
     #[fail(display = "Sled error: {}.", _0)]
     Sled(#[cause] sled::Error),
+    /// An error returned when a stored key or value is not a valid UTF-8 string
+    #[fail(display = "Utf8 error: {}.", _0)]
+    Utf8(#[cause] std::string::FromUtf8Error),
     /// An error returned when some of the files has unknown characters in name
     #[fail(display = "Wrong file name format.")]
     WrongFileNameFormat,
     /// An error returned by the thread pool
     #[fail(display = "Thread pool error: {}.", _0)]
     ThreadPoolBuild(#[cause] crate::thread_pool::ThreadPoolError),
+    /// An error returned when a log file does not start with the expected magic number
+    #[fail(display = "Log file {} has an invalid header.", log_id)]
+    InvalidLogFileHeader {
+        /// Id of the log file
+        log_id: u64,
+    },
+    /// An error returned when a log file was written with a newer version of the log format
+    #[fail(
+        display = "Log file {} has unsupported format version {}.",
+        log_id, version
+    )]
+    UnsupportedLogFormatVersion {
+        /// Id of the log file
+        log_id: u64,
+        /// Format version found in the log file header
+        version: u32,
+    },
+    /// An error returned when a record that is not at the end of a log file fails its checksum
+    #[fail(
+        display = "Corrupted record in log file {} at offset {}.",
+        log_id, offset
+    )]
+    CorruptedLog {
+        /// Id of the log file
+        log_id: u64,
+        /// Offset of the corrupted record in the log file
+        offset: u64,
+    },
+    /// An error returned when a checkpoint is taken to a directory that already holds some files
+    #[fail(display = "Checkpoint directory {} is not empty.", path)]
+    CheckpointDirNotEmpty {
+        /// Path of the checkpoint directory
+        path: String,
+    },
+    /// An error returned when a backup is asked for in a directory that would be out of the backup directory
+    #[fail(
+        display = "Backup directory {} is not a relative path within the backup directory.",
+        path
+    )]
+    InvalidBackupDir {
+        /// Path of the refused backup directory
+        path: String,
+    },
+    /// An error returned when the data directory of a store is locked by another open store
+    #[fail(display = "Directory {} is locked by the process {}.", path, pid)]
+    DirectoryLocked {
+        /// Path of the locked directory
+        path: String,
+        /// PID of the process holding the lock, or 0 if it could not be read
+        pid: u32,
+    },
 }
 
 impl From<bincode::Error> for KvStoreError {
@@ -61,6 +121,12 @@ impl From<sled::Error> for KvStoreError {
     }
 }
 
+impl From<std::string::FromUtf8Error> for KvStoreError {
+    fn from(error: std::string::FromUtf8Error) -> Self {
+        Self::Utf8(error)
+    }
+}
+
 impl From<thread_pool::ThreadPoolError> for KvStoreError {
     fn from(err: thread_pool::ThreadPoolError) -> Self {
         Self::ThreadPoolBuild(err)
@@ -1,15 +1,144 @@
-use crate::cp::*;
+use crate::{cp::*, Op};
+use parking_lot::{Condvar, Mutex};
+use rand::Rng;
 use smallvec::{smallvec, SmallVec};
 use std::{
     convert,
     fmt::{self},
-    io::{self, prelude::*},
+    io::{self, prelude::*, BufWriter},
     net::{SocketAddr, TcpStream},
+    thread,
+    time::Duration,
 };
 
-/// KVS store system tcp client
+/// Maximum number of requests of a pipeline sent ahead of the responses received, so that neither side
+/// fills the buffers of the connection and blocks on its writes while the other one does the same
+const PIPELINE_WINDOW: usize = 64;
+
+/// Default value of `KvClientBuilder::pool_size`
+const DEFAULT_POOL_SIZE: usize = 8;
+/// Default value of `KvClientBuilder::connect_timeout`
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Default value of `KvClientBuilder::read_timeout` and `KvClientBuilder::write_timeout`
+const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default value of `RetryPolicy::initial_backoff`
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+/// Default value of `RetryPolicy::max_backoff`
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(1);
+/// Number of retries of the default `RetryPolicy` of a `KvClient`
+const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// KVS store system tcp client.
+/// The commands are sent over a pool of connections to the server, shared by all the threads using the client.
+/// A connection is opened when a command finds none idle in the pool, as long as the pool is not full, and kept open
+/// for the following ones. Otherwise the command waits for a connection to be released by another one.
+/// A connection closed by the server, as it does once idle for too long, is dropped from the pool.
+///
+/// The commands that are safe to send again, the gets and the sets given an idempotency key, are retried
+/// according to the `RetryPolicy` of the client when they fail because of the connection or a timeout.
 pub struct KvClient {
     server_address: SocketAddr,
+    pool: ConnectionPool,
+    retry_policy: RetryPolicy,
+}
+
+/// Settings used to create a `KvClient`, built with chained calls starting from `KvClientBuilder::new()`
+///
+/// # Examples
+///
+/// ```no_run
+/// use kvs::{KvClientBuilder, RetryPolicy};
+/// use std::time::Duration;
+/// let client = KvClientBuilder::new()
+///     .pool_size(32)
+///     .read_timeout(Duration::from_secs(1))
+///     .retry_policy(RetryPolicy::new(5).max_backoff(Duration::from_millis(500)))
+///     .build("127.0.0.1:4000")
+///     .unwrap();
+/// client.send_cmd_set("key".to_owned(), "value".to_owned()).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvClientBuilder {
+    pool_size: usize,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    retry_policy: RetryPolicy,
+}
+
+/// How a `KvClient` retries the commands that are safe to send again when they fail because of the connection
+/// or a timeout. The retries wait for an exponential backoff, which doubles after each retry up to a maximum,
+/// and of which a random part, up to a half, is skipped so that the clients failing together do not retry together.
+///
+/// # Examples
+///
+/// ```no_run
+/// use kvs::RetryPolicy;
+/// use std::time::Duration;
+/// let policy = RetryPolicy::new(5)
+///     .initial_backoff(Duration::from_millis(50))
+///     .max_backoff(Duration::from_secs(2));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+/// The connections to the server of a `KvClient`
+struct ConnectionPool {
+    max_size: usize,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    state: Mutex<PoolState>,
+    /// Notified whenever a connection is released to the pool or closed
+    released: Condvar,
+}
+
+struct PoolState {
+    /// The open connections not in use, the most recently used one last
+    idle: Vec<TcpStream>,
+    /// Number of open connections, idle or in use
+    open: usize,
+}
+
+/// A connection taken from the pool. It is closed when dropped, unless it was released back to the pool.
+struct PooledConnection<'a> {
+    pool: &'a ConnectionPool,
+    stream: Option<TcpStream>,
+    /// Tells if the connection was used by previous commands, so that the server may have closed it since
+    reused: bool,
+    /// Number of responses received by the last exchange
+    received: usize,
+}
+
+/// A sequence of commands sent to the server at once with `KvClient::send_pipeline`, without waiting for the
+/// response of a command before sending the next one
+///
+/// # Examples
+///
+/// ```no_run
+/// use kvs::{KvClient, KvPipeline, PipelineReply};
+/// let client = KvClient::new("127.0.0.1:4000").unwrap();
+/// let mut pipeline = KvPipeline::new();
+/// pipeline.set("key1", "value1").get("key1").rm("key1");
+/// let replies = client.send_pipeline(pipeline).unwrap();
+/// assert_eq!(replies[1].as_ref().unwrap(), &PipelineReply::Value(Some(b"value1".to_vec())));
+/// ```
+#[derive(Debug, Default)]
+pub struct KvPipeline {
+    requests: Vec<Message>,
+}
+
+/// The result of a successful command of a `KvPipeline`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipelineReply {
+    /// A set, rm, batch or compare and swap command was applied
+    Done,
+    /// The value of the key of a get command, or `None` if the key was not found
+    Value(Option<Vec<u8>>),
 }
 
 /// Error return by the kvs client api
@@ -38,6 +167,42 @@ pub enum KvClientError<'a> {
     /// A specific kind of error happend for the communication protocol:
     ///   The client received a request message back from the server
     CommunicationProtocolMessageWrongKind,
+
+    /// The value received from the server is not a valid UTF-8 string
+    InvalidUtf8Value(std::string::FromUtf8Error),
+
+    /// The compare and swap was not applied because the current value of the key is not the expected one
+    CompareAndSwapFailed {
+        /// The current value of the key, or `None` if the key does not exist
+        current: Option<Vec<u8>>,
+    },
+
+    /// A specific kind of error happend for the communication protocol:
+    ///   The client received a response to a request it did not send, or to a request already answered
+    UnexpectedRequestId(u32),
+
+    /// The connection to the server was not established within the connect timeout
+    ConnectTimeout,
+
+    /// The server did not send a response within the read timeout
+    ReadTimeout,
+
+    /// The request was not sent to the server within the write timeout
+    WriteTimeout,
+
+    /// The command failed every time it was sent, as many times as allowed by the retry policy
+    RetriesExhausted {
+        /// Number of times the command was sent
+        attempts: u32,
+        /// The error of the last attempt
+        last: Box<KvClientError<'a>>,
+    },
+
+    /// A setting of the client builder was given a value of 0
+    ZeroSetting {
+        /// Name of the setting
+        setting: &'static str,
+    },
 }
 
 impl<'a> fmt::Display for KvClientError<'a> {
@@ -56,6 +221,29 @@ impl<'a> fmt::Display for KvClientError<'a> {
             KvClientError::CommunicationProtocolMessageWrongKind => {
                 f.write_str("KVS Communication protocol error: client received a request message")
             }
+            KvClientError::InvalidUtf8Value(err) => {
+                f.write_fmt(format_args!("Value is not a valid UTF-8 string: {}", err))
+            }
+            KvClientError::CompareAndSwapFailed { .. } => {
+                f.write_str("Compare and swap failed: the current value is not the expected one")
+            }
+            KvClientError::UnexpectedRequestId(request_id) => f.write_fmt(format_args!(
+                "KVS Communication protocol error: client received a response to unknown request {}",
+                request_id
+            )),
+            KvClientError::ConnectTimeout => f.write_str("Timed out connecting to the server"),
+            KvClientError::ReadTimeout => {
+                f.write_str("Timed out waiting for the response of the server")
+            }
+            KvClientError::WriteTimeout => f.write_str("Timed out sending the request to the server"),
+            KvClientError::RetriesExhausted { attempts, last } => f.write_fmt(format_args!(
+                "Command failed after {} attempts. Last error: {}",
+                attempts, last
+            )),
+            KvClientError::ZeroSetting { setting } => f.write_fmt(format_args!(
+                "Invalid client settings: the {} must not be 0",
+                setting
+            )),
         }
     }
 }
@@ -68,16 +256,79 @@ impl<'a> convert::From<io::Error> for KvClientError<'a> {
     }
 }
 
+impl<'a> convert::From<std::string::FromUtf8Error> for KvClientError<'a> {
+    fn from(err: std::string::FromUtf8Error) -> Self {
+        KvClientError::InvalidUtf8Value(err)
+    }
+}
+
 impl<'a> convert::From<error::Error> for KvClientError<'a> {
     fn from(err: error::Error) -> Self {
         KvClientError::CommunicationProtocolError(err)
     }
 }
 
-impl KvClient {
-    /// Creates a new instance of a KVClient given the server address
-    pub fn new<'a>(server_addr: &'a str) -> Result<Self, KvClientError<'a>> {
-        Ok(Self {
+impl KvClientBuilder {
+    /// Creates the default settings: a pool of at most 8 connections, a connect timeout of 5 seconds,
+    /// read and write timeouts of 30 seconds, and up to 3 retries of the commands safe to send again
+    pub fn new() -> Self {
+        Self {
+            pool_size: DEFAULT_POOL_SIZE,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_IO_TIMEOUT,
+            write_timeout: DEFAULT_IO_TIMEOUT,
+            retry_policy: RetryPolicy::new(DEFAULT_MAX_RETRIES),
+        }
+    }
+
+    /// Sets the maximum number of connections open at the same time, that is, the number of commands sent
+    /// in parallel by the threads sharing the client. It must not be 0, or `build` fails.
+    pub fn pool_size(mut self, pool_size: usize) -> Self {
+        self.pool_size = pool_size;
+        self
+    }
+
+    /// Sets how long opening a connection to the server may take, after which `KvClientError::ConnectTimeout`
+    /// is returned. It must not be 0, or `build` fails.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Sets how long the server may take to send a response once the request was sent, after which
+    /// `KvClientError::ReadTimeout` is returned. It must not be 0, or `build` fails, and must leave the server the
+    /// time to write the backups asked for.
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Sets how long sending a request may wait for the server to read the previous ones, after which
+    /// `KvClientError::WriteTimeout` is returned. It must not be 0, or `build` fails.
+    pub fn write_timeout(mut self, write_timeout: Duration) -> Self {
+        self.write_timeout = write_timeout;
+        self
+    }
+
+    /// Sets how the commands safe to send again are retried
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Creates a new instance of a KvClient given the server address. No connection is opened until the first command.
+    /// Returns `KvClientError::ZeroSetting` if the pool size or a timeout is 0.
+    pub fn build<'a>(&self, server_addr: &'a str) -> Result<KvClient, KvClientError<'a>> {
+        let settings = [
+            ("pool size", self.pool_size == 0),
+            ("connect timeout", self.connect_timeout.is_zero()),
+            ("read timeout", self.read_timeout.is_zero()),
+            ("write timeout", self.write_timeout.is_zero()),
+        ];
+        if let Some((setting, _)) = settings.iter().find(|(_, is_zero)| *is_zero) {
+            return Err(KvClientError::ZeroSetting { setting });
+        }
+        Ok(KvClient {
             server_address: match server_addr.parse::<SocketAddr>() {
                 Ok(addr) => addr,
                 Err(err) => {
@@ -87,76 +338,657 @@ impl KvClient {
                     })
                 }
             },
+            pool: ConnectionPool {
+                max_size: self.pool_size,
+                connect_timeout: self.connect_timeout,
+                read_timeout: self.read_timeout,
+                write_timeout: self.write_timeout,
+                state: Mutex::new(PoolState {
+                    idle: Vec::new(),
+                    open: 0,
+                }),
+                released: Condvar::new(),
+            },
+            retry_policy: self.retry_policy.clone(),
         })
     }
+}
+
+impl Default for KvClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RetryPolicy {
+    /// Creates a policy retrying a command up to `max_retries` times, waiting 10 milliseconds before
+    /// the first retry and at most 1 second before the following ones
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+
+    /// Creates a policy that never retries a command
+    pub fn never() -> Self {
+        Self::new(0)
+    }
+
+    /// Sets the time waited before the first retry
+    pub fn initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Sets the maximum time waited before a retry
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Get the maximum number of retries of a command.
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    /// Computes the time waited before the retry following the `retries` already done
+    fn backoff(&self, retries: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .checked_mul(2u32.saturating_pow(retries))
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff));
+        let half = backoff.as_nanos() as u64 / 2;
+        backoff - Duration::from_nanos(rand::thread_rng().gen_range(0, half + 1))
+    }
+}
+
+impl KvClient {
+    /// Creates a new instance of a KVClient given the server address, with the default settings of `KvClientBuilder`
+    pub fn new<'a>(server_addr: &'a str) -> Result<Self, KvClientError<'a>> {
+        KvClientBuilder::new().build(server_addr)
+    }
 
     /// Sends a command set, given the `key` and `value`, to the server over a tcp connection and get the ok
     /// result back if the operation completed sucessfully or the error if it failed
     pub fn send_cmd_set(&self, key: String, value: String) -> Result<(), KvClientError<'static>> {
-        let mut stream = std::net::TcpStream::connect(&self.server_address)?;
-        let msg = RequestSet::new_message(key, value);
-        KvClient::send_request(&msg, &mut stream)?;
-        match KvClient::recv_payload(&mut stream)? {
-            MessagePayload::Response(Response::Set(r)) => match r.code() {
-                StatusCode::KeyNotFound => Err(KvClientError::KeyNotFound),
-                StatusCode::FatalError => Err(KvClientError::ServerError),
-                StatusCode::Ok => Ok(()),
-            },
-            _ => Err(KvClientError::CommunicationProtocolMessageWrongKind),
-        }
+        self.send_cmd_set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sends a command set, given the byte string `key` and `value`, to the server over a tcp connection and
+    /// get the ok result back if the operation completed sucessfully or the error if it failed
+    pub fn send_cmd_set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<(), KvClientError<'static>> {
+        set_result(self.send_request(RequestSet::new_message(key, value))?)
+    }
+
+    /// Sends a command set, given the `key`, `value` and `idempotency_key`, to the server over a tcp connection and
+    /// get the ok result back if the operation completed sucessfully or the error if it failed.
+    /// The server applies only once the sets given the same idempotency key, among the last ones it applied,
+    /// so the command is retried according to the retry policy of the client. The idempotency key must be unique
+    /// to this set of the key to the value, for instance a random identifier.
+    pub fn send_cmd_set_idempotent(
+        &self,
+        key: String,
+        value: String,
+        idempotency_key: String,
+    ) -> Result<(), KvClientError<'static>> {
+        self.send_cmd_set_bytes_idempotent(
+            key.into_bytes(),
+            value.into_bytes(),
+            idempotency_key.into_bytes(),
+        )
+    }
+
+    /// Sends a command set, given the byte string `key`, `value` and `idempotency_key`, to the server over a tcp
+    /// connection and get the ok result back if the operation completed sucessfully or the error if it failed.
+    /// The command is retried like the one of `send_cmd_set_idempotent`.
+    pub fn send_cmd_set_bytes_idempotent(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        idempotency_key: Vec<u8>,
+    ) -> Result<(), KvClientError<'static>> {
+        set_result(
+            self.send_retried_request(RequestSet::new_idempotent_message(
+                key,
+                value,
+                idempotency_key,
+            ))?,
+        )
     }
 
     /// Sends a command get, given the `key`, to the server over a tcp connection and get the ok result back
-    /// if the operation completed sucessfully with the `key`'s `value` or the error if it failed
+    /// if the operation completed sucessfully with the `key`'s `value` or the error if it failed.
+    /// The command is retried according to the retry policy of the client.
+    /// The value must be a valid UTF-8 string, otherwise `KvClientError::InvalidUtf8Value` is returned
     pub fn send_cmd_get(&self, key: String) -> Result<Option<String>, KvClientError<'static>> {
-        let mut stream = std::net::TcpStream::connect(&self.server_address)?;
-        let msg = RequestGet::new_message(key);
-        KvClient::send_request(&msg, &mut stream)?;
-        match KvClient::recv_payload(&mut stream)? {
-            MessagePayload::Response(Response::Get(r)) => match r.code() {
-                StatusCode::KeyNotFound => Ok(None),
-                StatusCode::FatalError => Err(KvClientError::ServerError),
-                StatusCode::Ok => match r.value() {
-                    Some(s) => Ok(Some(s.clone())),
-                    None => Ok(None),
-                },
-            },
-            _ => Err(KvClientError::CommunicationProtocolMessageWrongKind),
+        match self.send_cmd_get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
         }
     }
 
+    /// Sends a command get, given the byte string `key`, to the server over a tcp connection and get the ok
+    /// result back if the operation completed sucessfully with the `key`'s `value` or the error if it failed
+    pub fn send_cmd_get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, KvClientError<'static>> {
+        get_result(self.send_retried_request(RequestGet::new_message(key))?)
+    }
+
     /// Sends a command rm, given the `key`, to the server over a tcp connection and get the ok
     /// result back if the operation completed sucessfully or the error if it failed
     pub fn send_cmd_rm(&self, key: String) -> Result<(), KvClientError<'static>> {
-        let mut stream = std::net::TcpStream::connect(&self.server_address)?;
-        let msg = RequestRemove::new_message(key);
-        KvClient::send_request(&msg, &mut stream)?;
-        match KvClient::recv_payload(&mut stream)? {
-            MessagePayload::Response(Response::Remove(r)) => match r.code() {
-                StatusCode::KeyNotFound => Err(KvClientError::KeyNotFound),
-                StatusCode::FatalError => Err(KvClientError::ServerError),
-                StatusCode::Ok => Ok(()),
-            },
-            _ => Err(KvClientError::CommunicationProtocolMessageWrongKind),
+        self.send_cmd_rm_bytes(key.into_bytes())
+    }
+
+    /// Sends a command rm, given the byte string `key`, to the server over a tcp connection and get the ok
+    /// result back if the operation completed sucessfully or the error if it failed
+    pub fn send_cmd_rm_bytes(&self, key: Vec<u8>) -> Result<(), KvClientError<'static>> {
+        rm_result(self.send_request(RequestRemove::new_message(key))?)
+    }
+
+    /// Sends a command batch, given the `ops`, to the server over a single tcp connection and get the ok
+    /// result back if all the operations were applied atomically or the error if none of them was
+    pub fn send_batch(&self, ops: Vec<Op>) -> Result<(), KvClientError<'static>> {
+        batch_result(self.send_request(RequestBatch::new_message(ops))?)
+    }
+
+    /// Sends a command compare and swap, given the `key`, the `expected` value and the `new` value, to the server
+    /// over a tcp connection and get the ok result back if the value was swapped or the error if it was not.
+    /// A `None` expected value means that the key must not exist, and a `None` new value removes the key.
+    pub fn send_cmd_cas(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<(), KvClientError<'static>> {
+        self.send_cmd_cas_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Sends a command compare and swap, given the byte string `key`, the `expected` value and the `new` value,
+    /// to the server over a tcp connection and get the ok result back if the value was swapped or the error if
+    /// it was not. A `None` expected value means that the key must not exist, and a `None` new value removes the key.
+    pub fn send_cmd_cas_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<(), KvClientError<'static>> {
+        cas_result(self.send_request(RequestCompareAndSwap::new_message(key, expected, new))?)
+    }
+
+    /// Sends an admin command backup, given the `dest_dir` where the server writes a checkpoint of its database,
+    /// over a tcp connection and get the ok result back if the checkpoint was written or the error if it failed.
+    /// The `dest_dir` is a path on the server, relative to its backup directory, and must not exist or be empty.
+    /// An absolute `dest_dir`, or one with `..` components, is refused by the server.
+    pub fn send_cmd_backup(&self, dest_dir: String) -> Result<(), KvClientError<'static>> {
+        backup_result(self.send_request(RequestBackup::new_message(dest_dir))?)
+    }
+
+    /// Sends the commands of the `pipeline` to the server over a single connection, without waiting for the response
+    /// of a command before sending the next one, and returns the result of each command in the same order.
+    /// The commands are applied by the server one after the other, in the order of the pipeline.
+    /// An error of the connection itself fails the whole pipeline, as the commands sent may have been applied or not.
+    pub fn send_pipeline(
+        &self,
+        pipeline: KvPipeline,
+    ) -> Result<Vec<Result<PipelineReply, KvClientError<'static>>>, KvClientError<'static>> {
+        Ok(self
+            .send_requests(pipeline.requests)?
+            .into_iter()
+            .map(pipeline_reply)
+            .collect())
+    }
+
+    /// Sends the request `msg` to the server and returns the payload of its response
+    fn send_request(&self, msg: Message) -> Result<MessagePayload, KvClientError<'static>> {
+        let mut payloads = self.send_requests(vec![msg])?;
+        Ok(payloads.remove(0))
+    }
+
+    /// Sends the request `msg`, which is safe to send again, to the server and returns the payload of its response.
+    /// The request is sent again, according to the retry policy, as long as it fails because of the connection
+    /// or a timeout.
+    fn send_retried_request(&self, msg: Message) -> Result<MessagePayload, KvClientError<'static>> {
+        let msgs = [msg];
+        let mut retries = 0;
+        loop {
+            let err = match self.exchange(&msgs) {
+                Ok(mut payloads) => return Ok(payloads.remove(0)),
+                Err(err) if is_retryable(&err) => err,
+                Err(err) => return Err(err),
+            };
+            if retries == self.retry_policy.max_retries {
+                if retries == 0 {
+                    return Err(err);
+                }
+                return Err(KvClientError::RetriesExhausted {
+                    attempts: retries + 1,
+                    last: Box::new(err),
+                });
+            }
+            thread::sleep(self.retry_policy.backoff(retries));
+            retries += 1;
+        }
+    }
+
+    /// Sends the requests `msgs` to the server and returns the payloads of their responses in the same order
+    fn send_requests(
+        &self,
+        msgs: Vec<Message>,
+    ) -> Result<Vec<MessagePayload>, KvClientError<'static>> {
+        // The requests of an exchange are numbered from 0, as the connection is done with the previous ones
+        let msgs: Vec<Message> = msgs
+            .into_iter()
+            .enumerate()
+            .map(|(i, msg)| msg.with_request_id(i as u32))
+            .collect();
+        self.exchange(&msgs)
+    }
+
+    /// Sends the numbered requests `msgs` over a connection of the pool and returns the payloads of their
+    /// responses in the same order. If a connection reused from the pool turns out to be closed before any response
+    /// is received, the requests are sent again over a new connection, but only if they are all safe to send again,
+    /// as the server may have applied them before closing the connection.
+    fn exchange(&self, msgs: &[Message]) -> Result<Vec<MessagePayload>, KvClientError<'static>> {
+        let mut conn = self.pool.get(self.server_address)?;
+        let payloads = match conn.exchange(msgs) {
+            Err(KvClientError::IoError(err))
+                if conn.reused
+                    && conn.received == 0
+                    && is_closed_error(&err)
+                    && msgs.iter().all(is_safe_to_resend) =>
+            {
+                drop(conn);
+                conn = self.pool.connect(self.server_address)?;
+                conn.exchange(msgs)?
+            }
+            res => res?,
+        };
+        // A connection that failed may be left in the middle of a message, so it is only released on success
+        conn.release();
+        Ok(payloads)
+    }
+}
+
+impl ConnectionPool {
+    /// Takes an open connection from the pool, opening a new one if none is idle and the pool is not full,
+    /// or waiting for one to be released otherwise
+    fn get(&self, addr: SocketAddr) -> Result<PooledConnection<'_>, KvClientError<'static>> {
+        let mut state = self.state.lock();
+        loop {
+            while let Some(stream) = state.idle.pop() {
+                if is_open(&stream) {
+                    return Ok(PooledConnection {
+                        pool: self,
+                        stream: Some(stream),
+                        reused: true,
+                        received: 0,
+                    });
+                }
+                state.open -= 1;
+            }
+            if state.open < self.max_size {
+                drop(state);
+                return self.connect(addr);
+            }
+            self.released.wait(&mut state);
+        }
+    }
+
+    /// Opens a new connection, counted as open by the pool even before it is connected, so that the pool never
+    /// exceeds its size while the lock is released
+    fn connect(&self, addr: SocketAddr) -> Result<PooledConnection<'_>, KvClientError<'static>> {
+        self.state.lock().open += 1;
+        let stream = match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+            Err(err) if is_timeout_error(&err) => Err(KvClientError::ConnectTimeout),
+            res => res
+                .and_then(|stream| {
+                    stream.set_nodelay(true)?;
+                    stream.set_read_timeout(Some(self.read_timeout))?;
+                    stream.set_write_timeout(Some(self.write_timeout))?;
+                    Ok(stream)
+                })
+                .map_err(KvClientError::from),
+        };
+        match stream {
+            Ok(stream) => Ok(PooledConnection {
+                pool: self,
+                stream: Some(stream),
+                reused: false,
+                received: 0,
+            }),
+            Err(err) => {
+                self.closed();
+                Err(err)
+            }
+        }
+    }
+
+    /// Frees the place in the pool of a connection closed, for a command waiting for one
+    fn closed(&self) {
+        self.state.lock().open -= 1;
+        self.released.notify_one();
+    }
+}
+
+impl<'a> PooledConnection<'a> {
+    /// Puts the connection back to the pool, for the following commands
+    fn release(mut self) {
+        if let Some(stream) = self.stream.take() {
+            self.pool.state.lock().idle.push(stream);
+            self.pool.released.notify_one();
         }
     }
 
-    fn send_request(msg: &Message, stream: &mut TcpStream) -> Result<(), error::Error> {
-        let mut buf = SmallVec::<[u8; 256]>::new();
-        buf.resize(ser::calc_len(msg)?, 0u8);
-        ser::to_bytes(msg, &mut buf[..])?;
-        stream.write_all(&buf[..])?;
-        Ok(())
+    /// Sends the requests `msgs`, keeping at most `PIPELINE_WINDOW` of them waiting for their responses,
+    /// and returns the payloads of the responses in the order of the requests, which are numbered from 0
+    fn exchange(
+        &mut self,
+        msgs: &[Message],
+    ) -> Result<Vec<MessagePayload>, KvClientError<'static>> {
+        self.received = 0;
+        let stream = self.stream.as_ref().expect("a pooled connection is open");
+        let mut payloads: Vec<Option<MessagePayload>> = msgs.iter().map(|_| None).collect();
+        // The requests are written together, until the window is full
+        let mut writer = BufWriter::new(stream);
+        for (i, msg) in msgs.iter().enumerate() {
+            if i - self.received == PIPELINE_WINDOW {
+                writer.flush().map_err(write_error)?;
+                recv_response(stream, &mut payloads)?;
+                self.received += 1;
+            }
+            send_request(msg, &mut writer)?;
+        }
+        writer.flush().map_err(write_error)?;
+        drop(writer);
+        while self.received < payloads.len() {
+            recv_response(stream, &mut payloads)?;
+            self.received += 1;
+        }
+        Ok(payloads.into_iter().flatten().collect())
+    }
+}
+
+impl<'a> Drop for PooledConnection<'a> {
+    fn drop(&mut self) {
+        // Not released back to the pool: the connection is closed
+        if self.stream.take().is_some() {
+            self.pool.closed();
+        }
+    }
+}
+
+/// Receives the next response and stores its payload in the slot of its request
+fn recv_response(
+    stream: &TcpStream,
+    payloads: &mut [Option<MessagePayload>],
+) -> Result<(), KvClientError<'static>> {
+    let (request_id, payload) = recv_payload(stream)?;
+    match payloads.get_mut(request_id as usize) {
+        Some(slot @ None) => {
+            *slot = Some(payload);
+            Ok(())
+        }
+        _ => Err(KvClientError::UnexpectedRequestId(request_id)),
+    }
+}
+
+fn send_request<W: Write>(msg: &Message, stream: &mut W) -> Result<(), KvClientError<'static>> {
+    let mut buf = SmallVec::<[u8; 256]>::new();
+    buf.resize(ser::calc_len(msg)?, 0u8);
+    ser::to_bytes(msg, &mut buf[..])?;
+    stream.write_all(&buf[..]).map_err(write_error)?;
+    Ok(())
+}
+
+fn recv_payload(mut stream: &TcpStream) -> Result<(u32, MessagePayload), KvClientError<'static>> {
+    let mut header_buf = [0u8; HEADER_SIZE];
+    stream.read_exact(&mut header_buf).map_err(read_error)?;
+    let header: Result<Header, _> = de::from_bytes(&header_buf);
+    let header = header?;
+
+    let mut payload_buf: SmallVec<[u8; 256]> = smallvec![0; header.payload_length() as usize];
+    stream.read_exact(&mut payload_buf).map_err(read_error)?;
+    Ok((header.request_id(), de::from_bytes(&payload_buf)?))
+}
+
+impl KvPipeline {
+    /// Creates an empty pipeline
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn recv_payload(stream: &mut TcpStream) -> Result<MessagePayload, error::Error> {
-        let mut header_buf = [0u8; HEADER_SIZE];
-        stream.read_exact(&mut header_buf)?;
-        let header: Result<Header, _> = de::from_bytes(&header_buf);
-        let header = header?;
+    /// Adds a command set of the `key` to the `value`
+    pub fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&mut self, key: K, value: V) -> &mut Self {
+        self.requests.push(RequestSet::new_message(key, value));
+        self
+    }
+
+    /// Adds a command get of the `key`
+    pub fn get<K: Into<Vec<u8>>>(&mut self, key: K) -> &mut Self {
+        self.requests.push(RequestGet::new_message(key));
+        self
+    }
+
+    /// Adds a command rm of the `key`
+    pub fn rm<K: Into<Vec<u8>>>(&mut self, key: K) -> &mut Self {
+        self.requests.push(RequestRemove::new_message(key));
+        self
+    }
+
+    /// Adds a command batch of the `ops`, which are applied atomically
+    pub fn batch(&mut self, ops: Vec<Op>) -> &mut Self {
+        self.requests.push(RequestBatch::new_message(ops));
+        self
+    }
 
-        let mut payload_buf: SmallVec<[u8; 256]> = smallvec![0; header.payload_length() as usize];
-        stream.read_exact(&mut payload_buf)?;
-        de::from_bytes(&payload_buf)
+    /// Adds a command compare and swap of the `key` from the `expected` value to the `new` value
+    pub fn cas<K: Into<Vec<u8>>>(
+        &mut self,
+        key: K,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> &mut Self {
+        self.requests
+            .push(RequestCompareAndSwap::new_message(key, expected, new));
+        self
+    }
+
+    /// Get the number of commands in the pipeline.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Tells if the pipeline has no commands.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+/// Tells if the server has not closed the connection, which it does once the connection stays idle for too long.
+/// Nothing is ever waiting to be read from an open connection between two exchanges.
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let open = matches!(
+        stream.peek(&mut [0u8; 1]),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock
+    );
+    stream.set_nonblocking(false).is_ok() && open
+}
+
+/// Tells if the io error `err` is the one of a connection closed by the server
+fn is_closed_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Tells if the io error `err` is the one of an operation that timed out
+fn is_timeout_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Converts the io error `err` of a read of a response into its client error
+fn read_error(err: io::Error) -> KvClientError<'static> {
+    if is_timeout_error(&err) {
+        KvClientError::ReadTimeout
+    } else {
+        KvClientError::IoError(err)
+    }
+}
+
+/// Converts the io error `err` of a write of a request into its client error
+fn write_error(err: io::Error) -> KvClientError<'static> {
+    if is_timeout_error(&err) {
+        KvClientError::WriteTimeout
+    } else {
+        KvClientError::IoError(err)
+    }
+}
+
+/// Tells if the command that failed with `err` may succeed if sent again: it failed because of the connection
+/// or a timeout, and not because of the server or the protocol
+fn is_retryable(err: &KvClientError) -> bool {
+    matches!(
+        err,
+        KvClientError::IoError(_)
+            | KvClientError::ConnectTimeout
+            | KvClientError::ReadTimeout
+            | KvClientError::WriteTimeout
+    )
+}
+
+/// Tells if the request `msg` can be applied twice without changing the outcome, which is the case of a get
+/// and of a set with an idempotency key
+fn is_safe_to_resend(msg: &Message) -> bool {
+    match msg.payload() {
+        MessagePayload::Request(Request::Get(_)) => true,
+        MessagePayload::Request(Request::Set(req)) => req.idempotency_key().is_some(),
+        _ => false,
+    }
+}
+
+/// Converts the payload of the response of a command of a pipeline into its reply
+fn pipeline_reply(payload: MessagePayload) -> Result<PipelineReply, KvClientError<'static>> {
+    let done = match payload {
+        MessagePayload::Response(Response::Get(_)) => {
+            return get_result(payload).map(PipelineReply::Value)
+        }
+        MessagePayload::Response(Response::Set(_)) => set_result(payload),
+        MessagePayload::Response(Response::Remove(_)) => rm_result(payload),
+        MessagePayload::Response(Response::Batch(_)) => batch_result(payload),
+        MessagePayload::Response(Response::CompareAndSwap(_)) => cas_result(payload),
+        MessagePayload::Response(Response::Backup(_)) => backup_result(payload),
+        MessagePayload::Request(_) => Err(KvClientError::CommunicationProtocolMessageWrongKind),
+    };
+    done.map(|()| PipelineReply::Done)
+}
+
+/// Converts the payload of the response of a set command into its result
+pub(crate) fn set_result(payload: MessagePayload) -> Result<(), KvClientError<'static>> {
+    match payload {
+        MessagePayload::Response(Response::Set(r)) => match r.code() {
+            StatusCode::KeyNotFound => Err(KvClientError::KeyNotFound),
+            StatusCode::FatalError | StatusCode::PreconditionFailed => {
+                Err(KvClientError::ServerError)
+            }
+            StatusCode::Ok => Ok(()),
+        },
+        _ => Err(KvClientError::CommunicationProtocolMessageWrongKind),
+    }
+}
+
+/// Converts the payload of the response of a get command into its result
+pub(crate) fn get_result(
+    payload: MessagePayload,
+) -> Result<Option<Vec<u8>>, KvClientError<'static>> {
+    match payload {
+        MessagePayload::Response(Response::Get(r)) => match r.code() {
+            StatusCode::KeyNotFound => Ok(None),
+            StatusCode::FatalError | StatusCode::PreconditionFailed => {
+                Err(KvClientError::ServerError)
+            }
+            StatusCode::Ok => match r.value() {
+                Some(v) => Ok(Some(v.to_vec())),
+                None => Ok(None),
+            },
+        },
+        _ => Err(KvClientError::CommunicationProtocolMessageWrongKind),
+    }
+}
+
+/// Converts the payload of the response of a rm command into its result
+pub(crate) fn rm_result(payload: MessagePayload) -> Result<(), KvClientError<'static>> {
+    match payload {
+        MessagePayload::Response(Response::Remove(r)) => match r.code() {
+            StatusCode::KeyNotFound => Err(KvClientError::KeyNotFound),
+            StatusCode::FatalError | StatusCode::PreconditionFailed => {
+                Err(KvClientError::ServerError)
+            }
+            StatusCode::Ok => Ok(()),
+        },
+        _ => Err(KvClientError::CommunicationProtocolMessageWrongKind),
+    }
+}
+
+/// Converts the payload of the response of a batch command into its result
+fn batch_result(payload: MessagePayload) -> Result<(), KvClientError<'static>> {
+    match payload {
+        MessagePayload::Response(Response::Batch(r)) => match r.code() {
+            StatusCode::KeyNotFound => Err(KvClientError::KeyNotFound),
+            StatusCode::FatalError | StatusCode::PreconditionFailed => {
+                Err(KvClientError::ServerError)
+            }
+            StatusCode::Ok => Ok(()),
+        },
+        _ => Err(KvClientError::CommunicationProtocolMessageWrongKind),
+    }
+}
+
+/// Converts the payload of the response of a compare and swap command into its result
+fn cas_result(payload: MessagePayload) -> Result<(), KvClientError<'static>> {
+    match payload {
+        MessagePayload::Response(Response::CompareAndSwap(r)) => match r.code() {
+            StatusCode::PreconditionFailed => Err(KvClientError::CompareAndSwapFailed {
+                current: r.current().map(<[u8]>::to_vec),
+            }),
+            StatusCode::KeyNotFound | StatusCode::FatalError => Err(KvClientError::ServerError),
+            StatusCode::Ok => Ok(()),
+        },
+        _ => Err(KvClientError::CommunicationProtocolMessageWrongKind),
+    }
+}
+
+/// Converts the payload of the response of a backup command into its result
+fn backup_result(payload: MessagePayload) -> Result<(), KvClientError<'static>> {
+    match payload {
+        MessagePayload::Response(Response::Backup(r)) => match r.code() {
+            StatusCode::KeyNotFound | StatusCode::FatalError | StatusCode::PreconditionFailed => {
+                Err(KvClientError::ServerError)
+            }
+            StatusCode::Ok => Ok(()),
+        },
+        _ => Err(KvClientError::CommunicationProtocolMessageWrongKind),
     }
 }
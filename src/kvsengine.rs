@@ -1,7 +1,249 @@
-use super::Result;
+use super::{CheckpointManifest, CompactionEstimate, CompactionLimits, CompactionProgress, Result};
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-/// Models a key-value database engine with a very simplified interface
+/// An iterator over the key-value pairs returned by a scan, in ascending order of keys
+pub type KvsIterator = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
+
+/// An iterator over the raw key-value pairs returned by a scan, in ascending order of keys
+pub type KvsBytesIterator = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
+
+/// A write operation to be applied as part of a batch by `KvsEngine::write_batch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Set the `value` of the `key`
+    Set {
+        /// The key to be set
+        key: Vec<u8>,
+        /// The new value of the key
+        value: Vec<u8>,
+    },
+
+    /// Remove the `key`. Unlike `KvsEngine::remove`, removing a key that does not exist is not an error
+    Remove {
+        /// The key to be removed
+        key: Vec<u8>,
+    },
+}
+
+impl Op {
+    /// Creates an operation that sets the `value` of the `key`
+    pub fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(key: K, value: V) -> Self {
+        Op::Set {
+            key: key.into(),
+            value: value.into(),
+        }
+    }
+
+    /// Creates an operation that removes the `key`
+    pub fn remove<K: Into<Vec<u8>>>(key: K) -> Self {
+        Op::Remove { key: key.into() }
+    }
+}
+
+/// Models a key-value database engine with a very simplified interface.
+///
+/// Keys and values are arbitrary byte strings. The methods taking and returning `String`s are a convenience
+/// layer over the byte oriented ones, which fails with `KvStoreError::Utf8` when reading bytes that are not
+/// valid UTF-8.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// The read-only view of the engine returned by `snapshot`
+    type Snapshot: KvsSnapshot;
+
+    /// Set the `value` of a byte string `key` to a byte string.
+    /// Return an error if the `value` is not written successfully.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::KvStore;
+    /// use kvs::KvsEngine;
+    ///
+    /// let images = KvStore::open("./").unwrap();
+    /// images.set_bytes(b"pixel".to_vec(), vec![0xFF, 0x00, 0x80]);
+    /// assert_eq!(images.get_bytes(b"pixel".to_vec()).unwrap(), Some(vec![0xFF, 0x00, 0x80]));
+    /// ```
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Get the byte string value of a byte string `key`.
+    /// If the `key` does not exist, return `None`.
+    /// Return an error if the value is not read successfully.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::KvStore;
+    /// use kvs::KvsEngine;
+    ///
+    /// let images = KvStore::open("./").unwrap();
+    /// images.set_bytes(b"pixel".to_vec(), vec![0xFF, 0x00, 0x80]);
+    /// assert_eq!(images.get_bytes(b"pixel".to_vec()).unwrap(), Some(vec![0xFF, 0x00, 0x80]));
+    /// ```
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Remove a given byte string `key`.
+    /// Return an error if the `key` does not exist or is not removed successfully.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::KvStore;
+    /// use kvs::KvsEngine;
+    ///
+    /// let images = KvStore::open("./").unwrap();
+    /// images.set_bytes(b"pixel".to_vec(), vec![0xFF, 0x00, 0x80]);
+    /// images.remove_bytes(b"pixel".to_vec());
+    /// assert_eq!(images.get_bytes(b"pixel".to_vec()).unwrap(), None);
+    /// ```
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Iterate over all the raw key-value pairs whose keys are within `range`, in ascending order of keys.
+    /// Return an error if the scan can not be started, or an error item if a value is not read successfully.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::KvStore;
+    /// use kvs::KvsEngine;
+    ///
+    /// let images = KvStore::open("./").unwrap();
+    /// images.set_bytes(vec![1, 0], vec![0xFF]);
+    /// images.set_bytes(vec![1, 1], vec![0x00]);
+    /// images.set_bytes(vec![2, 0], vec![0x80]);
+    /// let pairs = images
+    ///     .scan_bytes(vec![1, 1]..)
+    ///     .unwrap()
+    ///     .collect::<Result<Vec<_>, _>>()
+    ///     .unwrap();
+    /// assert_eq!(pairs, vec![(vec![1, 1], vec![0x00]), (vec![2, 0], vec![0x80])]);
+    /// ```
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsBytesIterator>;
+
+    /// Iterate over all the raw key-value pairs whose keys start with `prefix`, in ascending order of keys.
+    /// Return an error if the scan can not be started, or an error item if a value is not read successfully.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::KvStore;
+    /// use kvs::KvsEngine;
+    ///
+    /// let images = KvStore::open("./").unwrap();
+    /// images.set_bytes(vec![1, 0], vec![0xFF]);
+    /// images.set_bytes(vec![1, 1], vec![0x00]);
+    /// images.set_bytes(vec![2, 0], vec![0x80]);
+    /// assert_eq!(images.scan_prefix_bytes(vec![1]).unwrap().count(), 2);
+    /// ```
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<KvsBytesIterator>;
+
+    /// Apply all the `ops` in order, as a single atomic write: after a crash either all of them or none
+    /// of them are found in the database. Concurrent readers may observe the batch while it is being applied.
+    /// Return an error if the batch is not written successfully, in which case none of the `ops` is applied.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::{KvStore, KvsEngine, Op};
+    ///
+    /// let accounts = KvStore::open("./").unwrap();
+    /// accounts.set("alice".to_owned(), "10".to_owned());
+    /// accounts.write_batch(vec![
+    ///     Op::remove("alice"),
+    ///     Op::set("bob", "10"),
+    /// ]);
+    /// assert_eq!(accounts.get("alice".to_owned()).unwrap(), None);
+    /// assert_eq!(accounts.get("bob".to_owned()).unwrap(), Some("10".to_owned()));
+    /// ```
+    fn write_batch(&self, ops: Vec<Op>) -> Result<()>;
+
+    /// Atomically set the value of a byte string `key` to `new` if its current value is `expected`.
+    /// An `expected` value of `None` means that the key must not exist, and a `new` value of `None` removes the key.
+    /// Return `KvStoreError::CompareAndSwapFailed` with the current value if it is not the expected one,
+    /// or another error if the new value is not written successfully.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::{KvStore, KvStoreError, KvsEngine};
+    ///
+    /// let leases = KvStore::open("./").unwrap();
+    /// leases.compare_and_swap_bytes(b"leader".to_vec(), None, Some(b"node1".to_vec())).unwrap();
+    /// match leases.compare_and_swap_bytes(b"leader".to_vec(), None, Some(b"node2".to_vec())) {
+    ///     Err(KvStoreError::CompareAndSwapFailed { current }) => assert_eq!(current, Some(b"node1".to_vec())),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()>;
+
+    /// Set the `value` of a byte string `key` to a byte string that expires after `ttl`.
+    /// Once expired, the key is treated as if it did not exist. Setting the key again without a ttl makes it permanent.
+    /// Return an error if the `value` is not written successfully.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::KvStore;
+    /// use kvs::KvsEngine;
+    /// use std::time::Duration;
+    ///
+    /// let sessions = KvStore::open("./").unwrap();
+    /// sessions.set_bytes_with_ttl(b"token".to_vec(), vec![0x2A], Duration::from_secs(60));
+    /// assert_eq!(sessions.get_bytes(b"token".to_vec()).unwrap(), Some(vec![0x2A]));
+    /// ```
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Take a read-only view of the database as it is now, which keeps seeing the same state while writes go on.
+    /// No write is ever seen half-applied by the snapshot, and the keys expiring later are still seen by it.
+    /// Return an error if the snapshot can not be taken.
+    ///
+    /// `KvStore` copies the location of every key, but none of the values, and only holds back the writes while
+    /// doing so. `SledKvsEngine` has no snapshots of its own, so it only holds back the writes while registering the
+    /// snapshot, after which every write saves in it the value the keys it changes had before: the memory taken grows
+    /// with the number of keys changed while the snapshot is alive, and the writes are run one at a time meanwhile.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::{KvStore, KvsEngine, KvsSnapshot};
+    ///
+    /// let user_data = KvStore::open("./").unwrap();
+    /// user_data.set("name".to_owned(), "John".to_owned()).unwrap();
+    /// let snapshot = user_data.snapshot().unwrap();
+    /// user_data.set("name".to_owned(), "Mary".to_owned()).unwrap();
+    /// assert_eq!(snapshot.get("name".to_owned()).unwrap(), Some("John".to_owned()));
+    /// assert_eq!(user_data.get("name".to_owned()).unwrap(), Some("Mary".to_owned()));
+    /// ```
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// Write a consistent copy of the database to `dest_dir` while it keeps serving reads,
+    /// along with a manifest listing the files of the copy, which is written last.
+    /// The directory is created if it does not exist, and must be empty otherwise.
+    /// The copy is opened with the same engine, like any other database directory.
+    /// Return the manifest, or an error if the copy is not written successfully.
+    ///
+    /// `KvStore` links its log files into the copy and only holds back the writes to seal the current one.
+    /// `SledKvsEngine` writes every live key-value pair of a snapshot to a new database one by one, while the writes
+    /// go on.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::{KvStore, KvsEngine};
+    ///
+    /// let user_data = KvStore::open("./").unwrap();
+    /// user_data.set("name".to_owned(), "John".to_owned()).unwrap();
+    /// user_data.checkpoint("./backup").unwrap();
+    /// let restored = KvStore::open("./backup").unwrap();
+    /// assert_eq!(restored.get("name".to_owned()).unwrap(), Some("John".to_owned()));
+    /// ```
+    fn checkpoint<P: AsRef<Path>>(&self, dest_dir: P) -> Result<CheckpointManifest>;
+
     /// Set the `value` of a string `key` to a string.
     /// Return an error if the `value` is not written successfully.
     ///
@@ -21,11 +263,31 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// user_data.set("age".to_owned(), "22".to_owned());
     /// assert_eq!(user_data.get("age".to_owned()).unwrap(), Some("22".to_owned()));
     /// ```
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Set the `value` of a string `key` to a string that expires after `ttl`.
+    /// Return an error if the `value` is not written successfully.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::KvStore;
+    /// use std::time::Duration;
+    ///
+    /// let sessions = KvStore::open("./").unwrap();
+    /// sessions.set_with_ttl("session".to_owned(), "John".to_owned(), Duration::from_millis(10));
+    /// std::thread::sleep(Duration::from_millis(20));
+    /// assert_eq!(sessions.get("session".to_owned()).unwrap(), None);
+    /// ```
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
 
     /// Get the string value of a string `key`.
     /// If the `key` does not exist, return `None`.
-    /// Return an error if the value is not read successfully.
+    /// Return an error if the value is not read successfully or if it is not valid UTF-8.
     ///
     /// # Examples
     ///
@@ -36,7 +298,12 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// user_data.set("age".to_owned(), "21".to_owned());
     /// assert_eq!(user_data.get("name".to_owned()).unwrap(), Some("John".to_owned()));
     /// ```
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
 
     /// Remove a given `key`.
     /// Return an error if the `key` does not exist or is not removed successfully.
@@ -52,13 +319,218 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// user_data.remove("name".to_owned());
     /// assert_eq!(user_data.get("name".to_owned()).unwrap(), None);
     /// ```
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Iterate over all the key-value pairs whose keys are within `range`, in ascending order of keys.
+    /// Return an error if the scan can not be started, or an error item if a value is not read successfully
+    /// or if a key or value is not valid UTF-8.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::KvStore;
+    /// let mut user_data = KvStore::open("./").unwrap();
+    /// user_data.set("a".to_owned(), "1".to_owned());
+    /// user_data.set("b".to_owned(), "2".to_owned());
+    /// user_data.set("c".to_owned(), "3".to_owned());
+    /// let pairs = user_data
+    ///     .scan("a".to_owned().."c".to_owned())
+    ///     .unwrap()
+    ///     .collect::<Result<Vec<_>, _>>()
+    ///     .unwrap();
+    /// assert_eq!(pairs, vec![("a".to_owned(), "1".to_owned()), ("b".to_owned(), "2".to_owned())]);
+    /// ```
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<KvsIterator> {
+        let range = (
+            bytes_bound(range.start_bound()),
+            bytes_bound(range.end_bound()),
+        );
+        Ok(into_strings(self.scan_bytes(range)?))
+    }
+
+    /// Iterate over all the key-value pairs whose keys start with `prefix`, in ascending order of keys.
+    /// Return an error if the scan can not be started, or an error item if a value is not read successfully
+    /// or if a key or value is not valid UTF-8.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::KvStore;
+    /// let mut user_data = KvStore::open("./").unwrap();
+    /// user_data.set("user/1/name".to_owned(), "John".to_owned());
+    /// user_data.set("user/1/age".to_owned(), "21".to_owned());
+    /// user_data.set("user/2/name".to_owned(), "Mary".to_owned());
+    /// let user1_keys = user_data
+    ///     .scan_prefix("user/1/".to_owned())
+    ///     .unwrap()
+    ///     .map(|r| r.unwrap().0)
+    ///     .collect::<Vec<_>>();
+    /// assert_eq!(user1_keys, vec!["user/1/age".to_owned(), "user/1/name".to_owned()]);
+    /// ```
+    fn scan_prefix(&self, prefix: String) -> Result<KvsIterator> {
+        Ok(into_strings(self.scan_prefix_bytes(prefix.into_bytes())?))
+    }
+
+    /// Atomically set the value of a string `key` to `new` if its current value is `expected`.
+    /// An `expected` value of `None` means that the key must not exist, and a `new` value of `None` removes the key.
+    /// Return `KvStoreError::CompareAndSwapFailed` with the current value if it is not the expected one,
+    /// or another error if the new value is not written successfully.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::KvStore;
+    /// let mut user_data = KvStore::open("./").unwrap();
+    /// user_data.set("balance".to_owned(), "10".to_owned());
+    /// assert!(user_data
+    ///     .compare_and_swap("balance".to_owned(), Some("10".to_owned()), Some("5".to_owned()))
+    ///     .is_ok());
+    /// assert!(user_data
+    ///     .compare_and_swap("balance".to_owned(), Some("10".to_owned()), Some("0".to_owned()))
+    ///     .is_err());
+    /// ```
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+}
+
+/// Models a read-only view of a database engine at the point in time it was taken, as returned by
+/// `KvsEngine::snapshot`. The snapshot and the iterators it returns keep the state they see from being reclaimed,
+/// so they should not be kept around for longer than needed.
+pub trait KvsSnapshot: Clone + Send + 'static {
+    /// Get the byte string value of a byte string `key` at the time of the snapshot.
+    /// If the `key` did not exist, return `None`.
+    /// Return an error if the value is not read successfully.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Iterate over all the raw key-value pairs of the snapshot whose keys are within `range`,
+    /// in ascending order of keys.
+    /// Return an error if the scan can not be started, or an error item if a value is not read successfully.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsBytesIterator>;
+
+    /// Iterate over all the raw key-value pairs of the snapshot whose keys start with `prefix`,
+    /// in ascending order of keys.
+    /// Return an error if the scan can not be started, or an error item if a value is not read successfully.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<KvsBytesIterator>;
+
+    /// Get the string value of a string `key` at the time of the snapshot.
+    /// If the `key` did not exist, return `None`.
+    /// Return an error if the value is not read successfully or if it is not valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Iterate over all the key-value pairs of the snapshot whose keys are within `range`, in ascending order of keys.
+    /// Return an error if the scan can not be started, or an error item if a value is not read successfully
+    /// or if a key or value is not valid UTF-8.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<KvsIterator> {
+        let range = (
+            bytes_bound(range.start_bound()),
+            bytes_bound(range.end_bound()),
+        );
+        Ok(into_strings(self.scan_bytes(range)?))
+    }
+
+    /// Iterate over all the key-value pairs of the snapshot whose keys start with `prefix`, in ascending order of keys.
+    /// Return an error if the scan can not be started, or an error item if a value is not read successfully
+    /// or if a key or value is not valid UTF-8.
+    fn scan_prefix(&self, prefix: String) -> Result<KvsIterator> {
+        Ok(into_strings(self.scan_prefix_bytes(prefix.into_bytes())?))
+    }
 }
 
 /// Models a database archive compactor
 pub trait KvsCompactor {
-    /// Checks and run compaction strategy
+    /// Checks and run compaction strategy, without any limit on the work done
     fn compact(&self) -> Result<()> {
+        self.compact_with(&CompactionLimits::new()).map(|_| ())
+    }
+
+    /// Tells how much space a compaction would reclaim if it ran now, and how much it would have to rewrite
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::{KvStore, KvsCompactor};
+    ///
+    /// let dictionary = KvStore::open("./").unwrap();
+    /// if dictionary.compaction_needed().unwrap().is_needed() {
+    ///     dictionary.compact().unwrap();
+    /// }
+    /// ```
+    fn compaction_needed(&self) -> Result<CompactionEstimate> {
+        Ok(CompactionEstimate::default())
+    }
+
+    /// Checks and run compaction strategy, stopping once the budget of the `limits` is spent and
+    /// throttling its I/O to their rate. Returns the work done, which does not cover every log file picked
+    /// if the compaction ran out of time, or nothing if another compaction was already running.
+    fn compact_with(&self, _limits: &CompactionLimits) -> Result<CompactionProgress> {
+        Ok(CompactionProgress::default())
+    }
+
+    /// Returns the work done so far by the running compaction, or `None` if no compaction is running
+    fn compaction_progress(&self) -> Option<CompactionProgress> {
+        None
+    }
+
+    /// Removes the expired keys, so they stop taking up memory and disk space before they are read again
+    fn evict_expired(&self) -> Result<()> {
         Ok(())
     }
 }
+
+/// Returns the current time in milliseconds since the UNIX epoch, the unit of the expiry timestamps.
+/// A key expires once this time reaches its expiry timestamp.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Returns the expiry timestamp of a key set now with the given `ttl`
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Tells if no key can be within the `range`, which is the case when its start bound is after its end bound
+pub(crate) fn is_empty_range<K: Ord, R: RangeBounds<K>>(range: &R) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// Converts a bound on a string key into a bound on its bytes. Both sort in the same order.
+fn bytes_bound(bound: Bound<&String>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.clone().into_bytes()),
+        Bound::Excluded(key) => Bound::Excluded(key.clone().into_bytes()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Converts an iterator over raw key-value pairs into an iterator over UTF-8 strings
+fn into_strings(iter: KvsBytesIterator) -> KvsIterator {
+    Box::new(iter.map(|r| {
+        let (key, value) = r?;
+        Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+    }))
+}
@@ -1,32 +1,54 @@
-use crate::KvsCompactor;
+use crate::{CompactionLimits, CompactionScheduler, KvsCompactor};
 
-use super::{cp::*, kvsengine::KvsEngine, thread_pool::ThreadPool};
+use super::{cp::*, kvsengine::KvsEngine, thread_pool::ThreadPool, KvStoreError};
 use mio::{
     net::{TcpListener, TcpStream},
-    {Events, Interest, Poll, Token},
+    {Events, Interest, Poll, Token, Waker},
 };
 use mio_signals::{Signal, Signals};
 use mio_timerfd::{ClockId, TimerFd};
+use parking_lot::{Condvar, Mutex};
 use slog::Logger;
-use smallvec::{smallvec, SmallVec};
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
     error::Error,
+    ffi::OsStr,
     fmt,
-    io::prelude::*,
+    io::{self, prelude::*},
     net::SocketAddr,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::{Duration, Instant, SystemTime},
 };
 
 const SERVER_TOKEN: Token = Token(0);
 const SERVER_TIMER_TOKEN: Token = Token(1);
 const SERVER_SIGNALS_TOKEN: Token = Token(2);
+const SERVER_WAKER_TOKEN: Token = Token(3);
+/// The connections get the tokens that follow this one, in the order they are accepted
+const FIRST_CONNECTION_TOKEN: Token = Token(4);
 
 const SERVER_TIMER_CHECK_PERIOD: std::time::Duration = std::time::Duration::from_millis(100);
-const SERVER_COMPACTION_PERIOD: std::time::Duration = std::time::Duration::from_secs(5);
+const SERVER_EVICTION_PERIOD: std::time::Duration = std::time::Duration::from_secs(5);
 const POLL_ATTEMPTS: u16 = 10;
+/// Default time a connection is kept open without receiving any request
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Default directory the backups are written to, the working directory of the server
+const DEFAULT_BACKUP_DIR: &str = ".";
+/// Number of bytes read from a connection at once
+const READ_CHUNK_SIZE: usize = 4096;
+/// Number of bytes received by a connection, holding at least a whole request, after which it is no longer read
+/// until its requests are served
+const READ_BUFFER_LIMIT: usize = 64 * 1024;
+/// Number of bytes of the responses of a connection not yet written, after which its requests are no longer served
+/// until the peer reads them
+const WRITE_BUFFER_LIMIT: usize = 64 * 1024;
+/// Number of idempotency keys of the last sets applied remembered by the server, to recognize the sets sent again
+const IDEMPOTENCY_KEYS_CAPACITY: usize = 100_000;
 
 /// Macro to unwrap the Ok of a result or if Err, log and returns the control flow to the caller
 #[macro_export]
@@ -63,6 +85,11 @@ pub struct KvServer<Engine, Tp> {
     thread_pool: Tp,
     logger: Logger,
     shutdown_trigger: KvServerShutdownTrigger,
+    compaction_trigger: KvServerCompactionTrigger,
+    compaction_scheduler: CompactionScheduler,
+    idle_timeout: Duration,
+    idempotency_keys: Arc<IdempotencyKeys>,
+    backup_dir: Arc<PathBuf>,
     signals: Option<Signals>,
 }
 
@@ -87,6 +114,40 @@ impl KvServerShutdownTrigger {
     }
 }
 
+impl Default for KvServerShutdownTrigger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The signal that is sent to the KvServer asking it to run a compaction as soon as no other one is running,
+/// regardless of its compaction schedule
+#[derive(Debug, Clone)]
+pub struct KvServerCompactionTrigger(Arc<AtomicBool>);
+
+impl KvServerCompactionTrigger {
+    /// Creates a new compaction signal
+    pub fn new() -> Self {
+        Self(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Fires a signal to the server asking it to run a compaction
+    pub fn trigger(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    /// Tells if the signal was fired since the last call, and resets it
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::AcqRel)
+    }
+}
+
+impl Default for KvServerCompactionTrigger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// The error type returned by the new function of the KvServer
 #[derive(Debug)]
 pub enum KvServerCreationError<'a> {
@@ -125,6 +186,116 @@ impl Drop for LogConnectionClosedGuard {
     }
 }
 
+/// A connection accepted by the server, read and written by the event loop without blocking.
+/// Its requests are served one at a time, in the order they came: the next one is handed to a thread of the pool
+/// once the response of the previous one was queued for writing.
+struct Connection {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    last_active: Instant,
+    /// The bytes received and not yet framed into requests
+    read_buf: Vec<u8>,
+    /// The bytes of the responses not yet written
+    write_buf: Vec<u8>,
+    /// Tells if a request of the connection is being served by a thread of the pool
+    busy: bool,
+    /// Tells if the peer closed its side of the connection, so that no more bytes are received
+    read_closed: bool,
+    _log_closed_guard: LogConnectionClosedGuard,
+}
+
+/// The response to a request of the connection of the token, encoded by a thread of the pool
+type Served = (Token, Result<Vec<u8>, error::Error>);
+
+/// The idempotency keys of the last sets applied
+#[derive(Debug, Default)]
+struct IdempotencyKeys {
+    applied: Mutex<AppliedKeys>,
+    /// Notified when a set carrying an idempotency key is done being applied
+    done: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct AppliedKeys {
+    keys: HashSet<Vec<u8>>,
+    /// The keys in the order they were applied, the oldest one first
+    order: VecDeque<Vec<u8>>,
+    /// The keys of the sets being applied
+    in_flight: HashSet<Vec<u8>>,
+}
+
+impl IdempotencyKeys {
+    /// Applies the set done by `set` unless a set with the same `idempotency_key` was already applied.
+    /// A set sent again while the first one is still being applied waits for it to be done, so that it is not
+    /// applied twice, while the sets with other keys are applied concurrently.
+    fn apply<F>(&self, idempotency_key: &[u8], set: F) -> crate::Result<()>
+    where
+        F: FnOnce() -> crate::Result<()>,
+    {
+        let mut applied = self.applied.lock();
+        loop {
+            if applied.keys.contains(idempotency_key) {
+                return Ok(());
+            }
+            if !applied.in_flight.contains(idempotency_key) {
+                break;
+            }
+            self.done.wait(&mut applied);
+        }
+        applied.in_flight.insert(idempotency_key.to_vec());
+        drop(applied);
+
+        let in_flight = InFlightKey {
+            keys: self,
+            idempotency_key,
+        };
+        set()?;
+        let mut applied = self.applied.lock();
+        if applied.order.len() == IDEMPOTENCY_KEYS_CAPACITY {
+            if let Some(oldest) = applied.order.pop_front() {
+                applied.keys.remove(&oldest);
+            }
+        }
+        applied.keys.insert(idempotency_key.to_vec());
+        applied.order.push_back(idempotency_key.to_vec());
+        drop(applied);
+        drop(in_flight);
+        Ok(())
+    }
+}
+
+/// Marks a set carrying an idempotency key as being applied until dropped, even if the set failed or panicked
+struct InFlightKey<'a> {
+    keys: &'a IdempotencyKeys,
+    idempotency_key: &'a [u8],
+}
+
+impl Drop for InFlightKey<'_> {
+    fn drop(&mut self) {
+        self.keys
+            .applied
+            .lock()
+            .in_flight
+            .remove(self.idempotency_key);
+        self.keys.done.notify_all();
+    }
+}
+
+/// Resolves the directory `dest_dir` of a backup relative to the `backup_dir` of the server, refusing an absolute
+/// directory or one with `..` components, which could reach out of it
+fn backup_path(backup_dir: &Path, dest_dir: &Path) -> crate::Result<PathBuf> {
+    let mut components = dest_dir.components().peekable();
+    if components.peek().is_some()
+        && components.all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(backup_dir.join(dest_dir))
+    } else {
+        Err(KvStoreError::InvalidBackupDir {
+            path: dest_dir.display().to_string(),
+        })
+    }
+}
+
 impl<'a> fmt::Display for KvServerCreationError<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -174,10 +345,35 @@ where
             thread_pool,
             logger,
             shutdown_trigger: KvServerShutdownTrigger::new(),
+            compaction_trigger: KvServerCompactionTrigger::new(),
+            compaction_scheduler: CompactionScheduler::new(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            idempotency_keys: Arc::new(IdempotencyKeys::default()),
+            backup_dir: Arc::new(PathBuf::from(DEFAULT_BACKUP_DIR)),
             signals,
         })
     }
 
+    /// Sets when the server runs compactions, which by default is every 5 seconds and without limits
+    pub fn compaction_scheduler(mut self, compaction_scheduler: CompactionScheduler) -> Self {
+        self.compaction_scheduler = compaction_scheduler;
+        self
+    }
+
+    /// Sets how long a connection is kept open without receiving any request, which by default is 60 seconds.
+    /// Idle connections do not hold any thread of the pool, but each one holds a socket of the server.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets the directory the backups asked for by the clients are written to, which by default is the working
+    /// directory of the server. The directory of a backup is resolved relative to it, and may not reach out of it.
+    pub fn backup_dir<P: Into<PathBuf>>(mut self, backup_dir: P) -> Self {
+        self.backup_dir = Arc::new(backup_dir.into());
+        self
+    }
+
     fn poll(&mut self, poll: &mut Poll, events: &mut Events) -> Result<(), i32> {
         let mut poll_attempt = POLL_ATTEMPTS;
         loop {
@@ -196,7 +392,10 @@ where
         Ok(())
     }
 
-    /// Starts listening for connections and enter the forever loop handling server connections
+    /// Starts listening for connections and enter the forever loop handling server connections.
+    /// The connections are read and written by this loop without blocking, and only the execution of their requests
+    /// is handed to the thread pool. Once it returns, the open connections are closed, and the responses of the
+    /// requests still being executed are dropped.
     pub fn run(&mut self) -> Result<(), i32> {
         let mut listener = unwrap_or_return_code1_on_err!(
             TcpListener::bind(self.address),
@@ -234,10 +433,19 @@ where
                 "register event source: signal handler"
             );
         }
-        let compaction_timer_check_init =
-            SERVER_COMPACTION_PERIOD.as_millis() / SERVER_TIMER_CHECK_PERIOD.as_millis();
-        let mut compaction_timer_check_count = compaction_timer_check_init;
+        let eviction_timer_check_init =
+            SERVER_EVICTION_PERIOD.as_millis() / SERVER_TIMER_CHECK_PERIOD.as_millis();
+        let mut eviction_timer_check_count = eviction_timer_check_init;
+        let mut last_compaction = Instant::now();
         let compactor_running = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let waker = Arc::new(unwrap_or_return_code1_on_err!(
+            Waker::new(poll.registry(), SERVER_WAKER_TOKEN),
+            self.logger,
+            "create a waker"
+        ));
+        let mut connections = HashMap::<Token, Connection>::new();
+        let mut next_connection_token = FIRST_CONNECTION_TOKEN.0;
+        let (served_sender, served_receiver) = crossbeam::channel::unbounded::<Served>();
         let mut events = Events::with_capacity(1024);
         loop {
             self.poll(&mut poll, &mut events)?;
@@ -248,9 +456,7 @@ where
             for event in events.iter() {
                 match event.token() {
                     SERVER_TOKEN => loop {
-                        let log_server = self.logger.clone();
-                        let db = self.db.clone();
-                        let (mut stream, peer_addr) = match listener.accept() {
+                        let (stream, peer_addr) = match listener.accept() {
                             Ok((stream, address)) => (stream, address),
                             Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                                 break;
@@ -260,79 +466,73 @@ where
                                 return Err(1);
                             }
                         };
-                        self.thread_pool.spawn(move || {
-                            info!(log_server, "Acceppted connection"; "peer" => peer_addr);
-                            let _log_conn_closed_guard = LogConnectionClosedGuard {
+                        info!(self.logger, "Acceppted connection"; "peer" => peer_addr);
+                        let token = Token(next_connection_token);
+                        next_connection_token += 1;
+                        let mut conn = Connection {
+                            stream,
+                            peer_addr,
+                            last_active: Instant::now(),
+                            read_buf: Vec::new(),
+                            write_buf: Vec::new(),
+                            busy: false,
+                            read_closed: false,
+                            _log_closed_guard: LogConnectionClosedGuard {
                                 peer_addr,
-                                log_server: log_server.clone(),
-                            };
-                            match unwrap_or_return_on_err!(
-                                KvServer::<Engine, Tp>::recv_payload(&mut stream),
-                                log_server,
-                                "get the message payload from peer's message"
-                            ) {
-                                MessagePayload::Request(Request::Set(req)) => {
-                                    info!(log_server, "received message"; "peer" => peer_addr, "payload_type" => "RequestSet", "key" => req.key(), "value" => req.value());
-                                    let res = db.set(req.key().to_owned(), req.value().to_owned());
-                                    let resp = ResponseSet::new_message(StatusCode::from(&res));
-                                    unwrap_or_return_on_err!(
-                                        KvServer::<Engine, Tp>::send_response(&resp, &mut stream),
-                                        log_server,
-                                        "send response to peer"
-                                    );
-                                    info!(log_server, "sent message"; "peer" => peer_addr, "payload_type" => "ResponseSet", "status" => StatusCode::from(&res).to_string());
-                                }
-                                MessagePayload::Request(Request::Get(req)) => {
-                                    info!(log_server, "received message"; "peer" => peer_addr, "payload_type" => "RequestGet", "key" => req.key());
-                                    let res = db.get(req.key().to_owned());
-                                    let value = res.as_ref().unwrap_or(&None).clone();
-                                    let resp = ResponseGet::new_message(StatusCode::from(&res), value.clone());
-                                    unwrap_or_return_on_err!(
-                                        KvServer::<Engine, Tp>::send_response(&resp, &mut stream),
-                                        log_server,
-                                        "send response to peer"
-                                    );
-                                    info!(log_server, "sent message"; "peer" => peer_addr, "payload_type" => "ResponseGet", "status" => StatusCode::from(&res).to_string(), "value" => value);
-                                }
-                                MessagePayload::Request(Request::Remove(req)) => {
-                                    info!(log_server, "received message"; "peer" => peer_addr, "payload_type" => "RequestRemove", "key" => req.key());
-                                    let res = db.remove(req.key().to_owned());
-                                    let resp = ResponseRemove::new_message(StatusCode::from(&res));
-                                    unwrap_or_return_on_err!(
-                                        KvServer::<Engine, Tp>::send_response(&resp, &mut stream),
-                                        log_server,
-                                        "send response to peer"
-                                    );
-                                    info!(log_server, "sent message"; "peer" => peer_addr, "payload_type" => "ResponseRemove", "status" => StatusCode::from(&res).to_string());
-                                }
-                                MessagePayload::Response(_) => {
-                                    // Error: client sent a response message
-                                    error!(log_server, "received message"; "peer" => peer_addr, "payload_type" => "Response");
-                                    let resp = ResponseSet::new_message(StatusCode::FatalError);
-                                    unwrap_or_return_on_err!(
-                                        KvServer::<Engine, Tp>::send_response(&resp, &mut stream),
-                                        log_server,
-                                        "send response to peer"
-                                    );
-                                }
-                            }
-                        });
+                                log_server: self.logger.clone(),
+                            },
+                        };
+                        // Registered once for both, as the events only report the changes of readiness
+                        unwrap_or_return_code1_on_err!(
+                            poll.registry().register(
+                                &mut conn.stream,
+                                token,
+                                Interest::READABLE | Interest::WRITABLE
+                            ),
+                            self.logger,
+                            "register event source: connection"
+                        );
+                        connections.insert(token, conn);
                     },
                     SERVER_TIMER_TOKEN => {
                         if !compactor_running.load(std::sync::atomic::Ordering::Acquire) {
-                            if compaction_timer_check_count == 0 {
+                            let compact = self.compaction_trigger.take()
+                                || self
+                                    .compaction_scheduler
+                                    .is_due(last_compaction.elapsed(), SystemTime::now());
+                            if compact || eviction_timer_check_count == 0 {
                                 let db = self.db.clone();
                                 let logger = self.logger.clone();
+                                let limits = Some(self.compaction_scheduler.limits.clone())
+                                    .filter(|_| compact);
                                 let compactor_running = compactor_running.clone();
                                 compactor_running.store(true, std::sync::atomic::Ordering::Release);
                                 self.thread_pool.spawn(move || {
-                                    KvServer::<Engine, Tp>::run_compactor(db, logger);
+                                    KvServer::<Engine, Tp>::run_compactor(db, logger, limits);
                                     compactor_running
                                         .store(false, std::sync::atomic::Ordering::Release);
                                 });
-                                compaction_timer_check_count = compaction_timer_check_init;
+                                if compact {
+                                    last_compaction = Instant::now();
+                                }
+                                eviction_timer_check_count = eviction_timer_check_init;
                             } else {
-                                compaction_timer_check_count -= 1;
+                                eviction_timer_check_count -= 1;
+                            }
+                        }
+
+                        let idle_timeout = self.idle_timeout;
+                        let idle_tokens = connections
+                            .iter()
+                            .filter(|(_, conn)| {
+                                !conn.busy && conn.last_active.elapsed() >= idle_timeout
+                            })
+                            .map(|(token, _)| *token)
+                            .collect::<Vec<_>>();
+                        for token in idle_tokens {
+                            if let Some(mut conn) = connections.remove(&token) {
+                                info!(self.logger, "closing idle connection"; "peer" => conn.peer_addr);
+                                let _ = poll.registry().deregister(&mut conn.stream);
                             }
                         }
 
@@ -342,30 +542,74 @@ where
                             "setup the timer"
                         );
                     }
+                    SERVER_WAKER_TOKEN => {
+                        for (token, response) in served_receiver.try_iter() {
+                            // The connection may have been closed while its request was served
+                            let conn = match connections.get_mut(&token) {
+                                Some(conn) => conn,
+                                None => continue,
+                            };
+                            conn.busy = false;
+                            conn.last_active = Instant::now();
+                            let open = match response {
+                                Ok(response) => {
+                                    conn.write_buf.extend_from_slice(&response);
+                                    self.serve_connection(token, conn, &served_sender, &waker)
+                                }
+                                Err(e) => {
+                                    error!(self.logger, "Could not serve the request"; "peer" => conn.peer_addr, "error" => e.to_string());
+                                    false
+                                }
+                            };
+                            if !open {
+                                if let Some(mut conn) = connections.remove(&token) {
+                                    let _ = poll.registry().deregister(&mut conn.stream);
+                                }
+                            }
+                        }
+                    }
                     SERVER_SIGNALS_TOKEN => {
+                        let logger = &self.logger;
+                        let compaction_trigger = &self.compaction_trigger;
                         if let Some(signals) = &mut self.signals {
                             loop {
                                 match unwrap_or_return_code1_on_err!(
                                     signals.receive(),
-                                    self.logger,
+                                    logger,
                                     "retrieve received signal"
                                 ) {
                                     Some(Signal::Interrupt)
                                     | Some(Signal::Terminate)
                                     | Some(Signal::Quit) => {
-                                        info!(self.logger, "server is shutting down");
+                                        info!(logger, "server is shutting down");
                                         return Ok(());
                                     }
+                                    Some(Signal::User1) => {
+                                        info!(logger, "received compaction request");
+                                        compaction_trigger.trigger();
+                                    }
                                     None => break,
                                     Some(sig) => {
-                                        error!(self.logger, "received unexpected signal"; "signal" => format!("{:?}",sig));
+                                        error!(logger, "received unexpected signal"; "signal" => format!("{:?}",sig));
                                         return Err(1);
                                     }
                                 }
                             }
                         }
                     }
-                    _ => unreachable!(),
+                    token => {
+                        let open = match connections.get_mut(&token) {
+                            Some(conn) => {
+                                self.serve_connection(token, conn, &served_sender, &waker)
+                            }
+                            None => continue,
+                        };
+                        if !open {
+                            if let Some(mut conn) = connections.remove(&token) {
+                                let _ = poll.registry().deregister(&mut conn.stream);
+                            }
+                        }
+                    }
                 }
             }
         }
@@ -377,26 +621,257 @@ where
         self.shutdown_trigger.clone()
     }
 
-    fn send_response(msg: &Message, stream: &mut TcpStream) -> Result<(), error::Error> {
-        let mut buf = SmallVec::<[u8; 256]>::new();
-        buf.resize(ser::calc_len(msg)?, 0u8);
-        ser::to_bytes(msg, &mut buf[..])?;
-        stream.write_all(&buf[..])?;
+    /// Gives the user a trigger that can be used to ask the server to run a compaction.
+    /// The server also runs one when it receives a SIGUSR1, if it was created with a signal handler listening to it.
+    pub fn get_compaction_trigger(&self) -> KvServerCompactionTrigger {
+        self.compaction_trigger.clone()
+    }
+
+    /// Writes the queued responses of the connection and reads the bytes received, as long as it does not block,
+    /// then hands its next request to a thread of the pool if none is being served. The thread sends the response
+    /// through the `served_sender` and wakes the event loop with the `waker`.
+    /// Returns false once the connection must be closed.
+    fn serve_connection(
+        &self,
+        token: Token,
+        conn: &mut Connection,
+        served_sender: &crossbeam::channel::Sender<Served>,
+        waker: &Arc<Waker>,
+    ) -> bool {
+        if let Err(e) = conn.write_and_read() {
+            info!(self.logger, "connection failed"; "peer" => conn.peer_addr, "error" => e.to_string());
+            return false;
+        }
+        if conn.busy {
+            return true;
+        }
+        let request = if conn.write_buf.len() < WRITE_BUFFER_LIMIT {
+            match next_request(&mut conn.read_buf) {
+                Ok(request) => request,
+                Err(e) => {
+                    error!(self.logger, "Could not receive the request"; "peer" => conn.peer_addr, "error" => e.to_string());
+                    return false;
+                }
+            }
+        } else {
+            None
+        };
+        let (request_id, payload) = match request {
+            Some(request) => request,
+            // Closed by the peer once every response was written
+            None => return !conn.read_closed || !conn.write_buf.is_empty(),
+        };
+        conn.busy = true;
+        let db = self.db.clone();
+        let idempotency_keys = self.idempotency_keys.clone();
+        let backup_dir = self.backup_dir.clone();
+        let log_server = self.logger.clone();
+        let peer_addr = conn.peer_addr;
+        let served_sender = served_sender.clone();
+        let waker = waker.clone();
+        self.thread_pool.spawn(move || {
+            let res = KvServer::<Engine, Tp>::handle_request(
+                &db,
+                &idempotency_keys,
+                &backup_dir,
+                payload,
+                request_id,
+                peer_addr,
+                &log_server,
+            );
+            // Once the server is gone, the response is simply dropped
+            if served_sender.send((token, res)).is_ok() {
+                let _ = waker.wake();
+            }
+        });
+        true
+    }
+
+    /// Executes the request of the `payload` on the `db` and returns the encoded response, carrying the `request_id`.
+    /// The backups are written to directories of the `backup_dir`.
+    fn handle_request(
+        db: &Engine,
+        idempotency_keys: &IdempotencyKeys,
+        backup_dir: &Path,
+        payload: MessagePayload,
+        request_id: u32,
+        peer_addr: SocketAddr,
+        log_server: &Logger,
+    ) -> Result<Vec<u8>, error::Error> {
+        let mut response = Vec::new();
+        match payload {
+            MessagePayload::Request(Request::Set(req)) => {
+                info!(log_server, "received message"; "peer" => peer_addr, "payload_type" => "RequestSet", "key" => %String::from_utf8_lossy(req.key()), "value" => %String::from_utf8_lossy(req.value()));
+                let set = || db.set_bytes(req.key().to_vec(), req.value().to_vec());
+                let res = match req.idempotency_key() {
+                    Some(idempotency_key) => idempotency_keys.apply(idempotency_key, set),
+                    None => set(),
+                };
+                let resp = ResponseSet::new_message(StatusCode::from(&res));
+                encode_message(&resp.with_request_id(request_id), &mut response)?;
+                info!(log_server, "sent message"; "peer" => peer_addr, "payload_type" => "ResponseSet", "status" => StatusCode::from(&res).to_string());
+            }
+            MessagePayload::Request(Request::Get(req)) => {
+                info!(log_server, "received message"; "peer" => peer_addr, "payload_type" => "RequestGet", "key" => %String::from_utf8_lossy(req.key()));
+                let res = db.get_bytes(req.key().to_vec());
+                let value = res.as_ref().unwrap_or(&None).clone();
+                let resp = ResponseGet::new_message(StatusCode::from(&res), value.clone());
+                encode_message(&resp.with_request_id(request_id), &mut response)?;
+                info!(log_server, "sent message"; "peer" => peer_addr, "payload_type" => "ResponseGet", "status" => StatusCode::from(&res).to_string(), "value" => value.as_ref().map(|v| String::from_utf8_lossy(v).into_owned()));
+            }
+            MessagePayload::Request(Request::Remove(req)) => {
+                info!(log_server, "received message"; "peer" => peer_addr, "payload_type" => "RequestRemove", "key" => %String::from_utf8_lossy(req.key()));
+                let res = db.remove_bytes(req.key().to_vec());
+                let resp = ResponseRemove::new_message(StatusCode::from(&res));
+                encode_message(&resp.with_request_id(request_id), &mut response)?;
+                info!(log_server, "sent message"; "peer" => peer_addr, "payload_type" => "ResponseRemove", "status" => StatusCode::from(&res).to_string());
+            }
+            MessagePayload::Request(Request::Batch(req)) => {
+                info!(log_server, "received message"; "peer" => peer_addr, "payload_type" => "RequestBatch", "ops" => req.len());
+                let res = db.write_batch(req.into_ops());
+                let resp = ResponseBatch::new_message(StatusCode::from(&res));
+                encode_message(&resp.with_request_id(request_id), &mut response)?;
+                info!(log_server, "sent message"; "peer" => peer_addr, "payload_type" => "ResponseBatch", "status" => StatusCode::from(&res).to_string());
+            }
+            MessagePayload::Request(Request::CompareAndSwap(req)) => {
+                info!(log_server, "received message"; "peer" => peer_addr, "payload_type" => "RequestCompareAndSwap", "key" => %String::from_utf8_lossy(req.key()));
+                let res = db.compare_and_swap_bytes(
+                    req.key().to_vec(),
+                    req.expected().map(<[u8]>::to_vec),
+                    req.new_value().map(<[u8]>::to_vec),
+                );
+                let current = match &res {
+                    Err(KvStoreError::CompareAndSwapFailed { current }) => current.clone(),
+                    _ => None,
+                };
+                let resp = ResponseCompareAndSwap::new_message(StatusCode::from(&res), current);
+                encode_message(&resp.with_request_id(request_id), &mut response)?;
+                info!(log_server, "sent message"; "peer" => peer_addr, "payload_type" => "ResponseCompareAndSwap", "status" => StatusCode::from(&res).to_string());
+            }
+            MessagePayload::Request(Request::Backup(req)) => {
+                let dest_dir = Path::new(OsStr::from_bytes(req.dest_dir()));
+                info!(log_server, "received message"; "peer" => peer_addr, "payload_type" => "RequestBackup", "dest_dir" => %dest_dir.display());
+                let res = backup_path(backup_dir, dest_dir).and_then(|path| db.checkpoint(&path));
+                if let Err(err) = &res {
+                    error!(log_server, "Could not write checkpoint"; "dest_dir" => %dest_dir.display(), "error" => err.to_string());
+                }
+                let resp = ResponseBackup::new_message(StatusCode::from(&res));
+                encode_message(&resp.with_request_id(request_id), &mut response)?;
+                info!(log_server, "sent message"; "peer" => peer_addr, "payload_type" => "ResponseBackup", "status" => StatusCode::from(&res).to_string());
+            }
+            MessagePayload::Response(_) => {
+                // Error: client sent a response message
+                error!(log_server, "received message"; "peer" => peer_addr, "payload_type" => "Response");
+                let resp = ResponseSet::new_message(StatusCode::FatalError);
+                encode_message(&resp.with_request_id(request_id), &mut response)?;
+            }
+        }
+        Ok(response)
+    }
+
+    /// Evicts the expired keys and then, given the `limits`, runs a compaction if one is needed
+    fn run_compactor(db: Engine, logger: Logger, limits: Option<CompactionLimits>) {
+        unwrap_or_return_on_err!(db.evict_expired(), logger, "evict the expired keys");
+        let limits = match limits {
+            Some(limits) => limits,
+            None => return,
+        };
+        let estimate = unwrap_or_return_on_err!(
+            db.compaction_needed(),
+            logger,
+            "estimate the compaction work"
+        );
+        if !estimate.is_needed() {
+            return;
+        }
+        info!(logger, "starting compaction"; "log_files" => estimate.log_files, "reclaimable_bytes" => estimate.reclaimable_bytes, "bytes_to_rewrite" => estimate.bytes_to_rewrite);
+        let progress = unwrap_or_return_on_err!(
+            db.compact_with(&limits),
+            logger,
+            "run compaction successfully"
+        );
+        info!(logger, "finished compaction"; "log_files_compacted" => progress.log_files_compacted, "log_files_total" => progress.log_files_total, "bytes_read" => progress.bytes_read, "bytes_written" => progress.bytes_written, "elapsed_ms" => progress.elapsed.as_millis() as u64);
+    }
+}
+
+impl Connection {
+    /// Writes the queued responses and reads the bytes received, until the stream would block.
+    /// The reads stop early once enough bytes wait to be served.
+    fn write_and_read(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        while !self.read_closed
+            && (self.read_buf.len() < READ_BUFFER_LIMIT || !has_request(&self.read_buf))
+        {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.read_closed = true,
+                Ok(n) => {
+                    self.read_buf.extend_from_slice(&chunk[..n]);
+                    self.last_active = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
         Ok(())
     }
+}
 
-    fn recv_payload(stream: &mut TcpStream) -> Result<MessagePayload, error::Error> {
-        let mut header_buf = [0u8; HEADER_SIZE];
-        stream.read_exact(&mut header_buf)?;
-        let header: Result<Header, _> = de::from_bytes(&header_buf);
-        let header = header?;
+/// Reads the header at the start of the bytes `buf` received, if they hold all of it
+fn read_header(buf: &[u8]) -> Result<Option<Header>, error::Error> {
+    if buf.len() < HEADER_SIZE {
+        return Ok(None);
+    }
+    let header: Header = de::from_bytes(&buf[..HEADER_SIZE])?;
+    if header.protocol_version() != PROTOCOL_VERSION {
+        return Err(error::Error::Message(format!(
+            "unsupported protocol version {:#X}",
+            header.protocol_version()
+        )));
+    }
+    Ok(Some(header))
+}
 
-        let mut payload_buf: SmallVec<[u8; 256]> = smallvec![0; header.payload_length() as usize];
-        stream.read_exact(&mut payload_buf)?;
-        de::from_bytes(&payload_buf)
+/// Tells if the bytes `buf` received hold a whole request
+fn has_request(buf: &[u8]) -> bool {
+    match read_header(buf) {
+        Ok(Some(header)) => buf.len() >= HEADER_SIZE + header.payload_length() as usize,
+        // An invalid header is reported once the request is framed
+        Ok(None) => false,
+        Err(_) => true,
     }
+}
 
-    fn run_compactor(db: Engine, logger: Logger) {
-        unwrap_or_return_on_err!(db.compact(), logger, "run compaction successfully");
+/// Takes the first request, along with its id, out of the bytes `buf` received, if they hold all of it
+fn next_request(buf: &mut Vec<u8>) -> Result<Option<(u32, MessagePayload)>, error::Error> {
+    let header = match read_header(buf)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let frame_len = HEADER_SIZE + header.payload_length() as usize;
+    if buf.len() < frame_len {
+        return Ok(None);
     }
+    let payload = de::from_bytes(&buf[HEADER_SIZE..frame_len])?;
+    buf.drain(..frame_len);
+    Ok(Some((header.request_id(), payload)))
+}
+
+/// Appends the encoded `msg` to the `buf` of bytes to write
+fn encode_message(msg: &Message, buf: &mut Vec<u8>) -> Result<(), error::Error> {
+    let start = buf.len();
+    buf.resize(start + ser::calc_len(msg)?, 0u8);
+    ser::to_bytes(msg, &mut buf[start..])?;
+    Ok(())
 }
@@ -1,21 +1,33 @@
 use super::*;
-use crossbeam_epoch::{Atomic, Owned};
-use flurry::{epoch::Guard, HashMap as FlurryHashMap};
+use bincode::Options;
+use crossbeam::{
+    atomic::AtomicCell,
+    epoch::{Collector, Guard},
+};
+use crossbeam_skiplist::SkipMap;
+use dir_lock::DirLock;
 use itertools::Itertools;
-use kvsengine::KvsEngine;
-use parking_lot::{Mutex, MutexGuard};
+use kvsengine::{
+    expires_at, is_empty_range, now_millis, KvsBytesIterator, KvsEngine, KvsSnapshot, Op,
+};
+use parking_lot::{Mutex, MutexGuard, RwLock};
 use positioned_io::ReadAt;
 use serde::{Deserialize, Serialize};
 use smallvec::{smallvec, SmallVec};
 use std::{
-    collections::{HashMap as StdHashMap, HashSet as StdHashSet},
+    collections::{BTreeMap, BTreeSet, HashMap as StdHashMap, HashSet as StdHashSet},
     fmt::Debug,
     fs::{self, File, OpenOptions},
-    io::{BufReader, Seek, SeekFrom, Write},
-    iter::FromIterator,
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    ops::{Bound, Range, RangeBounds},
     path::{Path, PathBuf},
     result,
-    sync::{atomic::AtomicI64, atomic::Ordering, Arc},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 use walkdir::WalkDir;
 
@@ -23,26 +35,109 @@ use walkdir::WalkDir;
 const LOG_FILE_PREFIX: &str = "db";
 const LOG_FILE_SUFFIX: &str = ".log";
 
-/// The KvStore uses the constant `CMD_KEY_FACTOR` as a threshold to indicate the maximum proportion of commands
-/// written to log files before triggering the compaction algorithm.
-const CMD_KEY_FACTOR: f64 = 1.5;
-/// The constant `CMDS_THRESHOLD` is used as a lower bound on the total number of commands
-/// that must have been written to log files before trigerring compaction. Also, `CMDS_THRESHOLD/2` is also used
-/// to trigger the creation of a new file.
-const CMDS_THRESHOLD: u64 = 10000;
+/// Hint file names follow the pattern: `LOG_FILE_PREFIX` || id || `HINT_FILE_SUFFIX`, where id is the one of the
+/// sealed log file the hint file describes
+const HINT_FILE_SUFFIX: &str = ".hint";
+/// Extension of a hint file while it is being written
+const HINT_FILE_TMP_EXTENSION: &str = "hint.tmp";
+
+/// Every hint file follows the pattern:
+/// `HINT_FILE_MAGIC` || length of the log file (u64 LE) || crc32 of the payload (u32 LE) || payload (bincode serialized `Hint`)
+/// A hint file whose log file length does not match the one of the log file is stale and ignored.
+const HINT_FILE_MAGIC: [u8; 4] = *b"KVSH";
+const HINT_FILE_HEADER_SIZE: usize = 16;
+
+/// Every log file starts with a header following the pattern: `LOG_FILE_MAGIC` || format version (u32 LE)
+const LOG_FILE_MAGIC: [u8; 4] = *b"KVSL";
+/// Version 2 added the expiration time to the set commands. Version 1 log files are still read, but never appended to.
+/// Neither are version 0 log files, which were written before the header was introduced and hold their commands
+/// one after the other, without framing them into records.
+const LOG_FORMAT_VERSION: u32 = 2;
+const LOG_FILE_HEADER_SIZE: u64 = 8;
+
+/// Every command is written to the log files inside a record following the pattern:
+/// payload length (u32 LE) || crc32 of the payload (u32 LE) || payload (bincode serialized command)
+const RECORD_HEADER_SIZE: u64 = 8;
+
+/// The payload of a batch record follows the pattern:
+/// `Command::Batch` variant tag (u32 LE) || number of records (u64 LE) || (record length (u64 LE) || record)*
+/// where each inner record is framed just like a top-level one.
+const BATCH_PAYLOAD_HEADER_SIZE: u64 = 12;
+const BATCH_RECORD_LEN_SIZE: u64 = 8;
 
-/// The constant `CURR_FILE_OFFSET_THRESHOLD` is used as a lower bound for the file write offset
-/// to trigger the creation of a new file.
-const CURR_FILE_OFFSET_THRESHOLD: u64 = 1073741824;
+/// Default value of `KvStoreOptions::max_file_cmds`
+const DEFAULT_MAX_FILE_CMDS: u64 = 5000;
+/// Default value of `KvStoreOptions::max_file_size`
+const DEFAULT_MAX_FILE_SIZE: u64 = 1073741824;
+/// Default value of `KvStoreOptions::reader_cache_capacity`
+const DEFAULT_READER_CACHE_CAPACITY: usize = 64;
+/// Number of shards of the `LogReaderCache`, each one behind its own lock
+const READER_CACHE_SHARDS: usize = 16;
 
 /// Data structure that implements a persistent key-value store
 #[derive(Debug, Clone)]
 pub struct KvStore {
-    storage_index: Arc<FlurryHashMap<String, CommandIndex>>,
+    /// Ordered by key, so that the scans walk it directly. The location of a key is swapped in place when it is
+    /// written again, since replacing its entry would hide the key from the concurrent reads for a moment.
+    storage_index: Arc<SkipMap<Vec<u8>, AtomicCell<CommandIndex>>>,
+    /// Epochs the readers of the storage index are pinned to, so that the log files they may read are only deleted
+    /// once they are done. The deletions still pending are run once the last clone of the store is dropped.
+    collector: Collector,
     log_dir_path: PathBuf,
     writer_ctrl: Arc<Mutex<WriterControlData>>,
-    curr_log_r: Atomic<LogFileReader>,
-    last_collected_file_index: Arc<AtomicI64>,
+    log_readers: Arc<LogReaderCache>,
+    value_cache: Option<Arc<ValueCache>>,
+    compaction_lock: Arc<Mutex<()>>,
+    compaction_progress: Arc<Mutex<Option<CompactionProgress>>>,
+    snapshots: Arc<Mutex<SnapshotRegistry>>,
+    options: KvStoreOptions,
+    synced_position: Arc<Mutex<LogPosition>>,
+    /// Keeps other processes from opening the store until the last clone of it is dropped
+    _dir_lock: Arc<DirLock>,
+}
+
+/// A read-only view of a `KvStore` at the point in time it was taken, as returned by `KvsEngine::snapshot`.
+/// It holds a copy of the storage index, and the log files it points at are not deleted by the compactions
+/// until the snapshot, along with all of its clones and iterators, is dropped.
+#[derive(Debug, Clone)]
+pub struct KvStoreSnapshot(Arc<SnapshotData>);
+
+#[derive(Debug)]
+struct SnapshotData {
+    id: u64,
+    store: KvStore,
+    index: BTreeMap<Vec<u8>, CommandIndex>,
+}
+
+/// Keeps track of the live snapshots of a store, and of the log files compacted while they were alive
+#[derive(Debug, Default)]
+struct SnapshotRegistry {
+    next_id: u64,
+    live_ids: BTreeSet<u64>,
+    /// Ids of the compacted log files still pointed at by a snapshot, along with the id of the first snapshot
+    /// taken after they were compacted, which no longer needs them
+    retired_log_ids: Vec<(u64, u64)>,
+}
+
+/// Settings used to open a `KvStore`, built with chained calls starting from `KvStoreOptions::new()`
+///
+/// # Examples
+///
+/// ```no_run
+/// use kvs::{Durability, KvStore, KvStoreOptions};
+/// let options = KvStoreOptions::new()
+///     .durability(Durability::GroupCommit)
+///     .max_file_size(64 * 1024 * 1024);
+/// let dictionary = KvStore::open_with("./", options).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    durability: Durability,
+    max_file_size: u64,
+    max_file_cmds: u64,
+    compaction_policy: Arc<dyn CompactionPolicy>,
+    reader_cache_capacity: usize,
+    value_cache_capacity: u64,
 }
 
 /// An alias for the result type that includes the common error type
@@ -51,41 +146,578 @@ pub type Result<T> = result::Result<T, KvStoreError>;
 /// The type that is used to save the API calls: `set` and `remove` to the log files.
 #[derive(Debug, Serialize, Deserialize)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    /// `expires_at` is the time, in milliseconds since the unix epoch, after which the key is no longer visible
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    /// A batch of set and remove commands written atomically. Each element is a complete framed record,
+    /// so that the storage index can point directly at the commands inside of the batch.
+    Batch {
+        records: Vec<Vec<u8>>,
+    },
 }
 
-/// The location of the command in a log file
-#[derive(Debug, Clone)]
+/// The commands as written to the log files by the version 1 of the log format
+#[derive(Debug, Deserialize)]
+enum CommandV1 {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+    Batch { records: Vec<Vec<u8>> },
+}
+
+/// The commands as written to the log files by the version 0 of the log format
+#[derive(Debug, Serialize, Deserialize)]
+enum CommandV0 {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl From<CommandV0> for Command {
+    fn from(cmd: CommandV0) -> Self {
+        match cmd {
+            CommandV0::Set { key, value } => Command::Set {
+                key,
+                value,
+                expires_at: None,
+            },
+            CommandV0::Remove { key } => Command::Remove { key },
+        }
+    }
+}
+
+impl From<CommandV1> for Command {
+    fn from(cmd: CommandV1) -> Self {
+        match cmd {
+            CommandV1::Set { key, value } => Command::Set {
+                key,
+                value,
+                expires_at: None,
+            },
+            CommandV1::Remove { key } => Command::Remove { key },
+            CommandV1::Batch { records } => Command::Batch { records },
+        }
+    }
+}
+
+/// A command of a log file as needed to build the storage index, that is, without the value
+#[derive(Debug, Serialize, Deserialize)]
+enum HintEntry {
+    Set {
+        key: Vec<u8>,
+        offset: u64,
+        len: u64,
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+}
+
+/// The contents of a hint file: every command of a sealed log file, in the order they were written
+#[derive(Debug, Serialize, Deserialize)]
+struct Hint {
+    log_id: u64,
+    log_version: u32,
+    entries: Vec<HintEntry>,
+}
+
+/// The storage index built from the log files when the store is opened
+type IndexMap = StdHashMap<Vec<u8>, CommandIndex>;
+
+/// The offsets and lengths of the commands written inside of a batch record
+type RecordLocations = Vec<(u64, u64)>;
+
+/// The state of the store recovered from the log files when it is opened
+struct BuiltIndex {
+    storage_index: IndexMap,
+    /// Live bytes of each log file, by log id
+    segments: BTreeMap<u64, u64>,
+    last_log_id: u64,
+    last_log_version: u32,
+    /// Number of commands written in the last log file
+    cmd_counter: u64,
+}
+
+/// The location of the command in a log file, along with the expiration time of the key
+#[derive(Debug, Clone, Copy)]
 struct CommandIndex {
     log_id: u64,
     offset: u64,
     len: u64,
+    expires_at: Option<u64>,
+}
+
+impl CommandIndex {
+    /// Tells if the key has expired at the time `now`, in milliseconds since the unix epoch
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 /// Data structure managed by writers
 #[derive(Debug)]
 struct WriterControlData {
     curr_log: LogFileWriter,
-    total_cmd_counter: u64,
+    /// The live bytes of every log file not collected yet, the current one included, keyed by log id
+    segments: BTreeMap<u64, u64>,
 }
 
 /// Information about the current log file writer
 #[derive(Debug)]
 struct LogFileWriter {
     id: u64,
-    writer: File,
+    writer: Arc<File>,
     cmd_counter: u64,
     offset: u64,
 }
 
-/// Information about the current log file reader
+/// The log files picked to be compacted, along with the log ids reserved to write their live entries to
+#[derive(Debug)]
+struct Compaction {
+    log_ids_files: Vec<(u64, PathBuf)>,
+    output_ids: Range<u64>,
+    /// The oldest log file left out of the compaction, which may hold older copies of the compacted keys
+    oldest_kept_log_id: Option<u64>,
+}
+
+/// Writes the commands kept by a compaction to the log files with the ids reserved for it
+#[derive(Debug)]
+struct CompactionOutput<'a> {
+    log_dir_path: &'a Path,
+    options: &'a KvStoreOptions,
+    free_ids: Range<u64>,
+    used_ids: Vec<u64>,
+    writer: Option<LogFileWriter>,
+    bytes_written: u64,
+}
+
+/// A position in the sequence of log files, ordered by log id and then by offset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct LogPosition {
+    log_id: u64,
+    offset: u64,
+}
+
+/// Random access reader of a log file, which may be shared by concurrent reads
 #[derive(Debug)]
 struct LogFileReader {
     id: u64,
+    version: u32,
     reader: File,
 }
 
+/// A bounded cache of the log file readers, shared by all the clones of a store.
+/// The log ids are spread across shards, so that opening a log file seldom blocks the reads of other ones,
+/// and each shard closes its least recently used reader once it is full. A cached reader is found under
+/// the read lock of its shard, so the reads of the same log file never wait for each other.
+#[derive(Debug)]
+struct LogReaderCache {
+    log_dir_path: PathBuf,
+    shards: Vec<RwLock<LogReaderCacheShard>>,
+}
+
+/// The readers of a shard of the `LogReaderCache`, by log id.
+/// The tick only moves forward when a reader is opened, so that using a cached reader takes at most an atomic store,
+/// and the least recently used reader is the one not used since the most readers were opened.
+#[derive(Debug)]
+struct LogReaderCacheShard {
+    capacity: usize,
+    tick: AtomicU64,
+    readers: StdHashMap<u64, CachedLogReader>,
+}
+
+/// A reader of the `LogReaderCache`, along with the tick of its last use
+#[derive(Debug)]
+struct CachedLogReader {
+    last_used: AtomicU64,
+    reader: Arc<LogFileReader>,
+}
+
+/// Sequential reader over all the records of a log file
+#[derive(Debug)]
+struct LogFileScanner {
+    id: u64,
+    version: u32,
+    reader: BufReader<File>,
+    offset: u64,
+    file_len: u64,
+    payload_buf: Vec<u8>,
+}
+
+/// A record returned by the `LogFileScanner`
+#[derive(Debug)]
+enum ScannedRecord {
+    /// A complete record with a valid checksum
+    Valid { offset: u64, len: u64, cmd: Command },
+    /// The last record of the file, which was left incomplete or with a bad checksum
+    /// by a crash in the middle of a write
+    Torn { offset: u64 },
+}
+
+/// What `scan_log_file` does with a torn record found at the end of a log file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TornTail {
+    /// The torn record is cut off the file, which is only done to the current log file of a store being opened
+    Cut,
+    /// The torn record is left in the file and its commands are ignored
+    Skip,
+    /// The torn record is returned as a `KvStoreError::CorruptedLog`, since a sealed log file was complete
+    /// when it was sealed. Its length field may be the one corrupted, so the records after it are not cut off.
+    Reject,
+}
+
+/// Returns the header that must be written at the beginning of every log file
+fn log_file_header() -> [u8; LOG_FILE_HEADER_SIZE as usize] {
+    let mut header = [0u8; LOG_FILE_HEADER_SIZE as usize];
+    header[..4].copy_from_slice(&LOG_FILE_MAGIC);
+    header[4..].copy_from_slice(&LOG_FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+/// Reads the header of the log file, checks its magic number and returns its format version.
+/// A log file starting with the tag of a set or remove command (u32 LE) instead is a version 0 one, which has no header.
+fn read_log_file_header<R: Read>(log_id: u64, reader: &mut R) -> Result<u32> {
+    let mut header = [0u8; LOG_FILE_HEADER_SIZE as usize];
+    reader.read_exact(&mut header)?;
+    if header[..4] != LOG_FILE_MAGIC {
+        return match header[..4] {
+            [0, 0, 0, 0] | [1, 0, 0, 0] => Ok(0),
+            _ => Err(KvStoreError::InvalidLogFileHeader { log_id }),
+        };
+    }
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if version == 0 || version > LOG_FORMAT_VERSION {
+        return Err(KvStoreError::UnsupportedLogFormatVersion { log_id, version });
+    }
+    Ok(version)
+}
+
+/// Returns the offset of the first command of a log file written with the given `version` of the log format
+fn first_record_offset(version: u32) -> u64 {
+    if version == 0 {
+        0
+    } else {
+        LOG_FILE_HEADER_SIZE
+    }
+}
+
+/// Deserializes a command written with the given `version` of the log format
+fn decode_cmd(version: u32, payload: &[u8]) -> Result<Command> {
+    match version {
+        0 => Ok(bincode::deserialize::<CommandV0>(payload)?.into()),
+        1 => Ok(bincode::deserialize::<CommandV1>(payload)?.into()),
+        _ => Ok(bincode::deserialize(payload)?),
+    }
+}
+
+/// Serializes the command and frames it into a log record
+fn encode_record(cmd: &Command) -> Result<Vec<u8>> {
+    let payload_len = bincode::serialized_size(cmd)?;
+    let mut record = Vec::with_capacity((RECORD_HEADER_SIZE + payload_len) as usize);
+    record.extend_from_slice(&(payload_len as u32).to_le_bytes());
+    record.extend_from_slice(&[0u8; 4]);
+    bincode::serialize_into(&mut record, cmd)?;
+    let crc = crc32fast::hash(&record[RECORD_HEADER_SIZE as usize..]);
+    record[4..8].copy_from_slice(&crc.to_le_bytes());
+    Ok(record)
+}
+
+/// Frames each command into a record and all of them together into a single batch record.
+/// Returns the batch record and the offsets and lengths of the inner records relative to the start of the batch record.
+fn encode_batch_record(cmds: &[Command]) -> Result<(Vec<u8>, RecordLocations)> {
+    let records = cmds.iter().map(encode_record).collect::<Result<Vec<_>>>()?;
+    let locations = batch_record_locations(&records).collect::<Vec<_>>();
+    let batch = encode_record(&Command::Batch { records })?;
+    Ok((batch, locations))
+}
+
+/// Returns the offsets and lengths of the inner `records` relative to the start of the batch record holding them
+fn batch_record_locations(records: &[Vec<u8>]) -> impl Iterator<Item = (u64, u64)> + '_ {
+    let mut offset = RECORD_HEADER_SIZE + BATCH_PAYLOAD_HEADER_SIZE;
+    records.iter().map(move |record| {
+        let location = (offset + BATCH_RECORD_LEN_SIZE, record.len() as u64);
+        offset += BATCH_RECORD_LEN_SIZE + record.len() as u64;
+        location
+    })
+}
+
+/// Returns the commands stored in the record found at `offset` with their own offsets and lengths.
+/// A batch record is unfolded into the commands inside of it, otherwise the command is returned as is.
+fn record_cmds(
+    log_id: u64,
+    version: u32,
+    offset: u64,
+    len: u64,
+    cmd: Command,
+) -> Result<SmallVec<[(u64, u64, Command); 1]>> {
+    let records = match cmd {
+        Command::Batch { records } => records,
+        cmd => return Ok(smallvec![(offset, len, cmd)]),
+    };
+    let corrupted = || KvStoreError::CorruptedLog { log_id, offset };
+    batch_record_locations(&records)
+        .zip(records.iter())
+        .map(|((inner_offset, inner_len), record)| {
+            let cmd = record_payload(record)
+                .and_then(|payload| decode_cmd(version, payload).ok())
+                .ok_or_else(corrupted)?;
+            match cmd {
+                Command::Batch { .. } => Err(corrupted()),
+                cmd => Ok((offset + inner_offset, inner_len, cmd)),
+            }
+        })
+        .collect()
+}
+
+/// Returns the payload of the record if its length and checksum match the ones stored in the record header
+fn record_payload(record: &[u8]) -> Option<&[u8]> {
+    if (record.len() as u64) < RECORD_HEADER_SIZE {
+        return None;
+    }
+    let (header, payload) = record.split_at(RECORD_HEADER_SIZE as usize);
+    let payload_len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if payload_len as usize == payload.len() && crc32fast::hash(payload) == crc {
+        Some(payload)
+    } else {
+        None
+    }
+}
+
+/// Reads all the records of a log file and returns the hint describing it.
+/// A torn record at the end of the log file, left by a crash in the middle of a write, is handled as told by `torn_tail`.
+fn scan_log_file(log_id: u64, log_file_path: &Path, torn_tail: TornTail) -> Result<Hint> {
+    let mut scanner = LogFileScanner::open(log_id, log_file_path)?;
+    let log_version = scanner.version();
+    let mut entries = Vec::new();
+    while let Some(record) = scanner.next_record()? {
+        match record {
+            ScannedRecord::Valid { offset, len, cmd } => {
+                // A batch is a single record, so its commands are either all replayed or all cut off
+                for (offset, len, cmd) in record_cmds(log_id, log_version, offset, len, cmd)? {
+                    entries.push(match cmd {
+                        Command::Set {
+                            key, expires_at, ..
+                        } => HintEntry::Set {
+                            key,
+                            offset,
+                            len,
+                            expires_at,
+                        },
+                        Command::Remove { key } => HintEntry::Remove { key },
+                        Command::Batch { .. } => unreachable!(),
+                    });
+                }
+            }
+            ScannedRecord::Torn { offset } => match torn_tail {
+                TornTail::Cut => OpenOptions::new()
+                    .write(true)
+                    .open(log_file_path)?
+                    .set_len(offset)?,
+                TornTail::Skip => {}
+                TornTail::Reject => return Err(KvStoreError::CorruptedLog { log_id, offset }),
+            },
+        }
+    }
+    Ok(Hint {
+        log_id,
+        log_version,
+        entries,
+    })
+}
+
+/// Reads the hint file of a log file. Returns `None` if the hint file is missing, corrupted or stale,
+/// in which case the log file must be scanned instead.
+fn read_hint_file(log_id: u64, log_file_path: &Path, hint_file_path: &Path) -> Option<Hint> {
+    let contents = fs::read(hint_file_path).ok()?;
+    if contents.len() < HINT_FILE_HEADER_SIZE || contents[..4] != HINT_FILE_MAGIC {
+        return None;
+    }
+    let (header, payload) = contents.split_at(HINT_FILE_HEADER_SIZE);
+    let mut log_len = [0u8; 8];
+    log_len.copy_from_slice(&header[4..12]);
+    let crc = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+    if fs::metadata(log_file_path).ok()?.len() != u64::from_le_bytes(log_len)
+        || crc32fast::hash(payload) != crc
+    {
+        return None;
+    }
+    bincode::deserialize::<Hint>(payload)
+        .ok()
+        .filter(|hint| hint.log_id == log_id)
+}
+
+/// Returns the id of the log file of the hint file named `file_name`, or `None` if it is not the name of a hint file
+fn parse_hint_file_name(file_name: &str) -> Option<u64> {
+    file_name
+        .strip_prefix(LOG_FILE_PREFIX)?
+        .strip_suffix(HINT_FILE_SUFFIX)?
+        .parse()
+        .ok()
+}
+
+/// Writes the hint file of a sealed log file. The hint file is written under a temporary name and then renamed,
+/// so that a crash never leaves a partially written hint file behind.
+fn write_hint_file(log_file_path: &Path, hint_file_path: &Path, hint: &Hint) -> Result<()> {
+    let payload = bincode::serialize(hint)?;
+    let mut contents = Vec::with_capacity(HINT_FILE_HEADER_SIZE + payload.len());
+    contents.extend_from_slice(&HINT_FILE_MAGIC);
+    contents.extend_from_slice(&fs::metadata(log_file_path)?.len().to_le_bytes());
+    contents.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    contents.extend_from_slice(&payload);
+    let tmp_file_path = hint_file_path.with_extension(HINT_FILE_TMP_EXTENSION);
+    fs::write(tmp_file_path.as_path(), contents)?;
+    fs::rename(tmp_file_path, hint_file_path)?;
+    Ok(())
+}
+
+/// Reads every record of a log file and reports the problems found. A corrupted record is skipped
+/// as long as its length still fits in the file, so that the records after it are checked as well.
+fn verify_log_file(log_id: u64, log_file_path: &Path) -> Result<LogFileReport> {
+    let mut reader = BufReader::new(File::open(log_file_path)?);
+    let len = reader.get_ref().metadata()?.len();
+    let mut report = LogFileReport {
+        log_id,
+        path: log_file_path.to_path_buf(),
+        len,
+        cmds: 0,
+        problems: Vec::new(),
+    };
+    // A file shorter than the header was never written to, except for a torn header
+    if len < LOG_FILE_HEADER_SIZE {
+        if len > 0 {
+            report.problems.push(LogFileProblem::TornTail { offset: 0 });
+        }
+        return Ok(report);
+    }
+    let version = match read_log_file_header(log_id, &mut reader) {
+        Ok(version) => version,
+        Err(KvStoreError::Io(err)) => return Err(err.into()),
+        Err(_) => {
+            report.problems.push(LogFileProblem::InvalidHeader);
+            return Ok(report);
+        }
+    };
+    if version == 0 {
+        verify_unframed_log_file(&mut report, log_file_path)?;
+        return Ok(report);
+    }
+    let mut offset = LOG_FILE_HEADER_SIZE;
+    let mut record = Vec::new();
+    while offset < len {
+        let remaining = len - offset;
+        if remaining < RECORD_HEADER_SIZE {
+            report.problems.push(LogFileProblem::TornTail { offset });
+            break;
+        }
+        let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+        reader.read_exact(&mut header)?;
+        let payload_len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let record_len = RECORD_HEADER_SIZE + payload_len;
+        if record_len > remaining {
+            report.problems.push(LogFileProblem::TornTail { offset });
+            break;
+        }
+        record.clear();
+        record.extend_from_slice(&header);
+        record.resize(record_len as usize, 0u8);
+        reader.read_exact(&mut record[RECORD_HEADER_SIZE as usize..])?;
+        let payload = record_payload(&record);
+        let cmds = payload
+            .and_then(|payload| decode_cmd(version, payload).ok())
+            .and_then(|cmd| record_cmds(log_id, version, offset, record_len, cmd).ok());
+        match cmds {
+            Some(cmds) => report.cmds += cmds.len() as u64,
+            // Only a checksum failure at the end of the file is left by a crash, as `LogFileScanner` tells them apart
+            None if payload.is_none() && record_len == remaining => {
+                report.problems.push(LogFileProblem::TornTail { offset })
+            }
+            None => report
+                .problems
+                .push(LogFileProblem::CorruptedRecord { offset }),
+        }
+        offset += record_len;
+    }
+    Ok(report)
+}
+
+/// Reads every command of a version 0 log file and reports the problems found. Its commands are not framed into
+/// records, so the commands after a corrupted one can not be told apart and are left unchecked.
+fn verify_unframed_log_file(report: &mut LogFileReport, log_file_path: &Path) -> Result<()> {
+    let mut scanner = LogFileScanner::open(report.log_id, log_file_path)?;
+    loop {
+        match scanner.next_record() {
+            Ok(Some(ScannedRecord::Valid { .. })) => report.cmds += 1,
+            Ok(Some(ScannedRecord::Torn { offset })) => {
+                report.problems.push(LogFileProblem::TornTail { offset })
+            }
+            Ok(None) => return Ok(()),
+            Err(KvStoreError::CorruptedLog { offset, .. }) => {
+                report
+                    .problems
+                    .push(LogFileProblem::CorruptedRecord { offset });
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Moves the part of the log file starting at `offset` to a file of the quarantine directory of the store,
+/// and cuts it off the log file. The whole log file is moved if `offset` is 0.
+/// Returns the path of the file holding the part moved.
+fn quarantine_log_tail(dir_path: &Path, log_file_path: &Path, offset: u64) -> Result<PathBuf> {
+    let quarantine_dir_path = dir_path.join(QUARANTINE_DIR);
+    fs::create_dir_all(quarantine_dir_path.as_path())?;
+    let file_name = log_file_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(KvStoreError::WrongFileNameFormat)?;
+    let quarantine_path = quarantine_dir_path.join(format!("{}.{}", file_name, offset));
+    if offset == 0 {
+        fs::rename(log_file_path, quarantine_path.as_path())?;
+    } else {
+        let mut log_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(log_file_path)?;
+        log_file.seek(SeekFrom::Start(offset))?;
+        let mut quarantine_file = File::create(quarantine_path.as_path())?;
+        std::io::copy(&mut log_file, &mut quarantine_file)?;
+        quarantine_file.sync_all()?;
+        log_file.set_len(offset)?;
+        log_file.sync_all()?;
+    }
+    File::open(quarantine_dir_path.as_path())?.sync_all()?;
+    Ok(quarantine_path)
+}
+
+/// Returns the bound right after the last key starting with `prefix`, which is the prefix with its last byte
+/// that is not 0xFF incremented and the ones after it dropped. There is none if every byte is 0xFF.
+fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    match prefix.iter().rposition(|byte| *byte != 0xFF) {
+        Some(i) => {
+            let mut end = prefix[..=i].to_vec();
+            end[i] += 1;
+            Bound::Excluded(end)
+        }
+        None => Bound::Unbounded,
+    }
+}
+
+/// Hard links the file at `src` to `dst`, or copies it when they are not in the same file system
+fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    if fs::hard_link(src, dst).is_err() {
+        fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
 impl LogFileWriter {
     fn open<P>(id: u64, log_path: P, cmd_counter: u64) -> Result<Self>
     where
@@ -95,10 +727,14 @@ impl LogFileWriter {
             .create(true)
             .append(true)
             .open(log_path.as_ref())?;
-        let offset = writer.seek(SeekFrom::End(0))?;
+        let mut offset = writer.seek(SeekFrom::End(0))?;
+        if offset == 0 {
+            writer.write_all(&log_file_header())?;
+            offset = LOG_FILE_HEADER_SIZE;
+        }
         Ok(Self {
             id,
-            writer,
+            writer: Arc::new(writer),
             cmd_counter,
             offset,
         })
@@ -108,13 +744,15 @@ impl LogFileWriter {
     where
         P: AsRef<Path>,
     {
-        self.id = id;
-        self.writer = OpenOptions::new()
+        let mut writer = OpenOptions::new()
             .create(true)
             .append(true)
             .open(log_path.as_ref())?;
+        writer.write_all(&log_file_header())?;
+        self.id = id;
+        self.writer = Arc::new(writer);
         self.cmd_counter = 0;
-        self.offset = 0;
+        self.offset = LOG_FILE_HEADER_SIZE;
         Ok(())
     }
 
@@ -133,14 +771,94 @@ impl LogFileWriter {
         self.offset
     }
 
+    /// Get the position right after the last record written to the log file.
+    fn position(&self) -> LogPosition {
+        LogPosition {
+            log_id: self.id,
+            offset: self.offset,
+        }
+    }
+
+    /// Get a handle to the log file that can be used to sync it to the disk without holding the writer lock.
+    fn file(&self) -> Arc<File> {
+        self.writer.clone()
+    }
+
+    /// Forces all the records written to the log file to the disk.
+    fn sync(&self) -> Result<()> {
+        self.writer.sync_data()?;
+        Ok(())
+    }
+
+    /// Frames the commands into a single batch record and appends it to the log file in a single write.
+    /// Returns the offsets and lengths of the commands inside of the batch record.
+    fn append_batch(&mut self, cmds: &[Command]) -> Result<RecordLocations> {
+        let (record, locations) = encode_batch_record(cmds)?;
+        (&*self.writer).write_all(&record)?;
+        let offset = self.offset;
+        self.offset += record.len() as u64;
+        self.cmd_counter += cmds.len() as u64;
+        Ok(locations
+            .into_iter()
+            .map(|(inner_offset, len)| (offset + inner_offset, len))
+            .collect())
+    }
+
+    /// Frames the command into a record and appends it to the log file in a single write.
+    /// Returns the length of the record.
     fn append_cmd(&mut self, cmd: Command) -> Result<u64> {
-        let cmd_serialized = bincode::serialize(&cmd)?;
-        let cmd_len = cmd_serialized.len() as u64;
-        self.writer.write_all(&cmd_serialized)?;
-        self.writer.flush()?;
-        self.offset += cmd_len;
+        let record = encode_record(&cmd)?;
+        let record_len = record.len() as u64;
+        (&*self.writer).write_all(&record)?;
+        self.offset += record_len;
         self.cmd_counter += 1;
-        Ok(cmd_len)
+        Ok(record_len)
+    }
+}
+
+impl<'a> CompactionOutput<'a> {
+    fn new(log_dir_path: &'a Path, options: &'a KvStoreOptions, free_ids: Range<u64>) -> Self {
+        Self {
+            log_dir_path,
+            options,
+            free_ids,
+            used_ids: Vec::new(),
+            writer: None,
+            bytes_written: 0,
+        }
+    }
+
+    /// Appends the command to the current output log file, which is replaced by a new one once it is full.
+    /// There are as many reserved ids as compacted files and the commands kept fit in the compacted files, so the
+    /// reserved ids only run out when the last output log file is full, which is then allowed to grow further.
+    /// Returns the log id, offset and length of the command.
+    fn append(&mut self, cmd: Command) -> Result<(u64, u64, u64)> {
+        let is_full = self.writer.as_ref().is_none_or(|writer| {
+            writer.offset() > self.options.max_file_size
+                || writer.cmd_counter() > self.options.max_file_cmds
+        });
+        if is_full && !self.free_ids.is_empty() {
+            if let Some(writer) = self.writer.take() {
+                writer.sync()?;
+            }
+            let log_id = self.free_ids.next().unwrap();
+            let log_path = KvStore::format_log_path(self.log_dir_path, log_id);
+            self.writer = Some(LogFileWriter::open(log_id, log_path, 0)?);
+            self.used_ids.push(log_id);
+        }
+        let writer = self.writer.as_mut().unwrap();
+        let (log_id, offset) = (writer.id(), writer.offset());
+        let len = writer.append_cmd(cmd)?;
+        self.bytes_written += len;
+        Ok((log_id, offset, len))
+    }
+
+    /// Syncs the output log files to the disk and returns their ids
+    fn finish(self) -> Result<Vec<u64>> {
+        if let Some(writer) = self.writer {
+            writer.sync()?;
+        }
+        Ok(self.used_ids)
     }
 }
 
@@ -149,38 +867,249 @@ impl LogFileReader {
     where
         P: AsRef<Path>,
     {
+        let mut reader = OpenOptions::new()
+            .read(true)
+            .create(false)
+            .open(log_path.as_ref())?;
+        let version = read_log_file_header(id, &mut reader)?;
         Ok(Self {
             id,
-            reader: OpenOptions::new()
-                .read(true)
-                .create(false)
-                .open(log_path.as_ref())?,
+            version,
+            reader,
         })
     }
 
     fn read_cmd_at(&self, offset: u64, len: u64) -> Result<Command> {
         let mut buf: SmallVec<[u8; 512]> = smallvec![0u8; len as usize];
         self.reader.read_exact_at(offset, &mut buf[..])?;
-        Ok(bincode::deserialize(&buf)?)
+        // Version 0 commands are not framed into records
+        let payload = if self.version == 0 {
+            Some(&buf[..])
+        } else {
+            record_payload(&buf)
+        };
+        let payload = payload.ok_or(KvStoreError::CorruptedLog {
+            log_id: self.id,
+            offset,
+        })?;
+        decode_cmd(self.version, payload)
     }
+}
 
-    /// Get the log file reader's id.
-    fn id(&self) -> u64 {
-        self.id
+impl LogReaderCache {
+    fn new(log_dir_path: PathBuf, capacity: usize) -> Self {
+        let shard_capacity = capacity.div_ceil(READER_CACHE_SHARDS).max(1);
+        let shards = (0..READER_CACHE_SHARDS)
+            .map(|_| RwLock::new(LogReaderCacheShard::new(shard_capacity)))
+            .collect();
+        Self {
+            log_dir_path,
+            shards,
+        }
+    }
+
+    /// Returns the reader of the log file `log_id`, opening it if it is not cached.
+    /// The file is opened without holding the lock of the shard, so a slow open does not block the other readers.
+    fn get(&self, log_id: u64) -> Result<Arc<LogFileReader>> {
+        let shard = self.shard(log_id);
+        if let Some(reader) = shard.read().get(log_id) {
+            return Ok(reader);
+        }
+        let log_path = KvStore::format_log_path(self.log_dir_path.as_path(), log_id);
+        let reader = Arc::new(LogFileReader::open(log_id, log_path)?);
+        Ok(shard.write().insert(log_id, reader))
+    }
+
+    /// Closes the cached reader of the log file `log_id`, if any, so that the space of the file is given back
+    /// to the disk once it is deleted
+    fn remove(&self, log_id: u64) {
+        self.shard(log_id).write().remove(log_id);
+    }
+
+    fn shard(&self, log_id: u64) -> &RwLock<LogReaderCacheShard> {
+        &self.shards[(log_id % self.shards.len() as u64) as usize]
     }
 }
 
-impl WriterControlData {
-    fn new(curr_log: LogFileWriter, total_cmd_counter: u64) -> Self {
+impl LogReaderCacheShard {
+    fn new(capacity: usize) -> Self {
         Self {
-            curr_log,
-            total_cmd_counter,
+            capacity,
+            tick: AtomicU64::new(0),
+            readers: StdHashMap::with_capacity(capacity),
+        }
+    }
+
+    fn get(&self, log_id: u64) -> Option<Arc<LogFileReader>> {
+        let cached = self.readers.get(&log_id)?;
+        let tick = self.tick.load(Ordering::Relaxed);
+        // Stored only when it changes, so that the hot readers are not written on every read
+        if cached.last_used.load(Ordering::Relaxed) != tick {
+            cached.last_used.store(tick, Ordering::Relaxed);
+        }
+        Some(cached.reader.clone())
+    }
+
+    /// Caches the `reader`, unless another one of the same log file was cached in the meantime,
+    /// and returns the cached one
+    fn insert(&mut self, log_id: u64, reader: Arc<LogFileReader>) -> Arc<LogFileReader> {
+        if let Some(cached) = self.get(log_id) {
+            return cached;
+        }
+        let tick = {
+            let tick = self.tick.get_mut();
+            *tick += 1;
+            *tick
+        };
+        if self.readers.len() >= self.capacity {
+            let lru = self
+                .readers
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used.load(Ordering::Relaxed))
+                .map(|(id, _)| *id)
+                .unwrap();
+            self.readers.remove(&lru);
+        }
+        self.readers.insert(
+            log_id,
+            CachedLogReader {
+                last_used: AtomicU64::new(tick),
+                reader: reader.clone(),
+            },
+        );
+        reader
+    }
+
+    fn remove(&mut self, log_id: u64) {
+        self.readers.remove(&log_id);
+    }
+}
+
+impl LogFileScanner {
+    fn open<P>(id: u64, log_path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new()
+            .read(true)
+            .create(false)
+            .open(log_path.as_ref())?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        // A file shorter than the header was never written to, except for a torn header
+        let (offset, version) = if file_len < LOG_FILE_HEADER_SIZE {
+            (0, LOG_FORMAT_VERSION)
+        } else {
+            let version = read_log_file_header(id, &mut reader)?;
+            let offset = first_record_offset(version);
+            reader.seek(SeekFrom::Start(offset))?;
+            (offset, version)
+        };
+        Ok(Self {
+            id,
+            version,
+            reader,
+            offset,
+            file_len,
+            payload_buf: Vec::new(),
+        })
+    }
+
+    /// Reads the next record of the log file. Returns `None` when there are no more records.
+    /// Returns an error if a record fails its checksum and it is not the last one of the file.
+    fn next_record(&mut self) -> Result<Option<ScannedRecord>> {
+        let offset = self.offset;
+        if offset >= self.file_len {
+            return Ok(None);
         }
+        let remaining = self.file_len - offset;
+        if self.version == 0 {
+            return self.next_unframed_record(offset, remaining);
+        }
+        if remaining < RECORD_HEADER_SIZE {
+            return Ok(Some(self.torn_at(offset)));
+        }
+        let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+        self.reader.read_exact(&mut header)?;
+        let payload_len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let len = RECORD_HEADER_SIZE + payload_len;
+        if len > remaining {
+            return Ok(Some(self.torn_at(offset)));
+        }
+        self.payload_buf.resize(payload_len as usize, 0u8);
+        self.reader.read_exact(&mut self.payload_buf[..])?;
+        if crc32fast::hash(&self.payload_buf) != crc {
+            if len == remaining {
+                return Ok(Some(self.torn_at(offset)));
+            }
+            return Err(KvStoreError::CorruptedLog {
+                log_id: self.id,
+                offset,
+            });
+        }
+        let cmd = decode_cmd(self.version, &self.payload_buf).map_err(|_| {
+            KvStoreError::CorruptedLog {
+                log_id: self.id,
+                offset,
+            }
+        })?;
+        self.offset += len;
+        Ok(Some(ScannedRecord::Valid { offset, len, cmd }))
+    }
+
+    /// Reads the next command of a version 0 log file, which is not framed into a record. A command whose fields
+    /// run past the end of the file is torn, and any other one that can not be decoded is corrupted.
+    fn next_unframed_record(
+        &mut self,
+        offset: u64,
+        remaining: u64,
+    ) -> Result<Option<ScannedRecord>> {
+        let cmd = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(remaining)
+            .deserialize_from::<_, CommandV0>(&mut self.reader);
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            Err(err) => {
+                return match *err {
+                    bincode::ErrorKind::SizeLimit => Ok(Some(self.torn_at(offset))),
+                    bincode::ErrorKind::Io(ref err)
+                        if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                    {
+                        Ok(Some(self.torn_at(offset)))
+                    }
+                    _ => Err(KvStoreError::CorruptedLog {
+                        log_id: self.id,
+                        offset,
+                    }),
+                };
+            }
+        };
+        let len = bincode::serialized_size(&cmd)?;
+        self.offset += len;
+        Ok(Some(ScannedRecord::Valid {
+            offset,
+            len,
+            cmd: cmd.into(),
+        }))
     }
 
-    /// Get a reference to the writer control data's total cmd counter.
-    fn total_cmd_counter(&self) -> u64 {
-        self.total_cmd_counter
+    fn torn_at(&mut self, offset: u64) -> ScannedRecord {
+        self.offset = self.file_len;
+        ScannedRecord::Torn { offset }
+    }
+
+    /// Get the log file scanner's format version.
+    fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+impl WriterControlData {
+    fn new(curr_log: LogFileWriter, segments: BTreeMap<u64, u64>) -> Self {
+        Self { curr_log, segments }
     }
 
     /// Get a mutable reference to the writer control data's curr log.
@@ -188,61 +1117,418 @@ impl WriterControlData {
         &mut self.curr_log
     }
 
-    fn inc_total_cmd_counter(&mut self) {
-        self.total_cmd_counter += 1;
-    }
+    fn add_live_bytes(&mut self, log_id: u64, val: u64) {
+        *self.segments.entry(log_id).or_insert(0) += val;
+    }
+
+    /// Log files already collected are no longer accounted for
+    fn sub_live_bytes(&mut self, log_id: u64, val: u64) {
+        if let Some(live_bytes) = self.segments.get_mut(&log_id) {
+            *live_bytes = live_bytes.saturating_sub(val);
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Creates the default options:
+    ///     durability: `Durability::Buffered`
+    ///     max_file_size: 1 GiB
+    ///     max_file_cmds: 5000
+    ///     compaction_policy: `GarbageRatioPolicy::new()`
+    ///     reader_cache_capacity: 64
+    ///     value_cache_capacity: 0, no value is cached
+    pub fn new() -> Self {
+        Self {
+            durability: Durability::Buffered,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_file_cmds: DEFAULT_MAX_FILE_CMDS,
+            compaction_policy: Arc::new(GarbageRatioPolicy::new()),
+            reader_cache_capacity: DEFAULT_READER_CACHE_CAPACITY,
+            value_cache_capacity: 0,
+        }
+    }
+
+    /// Sets when the writes are synced to the disk.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets the size in bytes that the current log file must exceed to be sealed and replaced by a new one.
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Sets the number of commands that the current log file must exceed to be sealed and replaced by a new one.
+    pub fn max_file_cmds(mut self, max_file_cmds: u64) -> Self {
+        self.max_file_cmds = max_file_cmds;
+        self
+    }
+
+    /// Sets the policy that decides which sealed log files are rewritten by a compaction.
+    pub fn compaction_policy<P: CompactionPolicy + 'static>(
+        mut self,
+        compaction_policy: P,
+    ) -> Self {
+        self.compaction_policy = Arc::new(compaction_policy);
+        self
+    }
+
+    /// Sets the number of log files kept open for reading, shared by all the clones of the store.
+    /// The cache is split in shards, so the capacity is rounded up to a multiple of their number.
+    pub fn reader_cache_capacity(mut self, reader_cache_capacity: usize) -> Self {
+        self.reader_cache_capacity = reader_cache_capacity;
+        self
+    }
+
+    /// Sets the number of bytes of values kept in memory to serve the reads of hot keys without going to the disk.
+    /// A capacity of 0 disables the cache.
+    pub fn value_cache_capacity(mut self, value_cache_capacity: u64) -> Self {
+        self.value_cache_capacity = value_cache_capacity;
+        self
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KvStore {
+    /// Open the KvStore at a given `path` with the default options.
+    /// Return the KvStore.
+    /// Writes are left to the operating system to be flushed to the disk (`Durability::Buffered`).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::KvStore;
+    /// let dictionary = KvStore::open("./").unwrap();
+    /// ```
+    pub fn open<P>(path: P) -> Result<Self>
+    where
+        P: Into<PathBuf>,
+    {
+        KvStore::open_with(path, KvStoreOptions::new())
+    }
+
+    /// Open the KvStore at a given `path` with the given `options`.
+    /// Return the KvStore.
+    ///
+    /// The directory is locked until the last clone of the store is dropped, so that no other process writes
+    /// to the same log files. Return `KvStoreError::DirectoryLocked` if the store is already open,
+    /// in this process or another.
+    ///
+    /// A torn record at the end of the current log file is cut off, but one at the end of a sealed log file is
+    /// returned as `KvStoreError::CorruptedLog`, and left for `KvStore::repair` to cut off.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::{Durability, KvStore, KvStoreOptions};
+    /// let options = KvStoreOptions::new().durability(Durability::GroupCommit);
+    /// let dictionary = KvStore::open_with("./", options).unwrap();
+    /// ```
+    pub fn open_with<P>(path: P, options: KvStoreOptions) -> Result<Self>
+    where
+        P: Into<PathBuf>,
+    {
+        let log_dir_path = (path.into() as PathBuf).canonicalize()?.join("");
+        // Taken before anything is read, since opening the store cuts off the torn tails of the log files
+        let dir_lock = Arc::new(DirLock::acquire(log_dir_path.as_path())?);
+
+        let BuiltIndex {
+            storage_index,
+            mut segments,
+            last_log_id,
+            last_log_version,
+            mut cmd_counter,
+        } = KvStore::build_index(log_dir_path.as_path(), TornTail::Cut)?;
+
+        // Records are never appended to a log file written with an older version of the log format
+        let log_id = if last_log_version < LOG_FORMAT_VERSION {
+            cmd_counter = 0;
+            last_log_id + 1
+        } else {
+            last_log_id
+        };
+
+        let file_path = KvStore::format_log_path(log_dir_path.as_path(), log_id);
+        let curr_log_w = LogFileWriter::open(log_id, file_path.as_path(), cmd_counter)?;
+        segments.entry(log_id).or_insert(0);
+        let writer_ctrl = Arc::new(Mutex::new(WriterControlData::new(curr_log_w, segments)));
+        let log_readers = Arc::new(LogReaderCache::new(
+            log_dir_path.clone(),
+            options.reader_cache_capacity,
+        ));
+        let value_cache = Some(options.value_cache_capacity)
+            .filter(|capacity| *capacity > 0)
+            .map(|capacity| Arc::new(ValueCache::new(capacity)));
+        let storage_index = Arc::new(
+            storage_index
+                .into_iter()
+                .map(|(key, cmd_index)| (key, AtomicCell::new(cmd_index)))
+                .collect::<SkipMap<_, _>>(),
+        );
+        let synced_position = Arc::new(Mutex::new(LogPosition { log_id, offset: 0 }));
+
+        if let Durability::Interval(interval) = options.durability {
+            KvStore::spawn_interval_sync(Arc::downgrade(&writer_ctrl), interval);
+        }
+
+        Ok(KvStore {
+            storage_index,
+            collector: Collector::new(),
+            log_dir_path,
+            writer_ctrl,
+            log_readers,
+            value_cache,
+            compaction_lock: Arc::new(Mutex::new(())),
+            compaction_progress: Arc::new(Mutex::new(None)),
+            snapshots: Arc::new(Mutex::new(SnapshotRegistry::default())),
+            options,
+            synced_position,
+            _dir_lock: dir_lock,
+        })
+    }
+
+    /// Returns the hits and misses of the value cache and what it holds, or only zeros if it is disabled.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::{KvStore, KvStoreOptions, KvsEngine};
+    /// let options = KvStoreOptions::new().value_cache_capacity(64 * 1024 * 1024);
+    /// let config = KvStore::open_with("./", options).unwrap();
+    /// config.set("timeout".to_owned(), "30".to_owned()).unwrap();
+    /// config.get("timeout".to_owned()).unwrap();
+    /// config.get("timeout".to_owned()).unwrap();
+    /// assert_eq!(config.value_cache_stats().hits, 1);
+    /// ```
+    pub fn value_cache_stats(&self) -> CacheStats {
+        self.value_cache
+            .as_ref()
+            .map_or_else(CacheStats::default, |value_cache| value_cache.stats())
+    }
+
+    /// Iterate over all the live key-value pairs of the store at `path`, in ascending order of keys.
+    /// The log files are read without opening the store, so they are never written to, not even to cut off
+    /// a torn tail. The directory is locked until the iterator is dropped, so that the store is not opened
+    /// and compacted in the meantime. Returns `KvStoreError::DirectoryLocked` if the store is open when called.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::KvStore;
+    /// for record in KvStore::dump("./").unwrap() {
+    ///     println!("{}", serde_json::to_string(&record.unwrap()).unwrap());
+    /// }
+    /// ```
+    pub fn dump<P: AsRef<Path>>(path: P) -> Result<DumpIterator> {
+        let log_dir_path = path.as_ref().to_path_buf();
+        let dir_lock = DirLock::acquire(log_dir_path.as_path())?;
+        let BuiltIndex { storage_index, .. } =
+            KvStore::build_index(log_dir_path.as_path(), TornTail::Skip)?;
+        let log_readers = LogReaderCache::new(log_dir_path, DEFAULT_READER_CACHE_CAPACITY);
+        let entries = storage_index
+            .into_iter()
+            .sorted_by(|(key1, _), (key2, _)| Ord::cmp(key1, key2));
+        Ok(Box::new(entries.map(move |(key, cmd_index)| {
+            // Moved into the iterator, so that the directory stays locked until it is dropped
+            let _dir_lock = &dir_lock;
+            let cmd = log_readers
+                .get(cmd_index.log_id)?
+                .read_cmd_at(cmd_index.offset, cmd_index.len)?;
+            match cmd {
+                Command::Set { value, .. } => Ok(DumpRecord {
+                    key,
+                    value,
+                    expires_at: cmd_index.expires_at,
+                }),
+                _ => Err(KvStoreError::WrongFileOffset),
+            }
+        })))
+    }
+
+    /// Checks every log file of the store at `path` without opening it, decoding all of their commands, and reports
+    /// the records that can not be read, the files that do not belong to the store, the log files lost and the gaps
+    /// left in the log ids by the compactions.
+    /// Returns `KvStoreError::DirectoryLocked` if the store is open, and it must not be opened in the meantime.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::KvStore;
+    /// let report = KvStore::verify("./").unwrap();
+    /// for log_file in report.log_files {
+    ///     for problem in log_file.problems {
+    ///         println!("{}: {}", log_file.path.display(), problem);
+    ///     }
+    /// }
+    /// ```
+    pub fn verify<P: AsRef<Path>>(path: P) -> Result<VerifyReport> {
+        DirLock::check(path.as_ref())?;
+        KvStore::_verify(path.as_ref())
+    }
+
+    fn _verify(dir_path: &Path) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        for (log_id, log_file_path) in KvStore::list_log_ids_files_sorted(dir_path) {
+            report
+                .log_files
+                .push(verify_log_file(log_id, log_file_path.as_path())?);
+        }
+
+        let log_ids = report
+            .log_files
+            .iter()
+            .map(|log_file| log_file.log_id)
+            .collect::<BTreeSet<_>>();
 
-    fn sub_total_cmd_counter(&mut self, val: u64) {
-        self.total_cmd_counter -= val;
+        let known_paths = log_ids
+            .iter()
+            .flat_map(|log_id| {
+                vec![
+                    KvStore::format_log_path(dir_path, *log_id),
+                    KvStore::format_hint_path(dir_path, *log_id),
+                ]
+            })
+            .chain(vec![
+                dir_path.join(QUARANTINE_DIR),
+                dir_path.join(LOCK_FILE),
+            ])
+            .collect::<StdHashSet<_>>();
+        for entry in WalkDir::new(dir_path)
+            .min_depth(1)
+            .max_depth(1)
+            .sort_by(|e1, e2| Ord::cmp(&e1.file_name(), &e2.file_name()))
+        {
+            let entry = entry?;
+            if known_paths.contains(entry.path()) {
+                continue;
+            }
+            // The hint file of a log file is deleted before it, so it is never left behind by a compaction
+            match entry.file_name().to_str().and_then(parse_hint_file_name) {
+                Some(log_id) => report.missing_log_ids.push(log_id),
+                None => report.unknown_files.push(entry.path().to_path_buf()),
+            }
+        }
+        if let (Some(first), Some(last)) = (log_ids.iter().next(), log_ids.iter().next_back()) {
+            report.unused_log_ids = (*first..*last)
+                .filter(|log_id| {
+                    !log_ids.contains(log_id) && !report.missing_log_ids.contains(log_id)
+                })
+                .collect();
+        }
+        Ok(report)
     }
-}
 
-impl KvStore {
-    /// Open the KvStore at a given `path`.
-    /// Return the KvStore.
+    /// Repairs the log files of the store at `path` found with problems by `KvStore::verify`, so that it can
+    /// be opened again, and returns the changes made. The directory is locked in the meantime,
+    /// so `KvStoreError::DirectoryLocked` is returned if the store is open.
+    ///
+    /// A torn tail is cut off the log file, just like opening the store does. A log file with a corrupted record
+    /// is cut off at it, and the part cut off, with the valid records after the corrupted one, is moved to the
+    /// quarantine directory of the store. The keys written by the records cut off go back to their older values.
     ///
     /// # Examples
     ///
     /// ```no_run
     /// use kvs::KvStore;
+    /// if !KvStore::verify("./").unwrap().is_ok() {
+    ///     KvStore::repair("./").unwrap();
+    /// }
     /// let dictionary = KvStore::open("./").unwrap();
     /// ```
-    pub fn open<P>(path: P) -> Result<Self>
-    where
-        P: Into<PathBuf>,
-    {
-        let log_dir_path = (path.into() as PathBuf).canonicalize()?.join("");
-
-        let (storage_index, total_cmd_counter, log_id, cmd_counter) =
-            KvStore::build_index(log_dir_path.as_path())?;
+    pub fn repair<P: AsRef<Path>>(path: P) -> Result<Vec<RepairAction>> {
+        let dir_path = path.as_ref();
+        let _dir_lock = DirLock::acquire(dir_path)?;
+        let mut actions = Vec::new();
+        for log_file in KvStore::_verify(dir_path)?.log_files {
+            let action = match log_file.problems.first() {
+                None => continue,
+                // A torn tail is always the last problem of a log file
+                Some(LogFileProblem::TornTail { offset }) => {
+                    let file = OpenOptions::new()
+                        .write(true)
+                        .open(log_file.path.as_path())?;
+                    file.set_len(*offset)?;
+                    file.sync_all()?;
+                    RepairAction::Truncated {
+                        log_id: log_file.log_id,
+                        len: *offset,
+                    }
+                }
+                Some(LogFileProblem::InvalidHeader) => RepairAction::Quarantined {
+                    log_id: log_file.log_id,
+                    len: 0,
+                    path: quarantine_log_tail(dir_path, log_file.path.as_path(), 0)?,
+                },
+                Some(LogFileProblem::CorruptedRecord { offset }) => RepairAction::Quarantined {
+                    log_id: log_file.log_id,
+                    len: *offset,
+                    path: quarantine_log_tail(dir_path, log_file.path.as_path(), *offset)?,
+                },
+            };
+            // The hint file describes the log file as it was before the repair
+            match fs::remove_file(KvStore::format_hint_path(dir_path, log_file.log_id)) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+            actions.push(action);
+        }
+        if !actions.is_empty() {
+            File::open(dir_path)?.sync_all()?;
+        }
+        Ok(actions)
+    }
 
-        let file_path = KvStore::format_log_path(log_dir_path.as_path(), log_id);
-        let curr_log_w = LogFileWriter::open(log_id, file_path.as_path(), cmd_counter)?;
-        let writer_ctrl = Arc::new(Mutex::new(WriterControlData::new(
-            curr_log_w,
-            total_cmd_counter,
-        )));
-        let curr_log_r = Atomic::new(LogFileReader::open(log_id, file_path.as_path())?);
-        let storage_index = Arc::new(FlurryHashMap::from_iter(storage_index.into_iter()));
-        let last_collected_file_index = Arc::new(AtomicI64::new(log_id as i64 - 1));
+    /// Spawns a thread that syncs the current log file every `interval`, until the store is dropped.
+    fn spawn_interval_sync(writer_ctrl: Weak<Mutex<WriterControlData>>, interval: Duration) {
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let file = match writer_ctrl.upgrade() {
+                Some(writer_ctrl) => writer_ctrl.lock().curr_log_mut().file(),
+                None => break,
+            };
+            // A failed sync is retried on the next interval
+            let _ = file.sync_data();
+        });
+    }
 
-        Ok(KvStore {
-            storage_index,
-            log_dir_path,
-            writer_ctrl,
-            curr_log_r,
-            last_collected_file_index,
-        })
+    /// Makes the writes done so far durable according to the durability mode of the store.
+    /// Must be called right after a write with the writer lock still held. The lock is released
+    /// before waiting for a group commit, so that other writers can join it.
+    fn commit(&self, mut writer_ctrl: MutexGuard<'_, WriterControlData>) -> Result<()> {
+        match self.options.durability {
+            Durability::SyncEachWrite => writer_ctrl.curr_log_mut().sync(),
+            Durability::GroupCommit => {
+                let position = writer_ctrl.curr_log_mut().position();
+                drop(writer_ctrl);
+                self.group_commit(position)
+            }
+            Durability::Buffered | Durability::Interval(_) => Ok(()),
+        }
     }
 
-    /// Check if it should run compaction algorithm
-    fn should_run_compaction<'g>(
-        &self,
-        writer_ctrl: &'_ mut MutexGuard<'g, WriterControlData>,
-    ) -> bool {
-        writer_ctrl.total_cmd_counter() > CMDS_THRESHOLD
-            && ((writer_ctrl.total_cmd_counter() / (self.storage_index.len() as u64)) as f64)
-                > CMD_KEY_FACTOR
+    /// Waits until the logs are synced at least up to `position`.
+    /// The first writer to acquire the sync lock syncs everything written to the current log so far,
+    /// on behalf of all the writers queued behind it.
+    fn group_commit(&self, position: LogPosition) -> Result<()> {
+        let mut synced_position = self.synced_position.lock();
+        if *synced_position >= position {
+            return Ok(());
+        }
+        let (file, target_position) = {
+            let writer_ctrl = &mut self.writer_ctrl.lock();
+            let curr_log = writer_ctrl.curr_log_mut();
+            (curr_log.file(), curr_log.position())
+        };
+        file.sync_data()?;
+        *synced_position = target_position;
+        Ok(())
     }
 
     /// Check if it should create a new log file
@@ -251,8 +1537,8 @@ impl KvStore {
         writer_ctrl: &'_ mut MutexGuard<'g, WriterControlData>,
     ) -> bool {
         let curr_log = writer_ctrl.curr_log_mut();
-        curr_log.offset() > CURR_FILE_OFFSET_THRESHOLD
-            || curr_log.cmd_counter() > (CMDS_THRESHOLD / 2)
+        curr_log.offset() > self.options.max_file_size
+            || curr_log.cmd_counter() > self.options.max_file_cmds
     }
 
     /// Create a new log following the format for log file name and save the current log file information
@@ -260,77 +1546,444 @@ impl KvStore {
     fn do_create_new_file<'g>(
         &self,
         writer_ctrl: &'_ mut MutexGuard<'g, WriterControlData>,
+    ) -> Result<()> {
+        let new_file_id = writer_ctrl.curr_log_mut().id() + 1;
+        self.seal_curr_log(new_file_id, writer_ctrl)
+    }
+
+    /// Seals the current log and replaces it with a new one with id `new_file_id`.
+    fn seal_curr_log<'g>(
+        &self,
+        new_file_id: u64,
+        writer_ctrl: &'_ mut MutexGuard<'g, WriterControlData>,
     ) -> Result<()> {
         let curr_log = writer_ctrl.curr_log_mut();
-        let new_file_id = curr_log.id() + 1;
+        if self.options.durability != Durability::Buffered {
+            // Group commits and interval syncs only sync the current log, so a log must be synced before being sealed
+            curr_log.sync()?;
+        }
         let new_file_path = KvStore::format_log_path(self.log_dir_path.as_path(), new_file_id);
         curr_log.open_another(new_file_id, new_file_path)?;
+        writer_ctrl.add_live_bytes(new_file_id, 0);
+        if self.options.durability != Durability::Buffered {
+            File::open(self.log_dir_path.as_path())?.sync_all()?;
+        }
         Ok(())
     }
 
-    /// Runs the following compaction strategy:
-    ///     1. Collect all command offsets related to each active entry from the storage index map and rearrange them in a map where
-    ///        the keys are the log file ids and the value is a set of offsets of each active entry in that corresponding file.
-    ///     2. List all the log file names and ids in the database directory sorted by name.
-    ///     3. For each file read all of its commands, if the command is a set and refers to an active entry, write it down to
-    ///        the current log and after processing all of the file commands, delete the file.
-    fn do_compaction<'g>(
+    /// Returns the live and dead bytes of each log file with an id lower than `end_log_id`, sorted by log id
+    fn segment_stats(
         &self,
-        writer_ctrl: &'_ mut MutexGuard<'g, WriterControlData>,
-    ) -> Result<()> {
-        let index_guard = &self.storage_index.guard();
-        let curr_log = writer_ctrl.curr_log_mut();
-        let active_file_id_offsets_map = self.get_active_file_id_offsets_map(index_guard);
-        let curr_log_id = curr_log.id();
+        writer_ctrl: &WriterControlData,
+        end_log_id: u64,
+    ) -> Result<Vec<SegmentStats>> {
+        let mut segments = Vec::with_capacity(writer_ctrl.segments.len());
+        for (&log_id, &live_bytes) in writer_ctrl.segments.range(..end_log_id) {
+            let log_path = KvStore::format_log_path(self.log_dir_path.as_path(), log_id);
+            let total_bytes = fs::metadata(log_path)?
+                .len()
+                .saturating_sub(LOG_FILE_HEADER_SIZE);
+            segments.push(SegmentStats {
+                log_id,
+                live_bytes,
+                dead_bytes: total_bytes.saturating_sub(live_bytes),
+            });
+        }
+        Ok(segments)
+    }
 
-        let last_collected_file_index = self.last_collected_file_index.load(Ordering::SeqCst);
+    /// Returns the stats of the log files picked by the compaction policy out of the sealed `segments`
+    fn select_segments<'s>(&self, segments: &'s [SegmentStats]) -> Vec<&'s SegmentStats> {
+        let selected = self
+            .options
+            .compaction_policy
+            .select(segments)
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+        segments
+            .iter()
+            .filter(|segment| selected.contains(&segment.log_id))
+            .collect()
+    }
 
-        let log_ids_files = KvStore::list_log_ids_files_sorted(self.log_dir_path.as_path())
-            .filter(|(id, _)| *id < curr_log_id && (*id as i64) > last_collected_file_index)
+    /// Picks the log files to be compacted out of the sealed ones, according to the compaction policy
+    /// and to the byte budget of the `limits`.
+    /// If there are any, the current log is sealed and moved far enough ahead to leave a free log id for each of
+    /// the compacted log files, so that the live entries moved by the compaction are replayed after their old copies,
+    /// but before any write done while the compaction runs.
+    fn start_compaction<'g>(
+        &self,
+        writer_ctrl: &'_ mut MutexGuard<'g, WriterControlData>,
+        limits: &CompactionLimits,
+    ) -> Result<Option<Compaction>> {
+        let curr_log_id = writer_ctrl.curr_log_mut().id();
+        let segments = self.segment_stats(writer_ctrl, curr_log_id)?;
+        let mut budget = limits.max_bytes.unwrap_or(u64::MAX);
+        let selected = self
+            .select_segments(&segments)
+            .into_iter()
+            .enumerate()
+            .take_while(|(i, segment)| {
+                let bytes = segment.live_bytes + segment.dead_bytes;
+                let fits = *i == 0 || bytes <= budget;
+                budget = budget.saturating_sub(bytes);
+                fits
+            })
+            .map(|(_, segment)| segment.log_id)
+            .collect::<BTreeSet<_>>();
+        if selected.is_empty() {
+            return Ok(None);
+        }
+        let oldest_kept_log_id = segments
+            .iter()
+            .map(|segment| segment.log_id)
+            .find(|log_id| !selected.contains(log_id));
+        let log_ids_files = selected
+            .into_iter()
+            .map(|log_id| {
+                let log_path = KvStore::format_log_path(self.log_dir_path.as_path(), log_id);
+                (log_id, log_path)
+            })
             .collect::<Vec<_>>();
+        let output_ids = (curr_log_id + 1)..(curr_log_id + 1 + log_ids_files.len() as u64);
+        self.seal_curr_log(output_ids.end, writer_ctrl)?;
+        Ok(Some(Compaction {
+            log_ids_files,
+            output_ids,
+            oldest_kept_log_id,
+        }))
+    }
+
+    /// Runs the following compaction strategy, holding the writer lock only for the last step:
+    ///     1. Collect all command offsets related to each active entry from the storage index map and rearrange them in a map where
+    ///        the keys are the log file ids and the value is a set of offsets of each active entry in that corresponding file.
+    ///     2. For each compacted file read all of its commands, if the command is a set and refers to an active entry,
+    ///        write it down to the log files reserved for the compaction. Removals are kept as well while a log file
+    ///        left out of the compaction may still hold an older copy of the removed key, and so is a removal in place
+    ///        of every set of a key no longer indexed, which may have expired without any removal being written.
+    ///     3. Sync the new log files and point the storage index at the moved entries, except for the ones overwritten
+    ///        or removed in the meantime. Then delete the compacted files.
+    ///
+    /// Once the time budget of the `limits` is spent, the remaining files are left for a later compaction.
+    /// Their ids are greater than the ones of the compacted files, so they never hold an older copy of a moved key.
+    fn do_compaction(
+        &self,
+        compaction: Compaction,
+        limits: &CompactionLimits,
+        started_at: Instant,
+    ) -> Result<CompactionProgress> {
+        let active_file_id_offsets_map = self.get_active_file_id_offsets_map();
+        let now = now_millis();
+        let mut output = CompactionOutput::new(
+            self.log_dir_path.as_path(),
+            &self.options,
+            compaction.output_ids,
+        );
+        let mut moved_entries = Vec::new();
+        let mut expired_entries = Vec::new();
+        let mut kept_removals = Vec::new();
+        let mut removed_keys = StdHashSet::new();
+        let mut throttle = Throttle::new(limits.max_bytes_per_sec);
+        let mut progress = CompactionProgress {
+            log_files_total: compaction.log_ids_files.len(),
+            ..CompactionProgress::default()
+        };
+        *self.compaction_progress.lock() = Some(progress);
 
-        for (log_id, log_path) in log_ids_files {
-            {
-                let mut reader = BufReader::new(
-                    OpenOptions::new()
-                        .read(true)
-                        .create(false)
-                        .open(log_path.as_path())?,
-                );
-                let mut log_file_cmd_counter = 0;
-                let active_offsets = active_file_id_offsets_map.get(&log_id);
-                loop {
-                    let offset = reader.seek(SeekFrom::Current(0))?;
-                    let res = bincode::deserialize_from::<_, Command>(&mut reader);
-                    let is_active_entry = active_offsets.and_then(|hs| hs.get(&offset)).is_some();
-                    match res {
-                        Ok(Command::Set { key, value }) => {
-                            log_file_cmd_counter += 1;
-                            if is_active_entry {
-                                self.insert_entry(key, value, writer_ctrl)?;
+        for (log_id, log_path) in compaction.log_ids_files.iter() {
+            let out_of_time = limits
+                .max_duration
+                .is_some_and(|max_duration| started_at.elapsed() >= max_duration);
+            if progress.log_files_compacted > 0 && out_of_time {
+                break;
+            }
+            let mut scanner = LogFileScanner::open(*log_id, log_path.as_path())?;
+            let version = scanner.version();
+            let active_offsets = active_file_id_offsets_map.get(log_id);
+            let may_shadow_older_copies = compaction
+                .oldest_kept_log_id
+                .is_some_and(|oldest_kept_log_id| oldest_kept_log_id < *log_id);
+            while let Some(ScannedRecord::Valid { offset, len, cmd }) = scanner.next_record()? {
+                let bytes_written = output.bytes_written;
+                for (offset, _, cmd) in record_cmds(*log_id, version, offset, len, cmd)? {
+                    match cmd {
+                        Command::Set {
+                            key,
+                            value,
+                            expires_at,
+                        } => {
+                            let is_active_entry =
+                                active_offsets.and_then(|hs| hs.get(&offset)).is_some();
+                            if !is_active_entry {
+                                // A key expired and dropped from the index has no removal written for it
+                                if may_shadow_older_copies
+                                    && !self.storage_index.contains_key(&key)
+                                    && removed_keys.insert(key.clone())
+                                {
+                                    kept_removals.push(output.append(Command::Remove { key })?);
+                                }
+                                continue;
                             }
-                        }
-                        Ok(Command::Remove { key: _ }) => {
-                            log_file_cmd_counter += 1;
-                        }
-                        Err(err) => match *err {
-                            bincode::ErrorKind::Io(ref bincode_io_err) => {
-                                match bincode_io_err.kind() {
-                                    std::io::ErrorKind::UnexpectedEof => break,
-                                    _ => return Err(KvStoreError::from(err)),
+                            // Expired entries are dropped instead of being moved
+                            if expires_at.is_some_and(|expires_at| expires_at <= now) {
+                                if may_shadow_older_copies && removed_keys.insert(key.clone()) {
+                                    let location =
+                                        output.append(Command::Remove { key: key.clone() })?;
+                                    kept_removals.push(location);
                                 }
+                                expired_entries.push((key, *log_id, offset));
+                                continue;
                             }
-                            _ => return Err(KvStoreError::from(err)),
-                        },
+                            let (new_log_id, new_offset, new_len) =
+                                output.append(Command::Set {
+                                    key: key.clone(),
+                                    value,
+                                    expires_at,
+                                })?;
+                            moved_entries.push((
+                                key,
+                                *log_id,
+                                offset,
+                                CommandIndex {
+                                    log_id: new_log_id,
+                                    offset: new_offset,
+                                    len: new_len,
+                                    expires_at,
+                                },
+                            ));
+                        }
+                        Command::Remove { key } => {
+                            // A removal is only needed while the key has not been set again
+                            if may_shadow_older_copies
+                                && !self.storage_index.contains_key(&key)
+                                && removed_keys.insert(key.clone())
+                            {
+                                kept_removals.push(output.append(Command::Remove { key })?);
+                            }
+                        }
+                        Command::Batch { .. } => unreachable!(),
+                    }
+                }
+                let bytes_written = output.bytes_written - bytes_written;
+                progress.bytes_read += len;
+                progress.bytes_written += bytes_written;
+                throttle.consume(len + bytes_written);
+            }
+            progress.log_files_compacted += 1;
+            progress.elapsed = started_at.elapsed();
+            *self.compaction_progress.lock() = Some(progress);
+        }
+        let compacted_log_ids_files = &compaction.log_ids_files[..progress.log_files_compacted];
+
+        // The live entries moved must reach the disk before their old copies are deleted
+        let output_ids = output.finish()?;
+        if !output_ids.is_empty() && self.options.durability != Durability::Buffered {
+            File::open(self.log_dir_path.as_path())?.sync_all()?;
+        }
+
+        {
+            let writer_ctrl = &mut self.writer_ctrl.lock();
+            for log_id in output_ids {
+                writer_ctrl.add_live_bytes(log_id, 0);
+            }
+            for (log_id, _, len) in kept_removals {
+                writer_ctrl.add_live_bytes(log_id, len);
+            }
+            for (key, log_id, offset, cmd_index) in moved_entries {
+                if self.is_indexed_at(&key, log_id, offset) {
+                    // The moved value is still hot, so it stays cached at its new location
+                    if let Some(value_cache) = self.value_cache.as_ref() {
+                        value_cache
+                            .relocate((log_id, offset), (cmd_index.log_id, cmd_index.offset));
                     }
+                    self.index_entry(key, cmd_index, writer_ctrl);
+                }
+            }
+            for (key, log_id, offset) in expired_entries {
+                if self.is_indexed_at(&key, log_id, offset) {
+                    self.unindex_entry(&key, writer_ctrl);
                 }
-                writer_ctrl.sub_total_cmd_counter(log_file_cmd_counter);
             }
-            self.last_collected_file_index
-                .store(log_id as i64, Ordering::SeqCst);
-            index_guard.defer(move || fs::remove_file(log_path.as_path()).unwrap());
+            for (log_id, _) in compacted_log_ids_files {
+                writer_ctrl.segments.remove(log_id);
+            }
+        }
+
+        self.retire_log_files(
+            compacted_log_ids_files
+                .iter()
+                .map(|(log_id, _)| *log_id)
+                .collect(),
+            // Pinned only now, so that the throttled reads above do not hold back the deletions of other log files
+            &self.pin(),
+        );
+
+        progress.elapsed = started_at.elapsed();
+        Ok(progress)
+    }
+
+    /// Deletes the compacted log files once no snapshot taken before their compaction is alive
+    fn retire_log_files(&self, log_ids: Vec<u64>, index_guard: &'_ Guard) {
+        {
+            let snapshots = &mut self.snapshots.lock();
+            if !snapshots.live_ids.is_empty() {
+                let next_id = snapshots.next_id;
+                snapshots
+                    .retired_log_ids
+                    .extend(log_ids.into_iter().map(|log_id| (next_id, log_id)));
+                return;
+            }
+        }
+        self.remove_log_files(log_ids, index_guard);
+    }
+
+    /// Deletes the log files and their hint files once the readers that may still point at them are gone
+    fn remove_log_files(&self, log_ids: Vec<u64>, index_guard: &'_ Guard) {
+        for log_id in log_ids {
+            let log_path = KvStore::format_log_path(self.log_dir_path.as_path(), log_id);
+            let hint_path = KvStore::format_hint_path(self.log_dir_path.as_path(), log_id);
+            let log_readers = self.log_readers.clone();
+            // The deferred removal may only run once the store directory is gone, and not every log file
+            // has a hint file, so errors are ignored. The hint file goes first, so that a hint file without
+            // its log file is never left by a crash, and tells of a lost log file.
+            index_guard.defer(move || {
+                log_readers.remove(log_id);
+                let _ = fs::remove_file(hint_path.as_path());
+                let _ = fs::remove_file(log_path.as_path());
+            });
+        }
+        index_guard.flush();
+    }
+
+    /// Copies the storage index while holding the writer lock, so that no write is half-applied in the copy,
+    /// and registers the snapshot so that the log files it points at are not deleted
+    fn _snapshot(&self) -> Result<KvStoreSnapshot> {
+        let _writer_ctrl = self.writer_ctrl.lock();
+        let id = {
+            let snapshots = &mut self.snapshots.lock();
+            let id = snapshots.next_id;
+            snapshots.next_id += 1;
+            snapshots.live_ids.insert(id);
+            id
+        };
+        let now = now_millis();
+        let index = self
+            .storage_index
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().load()))
+            .filter(|(_, ci)| !ci.is_expired(now))
+            .collect();
+        Ok(KvStoreSnapshot(Arc::new(SnapshotData {
+            id,
+            store: self.clone(),
+            index,
+        })))
+    }
+
+    /// Unregisters the snapshot, deleting the log files that were only kept for it
+    fn release_snapshot(&self, id: u64) {
+        let log_ids = {
+            let snapshots = &mut self.snapshots.lock();
+            snapshots.live_ids.remove(&id);
+            let oldest_live_id = snapshots
+                .live_ids
+                .iter()
+                .next()
+                .copied()
+                .unwrap_or(u64::MAX);
+            let (released, retired) = snapshots
+                .retired_log_ids
+                .drain(..)
+                .partition::<Vec<_>, _>(|(next_id, _)| *next_id <= oldest_live_id);
+            snapshots.retired_log_ids = retired;
+            released
+                .into_iter()
+                .map(|(_, log_id)| log_id)
+                .collect::<Vec<_>>()
+        };
+        if !log_ids.is_empty() {
+            self.remove_log_files(log_ids, &self.pin());
+        }
+    }
+
+    /// Seals the current log, so that every write done so far is found in a sealed log file, and links the sealed
+    /// log files and their hint files into `dest_dir`. The sealed log files are never written again, and holding
+    /// the compaction lock keeps them from being deleted until they are linked.
+    /// The checkpoint gets a current log of its own, so that the store opened from it never appends to a file
+    /// shared with this one.
+    fn _checkpoint(&self, dest_dir: &Path) -> Result<CheckpointManifest> {
+        create_checkpoint_dir(dest_dir)?;
+        let _compaction_guard = self.compaction_lock.lock();
+        let (sealed_log_ids, next_log_id) = {
+            let writer_ctrl = &mut self.writer_ctrl.lock();
+            // The sealed log is only synced by the seal with some durability modes, and the checkpoint must be durable
+            writer_ctrl.curr_log_mut().sync()?;
+            let next_log_id = writer_ctrl.curr_log_mut().id() + 1;
+            self.seal_curr_log(next_log_id, writer_ctrl)?;
+            let sealed_log_ids = writer_ctrl
+                .segments
+                .range(..next_log_id)
+                .map(|(log_id, _)| *log_id)
+                .collect::<Vec<_>>();
+            (sealed_log_ids, next_log_id)
+        };
+        for log_id in sealed_log_ids {
+            link_or_copy(
+                KvStore::format_log_path(self.log_dir_path.as_path(), log_id).as_path(),
+                KvStore::format_log_path(dest_dir, log_id).as_path(),
+            )?;
+            let hint_path = KvStore::format_hint_path(self.log_dir_path.as_path(), log_id);
+            if hint_path.exists() {
+                link_or_copy(
+                    hint_path.as_path(),
+                    KvStore::format_hint_path(dest_dir, log_id).as_path(),
+                )?;
+            }
         }
+        LogFileWriter::open(
+            next_log_id,
+            KvStore::format_log_path(dest_dir, next_log_id),
+            0,
+        )?
+        .sync()?;
+        write_checkpoint_manifest(dest_dir, "kvs")
+    }
+
+    /// Pins the current thread to the epochs of the store until the guard is dropped
+    fn pin(&self) -> Guard {
+        self.collector.register().pin()
+    }
+
+    /// Tells if the `key` still points at the command found at `offset` in the log file `log_id`
+    fn is_indexed_at(&self, key: &[u8], log_id: u64, offset: u64) -> bool {
+        self.storage_index.get(key).is_some_and(|entry| {
+            let ci = entry.value().load();
+            ci.log_id == log_id && ci.offset == offset
+        })
+    }
 
+    /// Writes the hint files of the sealed log files that do not have one yet,
+    /// so that the next time the store is opened they do not need to be scanned in full.
+    /// Must be called with the compaction lock held, so that the log files are not deleted in the meantime.
+    fn write_missing_hint_files(&self) -> Result<()> {
+        let sealed_log_ids = {
+            let writer_ctrl = &mut self.writer_ctrl.lock();
+            let curr_log_id = writer_ctrl.curr_log_mut().id();
+            writer_ctrl
+                .segments
+                .range(..curr_log_id)
+                .map(|(log_id, _)| *log_id)
+                .collect::<Vec<_>>()
+        };
+        for log_id in sealed_log_ids {
+            let log_path = KvStore::format_log_path(self.log_dir_path.as_path(), log_id);
+            let hint_path = KvStore::format_hint_path(self.log_dir_path.as_path(), log_id);
+            if hint_path.exists() {
+                continue;
+            }
+            let hint = scan_log_file(log_id, log_path.as_path(), TornTail::Reject)?;
+            write_hint_file(log_path.as_path(), hint_path.as_path(), &hint)?;
+        }
         Ok(())
     }
 
@@ -342,121 +1995,209 @@ impl KvStore {
     ) -> Result<u64> {
         let curr_log = writer_ctrl.curr_log_mut();
         let nbytes = curr_log.append_cmd(cmd)?;
-        writer_ctrl.inc_total_cmd_counter();
         Ok(nbytes)
     }
 
     /// Given a file name and an offset, access that position in the log file and returns the value if found.
-    fn read_value_from_log_at(
-        &self,
-        log_id: u64,
-        offset: u64,
-        len: u64,
-        guard: &'_ Guard,
-    ) -> Result<String> {
-        let curr_log_r = unsafe { self.curr_log_r.load(Ordering::SeqCst, guard).deref() };
-        if log_id != curr_log_r.id() {
-            let file_path = KvStore::format_log_path(self.log_dir_path.as_path(), log_id);
-            self.curr_log_r.store(
-                Owned::new(LogFileReader::open(log_id, file_path)?),
-                Ordering::SeqCst,
-            );
+    /// The caller must have been pinned to the epochs of the store since it read the location of the value from the
+    /// storage index, so that a compaction does not delete the log file in the meantime.
+    fn read_value_from_log_at(&self, log_id: u64, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if let Some(value) = self
+            .value_cache
+            .as_ref()
+            .and_then(|value_cache| value_cache.get((log_id, offset)))
+        {
+            return Ok(value);
         }
-        let cmd = curr_log_r.read_cmd_at(offset, len)?;
-        if let Command::Set { key: _, value } = cmd {
+        let cmd = self.log_readers.get(log_id)?.read_cmd_at(offset, len)?;
+        if let Command::Set { value, .. } = cmd {
+            if let Some(value_cache) = self.value_cache.as_ref() {
+                value_cache.insert((log_id, offset), value.clone());
+            }
             Ok(value)
         } else {
             Err(KvStoreError::WrongFileOffset)
         }
     }
 
+    /// Frees the space taken in the value cache by the value of a command no longer pointed at by the storage index
+    fn uncache_value(&self, cmd_index: &CommandIndex) {
+        if let Some(value_cache) = self.value_cache.as_ref() {
+            value_cache.remove((cmd_index.log_id, cmd_index.offset));
+        }
+    }
+
     /// Given a directory path, finds and reads all the log files and returns the storage index,
-    /// the total number of commands written in the log files and a vec with information to be used
-    /// later by the compaction algorithm about each log file.
-    fn build_index<P>(dir_path: P) -> Result<(StdHashMap<String, CommandIndex>, u64, u64, u64)>
+    /// the live bytes of each log file, the id and format version of the last log file
+    /// and the number of commands written in it.
+    /// The sealed log files are read from their hint files, and only the ones whose hint file is missing or stale
+    /// are scanned in full.
+    /// Keys that have already expired are left out of the storage index.
+    /// A torn record at the end of the last log file is handled as told by `last_torn_tail`, while one at the end
+    /// of a sealed log file is always rejected.
+    fn build_index<P>(dir_path: P, last_torn_tail: TornTail) -> Result<BuiltIndex>
     where
         P: AsRef<Path>,
     {
         let mut storage_index = StdHashMap::new();
-        let mut total_cmds_counter = 0;
+        let mut segments = BTreeMap::new();
         let mut curr_log_id = 0;
+        let mut curr_log_version = LOG_FORMAT_VERSION;
         let mut cmd_counter = 0;
+        let now = now_millis();
 
-        for (log_id, log_file_path) in KvStore::list_log_ids_files_sorted(dir_path.as_ref()) {
+        let log_ids_files =
+            KvStore::list_log_ids_files_sorted(dir_path.as_ref()).collect::<Vec<_>>();
+        let last_log_id = log_ids_files.last().map(|(log_id, _)| *log_id);
+        for (log_id, log_file_path) in log_ids_files {
+            let hint_file_path = KvStore::format_hint_path(dir_path.as_ref(), log_id);
+            let torn_tail = if Some(log_id) == last_log_id {
+                last_torn_tail
+            } else {
+                TornTail::Reject
+            };
+            let hint =
+                match read_hint_file(log_id, log_file_path.as_path(), hint_file_path.as_path()) {
+                    Some(hint) => hint,
+                    None => scan_log_file(log_id, log_file_path.as_path(), torn_tail)?,
+                };
             curr_log_id = log_id;
-            cmd_counter = 0;
-            let mut log_file = OpenOptions::new()
-                .read(true)
-                .open(log_file_path.as_path())?;
-            let mut reader = BufReader::new(&mut log_file);
-
-            loop {
-                let offset = reader.seek(SeekFrom::Current(0))?;
-                let res = bincode::deserialize_from::<_, Command>(&mut reader);
-                match res {
-                    Ok(Command::Set { key, value }) => {
-                        total_cmds_counter += 1;
-                        cmd_counter += 1;
-                        let len = bincode::serialized_size(&Command::Set {
-                            key: key.clone(),
-                            value,
-                        })?;
-                        storage_index.insert(
-                            key,
-                            CommandIndex {
-                                log_id,
-                                offset,
-                                len,
-                            },
-                        );
+            curr_log_version = hint.log_version;
+            cmd_counter = hint.entries.len() as u64;
+            segments.insert(log_id, 0);
+            for entry in hint.entries {
+                match entry {
+                    HintEntry::Set {
+                        key,
+                        offset,
+                        len,
+                        expires_at,
+                    } => {
+                        let cmd_index = CommandIndex {
+                            log_id,
+                            offset,
+                            len,
+                            expires_at,
+                        };
+                        if cmd_index.is_expired(now) {
+                            storage_index.remove(&*key);
+                        } else {
+                            storage_index.insert(key, cmd_index);
+                        }
                     }
-                    Ok(Command::Remove { key }) => {
-                        total_cmds_counter += 1;
-                        cmd_counter += 1;
+                    HintEntry::Remove { key } => {
                         storage_index.remove(&*key);
                     }
-                    Err(err) => match *err {
-                        bincode::ErrorKind::Io(ref bincode_io_err) => match bincode_io_err.kind() {
-                            std::io::ErrorKind::UnexpectedEof => break,
-                            _ => return Err(KvStoreError::from(err)),
-                        },
-                        _ => return Err(KvStoreError::from(err)),
-                    },
                 }
             }
         }
-        Ok((storage_index, total_cmds_counter, curr_log_id, cmd_counter))
+        for cmd_index in storage_index.values() {
+            *segments.entry(cmd_index.log_id).or_insert(0) += cmd_index.len;
+        }
+        Ok(BuiltIndex {
+            storage_index,
+            segments,
+            last_log_id: curr_log_id,
+            last_log_version: curr_log_version,
+            cmd_counter,
+        })
     }
 
-    fn _set(&self, key: String, value: String) -> Result<()> {
-        let writer_ctrl = &mut self.writer_ctrl.lock();
-        self.insert_entry(key, value, writer_ctrl)?;
-
-        Ok(())
+    fn _set(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let mut writer_ctrl = self.writer_ctrl.lock();
+        self.insert_entry(key, value, expires_at, &mut writer_ctrl)?;
+        self.commit(writer_ctrl)
     }
 
-    fn _get(&self, key: String) -> Result<Option<String>> {
-        let index_guard = &self.storage_index.guard();
-        match self.storage_index.get(&key, index_guard).cloned() {
-            Some(ci) => Ok(Some(self.read_value_from_log_at(
-                ci.log_id,
-                ci.offset,
-                ci.len,
-                index_guard,
-            )?)),
-            None => Ok(None),
+    fn _get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let _index_guard = self.pin();
+        match self
+            .storage_index
+            .get(&key)
+            .map(|entry| entry.value().load())
+        {
+            Some(ci) if !ci.is_expired(now_millis()) => Ok(Some(
+                self.read_value_from_log_at(ci.log_id, ci.offset, ci.len)?,
+            )),
+            _ => Ok(None),
         }
     }
 
-    fn _remove(&self, key: String) -> Result<()> {
-        let curr_low_w = &mut self.writer_ctrl.lock();
-        let index_guard = &self.storage_index.guard();
-        if let None = self.storage_index.remove(&key, index_guard) {
+    fn _remove(&self, key: Vec<u8>) -> Result<()> {
+        let mut curr_low_w = self.writer_ctrl.lock();
+        let expired = self
+            .storage_index
+            .get(&key)
+            .is_some_and(|entry| entry.value().load().is_expired(now_millis()));
+        if !self.unindex_entry(&key, &mut curr_low_w) || expired {
             Err(KvStoreError::RemoveNonExistentKey)
         } else {
-            self.write_cmd_to_curr_log(Command::Remove { key }, curr_low_w)?;
-            Ok(())
+            self.write_cmd_to_curr_log(Command::Remove { key }, &mut curr_low_w)?;
+            self.commit(curr_low_w)
+        }
+    }
+
+    fn _write_batch(&self, ops: Vec<Op>) -> Result<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let cmds = ops
+            .into_iter()
+            .map(|op| match op {
+                Op::Set { key, value } => Command::Set {
+                    key,
+                    value,
+                    expires_at: None,
+                },
+                Op::Remove { key } => Command::Remove { key },
+            })
+            .collect::<Vec<_>>();
+        let mut writer_ctrl = self.writer_ctrl.lock();
+        let curr_log = writer_ctrl.curr_log_mut();
+        let log_id = curr_log.id();
+        let locations = curr_log.append_batch(&cmds)?;
+        for (cmd, (offset, len)) in cmds.into_iter().zip(locations) {
+            match cmd {
+                Command::Set { key, .. } => self.index_entry(
+                    key,
+                    CommandIndex {
+                        log_id,
+                        offset,
+                        len,
+                        expires_at: None,
+                    },
+                    &mut writer_ctrl,
+                ),
+                Command::Remove { key } => {
+                    self.unindex_entry(&key, &mut writer_ctrl);
+                }
+                Command::Batch { .. } => unreachable!(),
+            }
+        }
+        self.commit(writer_ctrl)
+    }
+
+    fn _compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        // Holding the writer lock keeps the value from changing between the comparison and the swap
+        let mut writer_ctrl = self.writer_ctrl.lock();
+        let current = self._get(key.clone())?;
+        if current != expected {
+            return Err(KvStoreError::CompareAndSwapFailed { current });
+        }
+        match new {
+            Some(value) => self.insert_entry(key, value, None, &mut writer_ctrl)?,
+            None if current.is_some() => {
+                self.unindex_entry(&key, &mut writer_ctrl);
+                self.write_cmd_to_curr_log(Command::Remove { key }, &mut writer_ctrl)?;
+            }
+            None => return Ok(()),
         }
+        self.commit(writer_ctrl)
     }
 
     fn format_log_path<P: Into<PathBuf>>(log_dir: P, log_id: u64) -> PathBuf {
@@ -466,6 +2207,13 @@ impl KvStore {
         ))
     }
 
+    fn format_hint_path<P: Into<PathBuf>>(log_dir: P, log_id: u64) -> PathBuf {
+        log_dir.into().join(format!(
+            "{}{:012}{}",
+            LOG_FILE_PREFIX, log_id, HINT_FILE_SUFFIX
+        ))
+    }
+
     fn list_log_ids_files_sorted<P: Into<PathBuf>>(
         log_dir: P,
     ) -> impl Iterator<Item = (u64, PathBuf)> {
@@ -498,12 +2246,10 @@ impl KvStore {
             })
     }
 
-    fn get_active_file_id_offsets_map(
-        &self,
-        index_guard: &'_ Guard,
-    ) -> StdHashMap<u64, StdHashSet<u64>> {
+    fn get_active_file_id_offsets_map(&self) -> StdHashMap<u64, StdHashSet<u64>> {
         self.storage_index
-            .values(index_guard)
+            .iter()
+            .map(|entry| entry.value().load())
             .sorted_by(|i1, i2| Ord::cmp(&i1.log_id, &i2.log_id))
             .group_by(|i1| i1.log_id)
             .into_iter()
@@ -513,8 +2259,9 @@ impl KvStore {
 
     fn insert_entry<'g>(
         &self,
-        key: String,
-        value: String,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
         writer_ctrl: &'_ mut MutexGuard<'g, WriterControlData>,
     ) -> Result<()> {
         let curr_log = writer_ctrl.curr_log_mut();
@@ -523,51 +2270,285 @@ impl KvStore {
         let cmd = Command::Set {
             key: key.clone(),
             value,
+            expires_at,
         };
         let len = self.write_cmd_to_curr_log(cmd, writer_ctrl)?;
-        let index_guard = &self.storage_index.guard();
-        self.storage_index.insert(
+        self.index_entry(
             key,
             CommandIndex {
                 log_id,
                 offset,
                 len,
+                expires_at,
             },
-            index_guard,
+            writer_ctrl,
         );
         Ok(())
     }
 
-    fn _compact(&self) -> Result<()> {
+    /// Removes every expired key from the storage index. Nothing is written to the logs, since an expired
+    /// set command is ignored when the index is rebuilt, and a compaction writes a removal in its place
+    /// while a log file left out of it may still hold an older copy of the key.
+    fn _evict_expired(&self) -> Result<()> {
         let writer_ctrl = &mut self.writer_ctrl.lock();
-        if self.should_create_new_file(writer_ctrl) {
-            self.do_create_new_file(writer_ctrl)?;
+        let now = now_millis();
+        let expired = self
+            .storage_index
+            .iter()
+            .filter(|entry| entry.value().load().is_expired(now))
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        for key in expired {
+            self.unindex_entry(&key, writer_ctrl);
         }
+        Ok(())
+    }
 
-        if self.should_run_compaction(writer_ctrl) {
-            self.do_compaction(writer_ctrl)?;
+    /// Points the `key` at the set command written to the logs at `cmd_index`,
+    /// moving the bytes of the command it pointed at before from the live to the dead ones.
+    /// The storage index is only written with the writer lock held, so the old command can not change in the meantime.
+    fn index_entry<'g>(
+        &self,
+        key: Vec<u8>,
+        cmd_index: CommandIndex,
+        writer_ctrl: &'_ mut MutexGuard<'g, WriterControlData>,
+    ) {
+        writer_ctrl.add_live_bytes(cmd_index.log_id, cmd_index.len);
+        match self.storage_index.get(&key) {
+            Some(entry) => {
+                let old_cmd_index = entry.value().swap(cmd_index);
+                writer_ctrl.sub_live_bytes(old_cmd_index.log_id, old_cmd_index.len);
+                self.uncache_value(&old_cmd_index);
+            }
+            None => {
+                self.storage_index.insert(key, AtomicCell::new(cmd_index));
+            }
         }
+    }
 
-        Ok(())
+    /// Removes the `key` from the storage index, moving the bytes of the command it pointed at
+    /// from the live to the dead ones. Returns false if the key was not found.
+    fn unindex_entry<'g>(
+        &self,
+        key: &[u8],
+        writer_ctrl: &'_ mut MutexGuard<'g, WriterControlData>,
+    ) -> bool {
+        match self.storage_index.remove(key) {
+            Some(entry) => {
+                let old_cmd_index = entry.value().load();
+                writer_ctrl.sub_live_bytes(old_cmd_index.log_id, old_cmd_index.len);
+                self.uncache_value(&old_cmd_index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn _scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsBytesIterator> {
+        if is_empty_range(&range) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        Ok(self.iter_range(range.start_bound().cloned(), range.end_bound().cloned()))
+    }
+
+    fn _scan_prefix(&self, prefix: Vec<u8>) -> Result<KvsBytesIterator> {
+        let end = prefix_end(&prefix);
+        Ok(self.iter_range(Bound::Included(prefix), end))
+    }
+
+    /// Returns an iterator that walks the storage index lazily from `start` to `end`, reading each value
+    /// once it is reached. Every step looks up the first key after the last one returned, so no key is copied
+    /// up front, and the keys written or removed in the meantime are seen as long as they are not behind it.
+    fn iter_range(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> KvsBytesIterator {
+        let store = self.clone();
+        let mut start = start;
+        Box::new(std::iter::from_fn(move || loop {
+            let _index_guard = store.pin();
+            let (key, ci) = {
+                let entry = store
+                    .storage_index
+                    .range::<Vec<u8>, _>((start.as_ref(), end.as_ref()))
+                    .next()?;
+                (entry.key().clone(), entry.value().load())
+            };
+            start = Bound::Excluded(key.clone());
+            if ci.is_expired(now_millis()) {
+                continue;
+            }
+            return Some(
+                store
+                    .read_value_from_log_at(ci.log_id, ci.offset, ci.len)
+                    .map(|value| (key, value)),
+            );
+        }))
+    }
+
+    fn _compaction_needed(&self) -> Result<CompactionEstimate> {
+        let writer_ctrl = &mut self.writer_ctrl.lock();
+        // A full current log file is sealed by the compaction before it picks the log files to compact
+        let end_log_id = if self.should_create_new_file(writer_ctrl) {
+            writer_ctrl.curr_log_mut().id() + 1
+        } else {
+            writer_ctrl.curr_log_mut().id()
+        };
+        let segments = self.segment_stats(writer_ctrl, end_log_id)?;
+        let selected = self.select_segments(&segments);
+        Ok(CompactionEstimate {
+            log_files: selected.len(),
+            reclaimable_bytes: selected.iter().map(|segment| segment.dead_bytes).sum(),
+            bytes_to_rewrite: selected.iter().map(|segment| segment.live_bytes).sum(),
+        })
+    }
+
+    fn _compact(&self, limits: &CompactionLimits) -> Result<CompactionProgress> {
+        // A single compaction runs at a time, and the writer lock is only held to start and to finish it
+        let _compaction_guard = match self.compaction_lock.try_lock() {
+            Some(guard) => guard,
+            None => return Ok(CompactionProgress::default()),
+        };
+        let started_at = Instant::now();
+        let compaction = {
+            let writer_ctrl = &mut self.writer_ctrl.lock();
+            if self.should_create_new_file(writer_ctrl) {
+                self.do_create_new_file(writer_ctrl)?;
+            }
+
+            self.start_compaction(writer_ctrl, limits)?
+        };
+        let progress = match compaction {
+            Some(compaction) => {
+                let progress = self.do_compaction(compaction, limits, started_at);
+                *self.compaction_progress.lock() = None;
+                progress?
+            }
+            None => CompactionProgress::default(),
+        };
+
+        self.write_missing_hint_files()?;
+        Ok(progress)
     }
 }
 
 impl KvsEngine for KvStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self._set(key, value)
+    type Snapshot = KvStoreSnapshot;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self._set(key, value, None)
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self._set(key, value, Some(expires_at(ttl)))
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         self._get(key)
     }
 
-    fn remove(&self, key: String) -> Result<()> {
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
         self._remove(key)
     }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsBytesIterator> {
+        self._scan(range)
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<KvsBytesIterator> {
+        self._scan_prefix(prefix)
+    }
+
+    fn write_batch(&self, ops: Vec<Op>) -> Result<()> {
+        self._write_batch(ops)
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        self._compare_and_swap(key, expected, new)
+    }
+
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        self._snapshot()
+    }
+
+    fn checkpoint<P: AsRef<Path>>(&self, dest_dir: P) -> Result<CheckpointManifest> {
+        self._checkpoint(dest_dir.as_ref())
+    }
+}
+
+impl KvStoreSnapshot {
+    /// Returns an iterator that reads the values of the `entries` lazily, keeping the snapshot alive until it is dropped
+    fn iter_entries(&self, entries: Vec<(Vec<u8>, CommandIndex)>) -> KvsBytesIterator {
+        let snapshot = self.clone();
+        Box::new(entries.into_iter().map(move |(key, ci)| {
+            let value = snapshot
+                .0
+                .store
+                .read_value_from_log_at(ci.log_id, ci.offset, ci.len)?;
+            Ok((key, value))
+        }))
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.0.index.get(&key) {
+            Some(ci) => Ok(Some(
+                self.0
+                    .store
+                    .read_value_from_log_at(ci.log_id, ci.offset, ci.len)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsBytesIterator> {
+        if is_empty_range(&range) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let entries = self
+            .0
+            .index
+            .range(range)
+            .map(|(key, ci)| (key.clone(), *ci))
+            .collect();
+        Ok(self.iter_entries(entries))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<KvsBytesIterator> {
+        let entries = self
+            .0
+            .index
+            .range::<Vec<u8>, _>((Bound::Included(&prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix.as_slice()))
+            .map(|(key, ci)| (key.clone(), *ci))
+            .collect();
+        Ok(self.iter_entries(entries))
+    }
+}
+
+impl Drop for SnapshotData {
+    fn drop(&mut self) {
+        self.store.release_snapshot(self.id);
+    }
 }
 
 impl KvsCompactor for KvStore {
-    fn compact(&self) -> Result<()> {
-        self._compact()
+    fn compaction_needed(&self) -> Result<CompactionEstimate> {
+        self._compaction_needed()
+    }
+
+    fn compact_with(&self, limits: &CompactionLimits) -> Result<CompactionProgress> {
+        self._compact(limits)
+    }
+
+    fn compaction_progress(&self) -> Option<CompactionProgress> {
+        *self.compaction_progress.lock()
+    }
+
+    fn evict_expired(&self) -> Result<()> {
+        self._evict_expired()
     }
 }
@@ -14,14 +14,28 @@ extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
 
+pub use asynckvclient::*;
+pub use checkpoint::*;
+pub use compaction::*;
+pub use dir_lock::LOCK_FILE;
+pub use dump::*;
+pub use durability::*;
 pub use error::*;
 pub use kvclient::*;
 pub use kvsengine::*;
 pub use kvserver::*;
 pub use kvstore::*;
 pub use sledkvsengine::*;
+pub use value_cache::*;
+pub use verify::*;
 
+mod asynckvclient;
+mod checkpoint;
+mod compaction;
 pub mod cp;
+mod dir_lock;
+mod dump;
+mod durability;
 mod error;
 mod kvclient;
 mod kvsengine;
@@ -29,3 +43,5 @@ mod kvserver;
 mod kvstore;
 mod sledkvsengine;
 pub mod thread_pool;
+mod value_cache;
+mod verify;
@@ -1,18 +1,69 @@
 use crate::KvsCompactor;
 
-use super::{KvStoreError, KvsEngine, Result};
-use itertools::Itertools;
-use sled::{Config, Db};
+use super::{
+    checkpoint::{create_checkpoint_dir, write_checkpoint_manifest},
+    dir_lock::DirLock,
+    kvsengine::{expires_at, is_empty_range, now_millis},
+    CheckpointManifest, DumpIterator, DumpRecord, Durability, KvStoreError, KvsBytesIterator,
+    KvsEngine, KvsSnapshot, Op, Result,
+};
+use parking_lot::{Mutex, RwLock};
+use sled::{
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+        TransactionalTree,
+    },
+    Config, Db, IVec, Transactional, Tree,
+};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+/// Name of the sled tree that maps the keys set with a ttl to their expiry timestamps
+const EXPIRATIONS_TREE: &str = "expirations";
 
 /// Encaspulates the sled database engine
 #[derive(Debug, Clone)]
 pub struct SledKvsEngine {
     db: Db,
+    expirations: Tree,
+    durability: Durability,
+    /// Held shared by the transactions and exclusively while a snapshot is taken
+    snapshot_lock: Arc<RwLock<()>>,
+    /// The snapshots taken, to which the transactions save the previous state of the keys they change.
+    /// Held by the transactions while they change the keys and save their previous state, as long as a snapshot is alive.
+    snapshots: Arc<Mutex<Vec<Weak<SnapshotState>>>>,
+}
+
+/// A read-only view of a `SledKvsEngine` at the point in time it was taken, as returned by `KvsEngine::snapshot`.
+/// Sled has no snapshots of its own, so the snapshot reads the database as it is, and the writes done since it was
+/// taken save in it the value and expiry the keys they change had before.
+#[derive(Debug, Clone)]
+pub struct SledKvsSnapshot(Arc<SnapshotState>);
+
+/// The value of a key along with its expiry timestamp, as stored in the data and the expirations trees
+type SledPair = (IVec, Option<IVec>);
+
+#[derive(Debug)]
+struct SnapshotState {
+    db: Db,
+    expirations: Tree,
+    /// The snapshots of the engine, locked to wait for the transactions changing the keys
+    snapshots: Arc<Mutex<Vec<Weak<SnapshotState>>>>,
+    /// When the snapshot was taken, the keys expired by then being not seen by it
+    taken_at: u64,
+    /// The state of every key changed since the snapshot was taken, as it was before its first change:
+    /// its value and expiry, or `None` if it did not exist
+    previous: Mutex<BTreeMap<Vec<u8>, Option<SledPair>>>,
 }
 
 impl SledKvsEngine {
     /// Open the SledKvsEngine at a given `path`.
     /// Return the SledKvsEngine.
+    /// Every write is flushed to the disk before returning (`Durability::SyncEachWrite`).
     ///
     /// # Examples
     ///
@@ -21,41 +72,438 @@ impl SledKvsEngine {
     /// let dictionary = SledKvsEngine::open("./").unwrap();
     /// ```
     pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<SledKvsEngine> {
-        let db = Config::new()
-            .path(path)
-            .open()
-            .map_err(KvStoreError::from)?;
-        Ok(SledKvsEngine { db })
+        SledKvsEngine::open_with_durability(path, Durability::SyncEachWrite)
+    }
+
+    /// Open the SledKvsEngine at a given `path`, flushing the writes to the disk according to the `durability` mode.
+    /// Return the SledKvsEngine.
+    ///
+    /// Sled already coalesces concurrent flushes into a single one, so `SyncEachWrite` and `GroupCommit` behave
+    /// the same. `Buffered` leaves the writes to the periodic flush of sled.
+    ///
+    /// Return `KvStoreError::DirectoryLocked` if the directory is locked by an open `KvStore`.
+    /// Sled locks its own files, so two engines are never open at once either.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::{Durability, SledKvsEngine};
+    /// let dictionary = SledKvsEngine::open_with_durability("./", Durability::Buffered).unwrap();
+    /// ```
+    pub fn open_with_durability<P: AsRef<std::path::Path>>(
+        path: P,
+        durability: Durability,
+    ) -> Result<SledKvsEngine> {
+        DirLock::check(path.as_ref())?;
+        let mut config = Config::new().path(path);
+        if let Durability::Interval(interval) = durability {
+            config = config.flush_every_ms(Some(interval.as_millis() as u64));
+        }
+        let db = config.open().map_err(KvStoreError::from)?;
+        let expirations = db.open_tree(EXPIRATIONS_TREE)?;
+        Ok(SledKvsEngine {
+            db,
+            expirations,
+            durability,
+            snapshot_lock: Arc::new(RwLock::new(())),
+            snapshots: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    /// Iterate over all the live key-value pairs of the engine, along with their expiry times,
+    /// in ascending order of keys. The pairs are read as the scans do, so they may reflect the writes done meanwhile.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::SledKvsEngine;
+    /// let dictionary = SledKvsEngine::open("./").unwrap();
+    /// for record in dictionary.dump().unwrap() {
+    ///     println!("{}", serde_json::to_string(&record.unwrap()).unwrap());
+    /// }
+    /// ```
+    pub fn dump(&self) -> Result<DumpIterator> {
+        let expirations = self.expirations.clone();
+        let now = now_millis();
+        Ok(Box::new(self.db.iter().filter_map(move |r| {
+            let record = r.map_err(KvStoreError::from).and_then(|(key, value)| {
+                let expires_at = expirations
+                    .get(&key)?
+                    .and_then(|expiry| expiry.as_ref().try_into().ok())
+                    .map(u64::from_be_bytes);
+                Ok(DumpRecord {
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                    expires_at,
+                })
+            });
+            match record {
+                Ok(record)
+                    if record
+                        .expires_at
+                        .is_some_and(|expires_at| expires_at <= now) =>
+                {
+                    None
+                }
+                record => Some(record),
+            }
+        })))
+    }
+
+    /// Converts an iterator over the key-value pairs of sled into an iterator over owned byte vectors,
+    /// skipping the expired keys
+    fn iter_bytes(&self, iter: sled::Iter) -> KvsBytesIterator {
+        let expirations = self.expirations.clone();
+        let now = now_millis();
+        Box::new(iter.filter_map(move |r| {
+            let (key, value) = match r {
+                Ok(pair) => pair,
+                Err(err) => return Some(Err(err.into())),
+            };
+            match expirations.get(&key).map(|expiry| is_expired(expiry, now)) {
+                Ok(true) => None,
+                Ok(false) => Some(Ok((key.to_vec(), value.to_vec()))),
+                Err(err) => Some(Err(err.into())),
+            }
+        }))
+    }
+
+    /// Runs `f`, which changes the `keys`, in a transaction over the data and the expirations trees.
+    /// The previous state of the `keys` is saved to the snapshots alive.
+    fn transaction<T, F>(&self, keys: &[&[u8]], f: F) -> Result<T>
+    where
+        F: Fn(
+            &TransactionalTree,
+            &TransactionalTree,
+        ) -> ConflictableTransactionResult<T, KvStoreError>,
+    {
+        let _snapshot_lock = self.snapshot_lock.read();
+        let mut snapshots = self.snapshots.lock();
+        snapshots.retain(|snapshot| snapshot.strong_count() > 0);
+        let alive = snapshots
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<_>>();
+        // While a snapshot is alive, the transactions are run one at a time, so that the previous state of a key
+        // is saved by the first one changing it
+        let _snapshots = if alive.is_empty() {
+            drop(snapshots);
+            None
+        } else {
+            Some(snapshots)
+        };
+        let (res, previous) = (&*self.db, &self.expirations)
+            .transaction(|(db, expirations)| {
+                let mut previous = Vec::new();
+                if !alive.is_empty() {
+                    for key in keys {
+                        let pair = match db.get(key)? {
+                            Some(value) => Some((value, expirations.get(key)?)),
+                            None => None,
+                        };
+                        previous.push((key.to_vec(), pair));
+                    }
+                }
+                Ok((f(db, expirations)?, previous))
+            })
+            .map_err(|err| match err {
+                TransactionError::Abort(err) => err,
+                TransactionError::Storage(err) => err.into(),
+            })?;
+        for snapshot in alive {
+            let mut snapshot_previous = snapshot.previous.lock();
+            for (key, pair) in previous.iter() {
+                snapshot_previous
+                    .entry(key.clone())
+                    .or_insert_with(|| pair.clone());
+            }
+        }
+        Ok(res)
+    }
+
+    /// Sets the `value` of the `key`, expiring at `expires_at` if it is not `None`
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        self.transaction(&[key.as_slice()], |db, expirations| {
+            db.insert(key.as_slice(), value.as_slice())?;
+            match expires_at {
+                Some(expires_at) => {
+                    expirations.insert(key.as_slice(), &expires_at.to_be_bytes())?;
+                }
+                None => {
+                    expirations.remove(key.as_slice())?;
+                }
+            }
+            Ok(())
+        })?;
+        self.commit()
+    }
+
+    fn commit(&self) -> Result<()> {
+        if self.durability.syncs_on_write() {
+            self.db.flush()?;
+        }
+        Ok(())
     }
 }
 
-impl KvsCompactor for SledKvsEngine {}
+/// Tells if an expiry timestamp read from the expirations tree is at or before `now`
+fn is_expired(expiry: Option<IVec>, now: u64) -> bool {
+    expiry
+        .and_then(|expiry| expiry.as_ref().try_into().ok())
+        .is_some_and(|expiry| u64::from_be_bytes(expiry) <= now)
+}
+
+impl KvsCompactor for SledKvsEngine {
+    fn evict_expired(&self) -> Result<()> {
+        let now = now_millis();
+        for r in self.expirations.iter() {
+            let (key, expiry) = r?;
+            if !is_expired(Some(expiry), now) {
+                continue;
+            }
+            // The key may have been set again since it was read, so the expiry is checked inside the transaction
+            self.transaction(&[key.as_ref()], |db, expirations| {
+                if is_expired(expirations.get(&key)?, now) {
+                    db.remove(&key)?;
+                    expirations.remove(&key)?;
+                }
+                Ok(())
+            })?;
+        }
+        self.commit()
+    }
+}
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.db
-            .insert(key.as_bytes(), value.as_bytes())
-            .map(|_| ())
-            .map_err(KvStoreError::from)?;
-        self.db.flush()?;
-        Ok(())
+    type Snapshot = SledKvsSnapshot;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.insert(key, value, None)
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        self.db.get(key).map_err(KvStoreError::from).map(|v| {
-            v.and_then(|iv| String::from_utf8(iv.iter().map(Clone::clone).collect_vec()).ok())
-        })
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let value = self.db.get(&key).map_err(KvStoreError::from)?;
+        if value.is_some() && is_expired(self.expirations.get(&key)?, now_millis()) {
+            return Ok(None);
+        }
+        Ok(value.map(|iv| iv.to_vec()))
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.db
-            .remove(key)
-            .map_err(KvStoreError::from)
-            .and_then(|v| match v {
-                Some(_) => Ok(()),
-                None => Err(KvStoreError::RemoveNonExistentKey),
-            })?;
-        self.db.flush()?;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let now = now_millis();
+        self.transaction(&[key.as_slice()], |db, expirations| {
+            let expired = is_expired(expirations.remove(key.as_slice())?, now);
+            match db.remove(key.as_slice())? {
+                Some(_) if !expired => Ok(()),
+                _ => Err(KvStoreError::RemoveNonExistentKey),
+            }
+            .map_err(ConflictableTransactionError::Abort)
+        })?;
+        self.commit()
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsBytesIterator> {
+        if is_empty_range(&range) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let to_ivec = |bound: Bound<&Vec<u8>>| match bound {
+            Bound::Included(key) => Bound::Included(IVec::from(key.as_slice())),
+            Bound::Excluded(key) => Bound::Excluded(IVec::from(key.as_slice())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let range = (to_ivec(range.start_bound()), to_ivec(range.end_bound()));
+        Ok(self.iter_bytes(self.db.range(range)))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<KvsBytesIterator> {
+        Ok(self.iter_bytes(self.db.scan_prefix(prefix)))
+    }
+
+    fn write_batch(&self, ops: Vec<Op>) -> Result<()> {
+        let keys = ops
+            .iter()
+            .map(|op| match op {
+                Op::Set { key, .. } | Op::Remove { key } => key.as_slice(),
+            })
+            .collect::<Vec<_>>();
+        self.transaction(&keys, |db, expirations| {
+            for op in &ops {
+                match op {
+                    Op::Set { key, value } => {
+                        db.insert(key.as_slice(), value.as_slice())?;
+                    }
+                    Op::Remove { key } => {
+                        db.remove(key.as_slice())?;
+                    }
+                }
+                match op {
+                    Op::Set { key, .. } | Op::Remove { key } => {
+                        expirations.remove(key.as_slice())?;
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        self.commit()
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let now = now_millis();
+        self.transaction(&[key.as_slice()], |db, expirations| {
+            // An expired key is compared as if it did not exist
+            let current = match db.get(key.as_slice())? {
+                Some(_) if is_expired(expirations.get(key.as_slice())?, now) => None,
+                current => current.map(|iv| iv.to_vec()),
+            };
+            if current != expected {
+                return Err(ConflictableTransactionError::Abort(
+                    KvStoreError::CompareAndSwapFailed { current },
+                ));
+            }
+            match &new {
+                Some(value) => {
+                    db.insert(key.as_slice(), value.as_slice())?;
+                }
+                None => {
+                    db.remove(key.as_slice())?;
+                }
+            }
+            expirations.remove(key.as_slice())?;
+            Ok(())
+        })?;
+        self.commit()
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.insert(key, value, Some(expires_at(ttl)))
+    }
+
+    /// Only holds back the writes while the snapshot is registered, after which they save to it the previous state
+    /// of the keys they change
+    fn snapshot(&self) -> Result<SledKvsSnapshot> {
+        let _snapshot_lock = self.snapshot_lock.write();
+        let state = Arc::new(SnapshotState {
+            db: self.db.clone(),
+            expirations: self.expirations.clone(),
+            snapshots: self.snapshots.clone(),
+            taken_at: now_millis(),
+            previous: Mutex::new(BTreeMap::new()),
+        });
+        self.snapshots.lock().push(Arc::downgrade(&state));
+        Ok(SledKvsSnapshot(state))
+    }
+
+    /// Sled can not link its files like `KvStore` does, so the checkpoint is a new sled database
+    /// holding a copy of all the live keys and their expiry timestamps, written one by one from a snapshot
+    /// while the writes go on
+    fn checkpoint<P: AsRef<Path>>(&self, dest_dir: P) -> Result<CheckpointManifest> {
+        let dest_dir = dest_dir.as_ref();
+        create_checkpoint_dir(dest_dir)?;
+        {
+            let snapshot = self.snapshot()?;
+            let checkpoint = Config::new().path(dest_dir).open()?;
+            let checkpoint_expirations = checkpoint.open_tree(EXPIRATIONS_TREE)?;
+            snapshot.0.write_to(&checkpoint, &checkpoint_expirations)?;
+            checkpoint.flush()?;
+        }
+        write_checkpoint_manifest(dest_dir, "sled")
+    }
+}
+
+impl SnapshotState {
+    /// Waits for the transactions changing keys to be done, so that every key changed since the snapshot was taken
+    /// and read meanwhile has saved its previous state
+    fn wait_for_writes(&self) {
+        drop(self.snapshots.lock());
+    }
+
+    /// Returns the live key-value pairs of the `range` whose keys start with `prefix`, as they were when the
+    /// snapshot was taken
+    fn pairs(
+        &self,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        prefix: &[u8],
+    ) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+        let mut pairs = BTreeMap::new();
+        for r in self.db.range(range.clone()) {
+            let (key, value) = r?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            let expiry = self.expirations.get(&key)?;
+            pairs.insert(key.to_vec(), (value, expiry));
+        }
+        self.wait_for_writes();
+        for (key, pair) in self.previous.lock().range(range) {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            match pair {
+                Some(pair) => pairs.insert(key.clone(), pair.clone()),
+                None => pairs.remove(key),
+            };
+        }
+        Ok(pairs
+            .into_iter()
+            .filter(|(_, (_, expiry))| !is_expired(expiry.clone(), self.taken_at))
+            .map(|(key, (value, _))| (key, value.to_vec()))
+            .collect())
+    }
+
+    /// Writes the live key-value pairs, along with their expiry timestamps, as they were when the snapshot was taken
+    /// to the `db` and `expirations` trees, one by one
+    fn write_to(&self, db: &Db, expirations: &Tree) -> Result<()> {
+        let write = |key: &[u8], pair: Option<SledPair>| -> Result<()> {
+            match pair {
+                Some((value, expiry)) if !is_expired(expiry.clone(), self.taken_at) => {
+                    db.insert(key, value)?;
+                    match expiry {
+                        Some(expiry) => expirations.insert(key, expiry)?,
+                        None => expirations.remove(key)?,
+                    };
+                }
+                _ => {
+                    db.remove(key)?;
+                    expirations.remove(key)?;
+                }
+            }
+            Ok(())
+        };
+        for r in self.db.iter() {
+            let (key, value) = r?;
+            write(&key, Some((value, self.expirations.get(&key)?)))?;
+        }
+        // The keys changed while they were copied are written again, as they were before
+        self.wait_for_writes();
+        let previous = self.previous.lock().clone();
+        for (key, pair) in previous {
+            write(&key, pair)?;
+        }
         Ok(())
     }
 }
+
+impl KvsSnapshot for SledKvsSnapshot {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let range = (Bound::Included(key.clone()), Bound::Included(key));
+        Ok(self.0.pairs(range, &[])?.into_values().next())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsBytesIterator> {
+        if is_empty_range(&range) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(Box::new(self.0.pairs(range, &[])?.into_iter().map(Ok)))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<KvsBytesIterator> {
+        let range = (Bound::Included(prefix.clone()), Bound::Unbounded);
+        Ok(Box::new(self.0.pairs(range, &prefix)?.into_iter().map(Ok)))
+    }
+}
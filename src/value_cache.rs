@@ -0,0 +1,187 @@
+use parking_lot::RwLock;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+/// Number of shards of the `ValueCache`, each one behind its own lock
+const VALUE_CACHE_SHARDS: usize = 16;
+
+/// The location of a value in the log files: its log id and the offset of its command
+pub(crate) type ValueLocation = (u64, u64);
+
+/// Counters of the lookups served by the value cache of a `KvStore`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of reads served from the cache
+    pub hits: u64,
+    /// Number of reads that had to go to the log files
+    pub misses: u64,
+    /// Number of values held by the cache
+    pub entries: u64,
+    /// Bytes of the values held by the cache
+    pub bytes: u64,
+}
+
+/// A concurrent cache of values, bounded in bytes and keyed by the location of the values in the log files.
+/// A location is never written twice, so a cached value can never be stale: a key that is set again points
+/// at a new location, and the entries of the locations no longer pointed at are only removed to free their space.
+///
+/// The locations are spread across shards, each one evicting its values with the CLOCK algorithm:
+/// a hit only flags the value as referenced under the read lock of its shard, and the eviction spares
+/// the flagged values for one more round.
+#[derive(Debug)]
+pub(crate) struct ValueCache {
+    shards: Vec<RwLock<ValueCacheShard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug)]
+struct ValueCacheShard {
+    capacity: u64,
+    bytes: u64,
+    slot_by_location: HashMap<ValueLocation, usize>,
+    slots: Vec<Option<ValueCacheSlot>>,
+    free_slots: Vec<usize>,
+    hand: usize,
+}
+
+#[derive(Debug)]
+struct ValueCacheSlot {
+    location: ValueLocation,
+    value: Vec<u8>,
+    referenced: AtomicBool,
+}
+
+impl ValueCache {
+    /// Creates a cache holding up to `capacity` bytes of values, split evenly across the shards
+    pub(crate) fn new(capacity: u64) -> Self {
+        let shard_capacity = capacity.div_ceil(VALUE_CACHE_SHARDS as u64);
+        Self {
+            shards: (0..VALUE_CACHE_SHARDS)
+                .map(|_| RwLock::new(ValueCacheShard::new(shard_capacity)))
+                .collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn get(&self, location: ValueLocation) -> Option<Vec<u8>> {
+        let value = self.shard(location).read().get(location);
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    pub(crate) fn insert(&self, location: ValueLocation, value: Vec<u8>) {
+        self.shard(location).write().insert(location, value);
+    }
+
+    pub(crate) fn remove(&self, location: ValueLocation) {
+        self.shard(location).write().remove(location);
+    }
+
+    /// Moves the value cached at `from` to the location `to`, which holds the same value
+    pub(crate) fn relocate(&self, from: ValueLocation, to: ValueLocation) {
+        if let Some(value) = self.shard(from).write().remove(from) {
+            self.insert(to, value);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            ..CacheStats::default()
+        };
+        for shard in self.shards.iter() {
+            let shard = shard.read();
+            stats.entries += shard.slot_by_location.len() as u64;
+            stats.bytes += shard.bytes;
+        }
+        stats
+    }
+
+    fn shard(&self, (log_id, offset): ValueLocation) -> &RwLock<ValueCacheShard> {
+        let hash = log_id.wrapping_mul(31).wrapping_add(offset);
+        &self.shards[(hash % self.shards.len() as u64) as usize]
+    }
+}
+
+impl ValueCacheShard {
+    fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            bytes: 0,
+            slot_by_location: HashMap::new(),
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            hand: 0,
+        }
+    }
+
+    fn get(&self, location: ValueLocation) -> Option<Vec<u8>> {
+        let slot = self.slots[*self.slot_by_location.get(&location)?]
+            .as_ref()
+            .unwrap();
+        slot.referenced.store(true, Ordering::Relaxed);
+        Some(slot.value.clone())
+    }
+
+    /// Caches the `value`, evicting as many values as needed to make room for it.
+    /// A value bigger than the whole shard is not cached.
+    fn insert(&mut self, location: ValueLocation, value: Vec<u8>) {
+        let len = value.len() as u64;
+        if len > self.capacity || self.slot_by_location.contains_key(&location) {
+            return;
+        }
+        while self.bytes + len > self.capacity {
+            self.evict_one();
+        }
+        let slot = ValueCacheSlot {
+            location,
+            value,
+            referenced: AtomicBool::new(false),
+        };
+        let i = match self.free_slots.pop() {
+            Some(i) => {
+                self.slots[i] = Some(slot);
+                i
+            }
+            None => {
+                self.slots.push(Some(slot));
+                self.slots.len() - 1
+            }
+        };
+        self.slot_by_location.insert(location, i);
+        self.bytes += len;
+    }
+
+    fn remove(&mut self, location: ValueLocation) -> Option<Vec<u8>> {
+        let i = self.slot_by_location.remove(&location)?;
+        let slot = self.slots[i].take().unwrap();
+        self.free_slots.push(i);
+        self.bytes -= slot.value.len() as u64;
+        Some(slot.value)
+    }
+
+    /// Sweeps the slots from the hand, sparing the referenced values once, and evicts the first value
+    /// that was not referenced since the last sweep
+    fn evict_one(&mut self) {
+        loop {
+            self.hand = (self.hand + 1) % self.slots.len();
+            if let Some(slot) = &self.slots[self.hand] {
+                if !slot.referenced.swap(false, Ordering::Relaxed) {
+                    let location = slot.location;
+                    self.remove(location);
+                    return;
+                }
+            }
+        }
+    }
+}
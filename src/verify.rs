@@ -0,0 +1,100 @@
+use std::{fmt, path::PathBuf};
+
+/// Name of the directory of a store where `KvStore::repair` moves the parts of the log files it cuts off
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// What `KvStore::verify` found in the directory of a store
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The log files, sorted by id
+    pub log_files: Vec<LogFileReport>,
+    /// The files that do not belong to the store
+    pub unknown_files: Vec<PathBuf>,
+    /// The ids of the log files lost, whose hint file was left behind. The commands they held are lost,
+    /// so the store has to be restored from a backup.
+    pub missing_log_ids: Vec<u64>,
+    /// The other ids missing between the first and the last log files. They are left by the compactions,
+    /// which delete the log files they compact and may not use all of the ids they reserve.
+    pub unused_log_ids: Vec<u64>,
+}
+
+/// What `KvStore::verify` found in a log file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFileReport {
+    /// Id of the log file
+    pub log_id: u64,
+    /// Path of the log file
+    pub path: PathBuf,
+    /// Length of the log file in bytes
+    pub len: u64,
+    /// Number of commands decoded from the log file
+    pub cmds: u64,
+    /// The problems found in the log file, sorted by offset
+    pub problems: Vec<LogFileProblem>,
+}
+
+/// A problem found in a log file by `KvStore::verify`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFileProblem {
+    /// The log file does not start with a valid header, so none of its records can be read
+    InvalidHeader,
+    /// The last record of the log file is incomplete, as left by a crash in the middle of a write.
+    /// It is cut off when the store is opened.
+    TornTail {
+        /// Offset of the incomplete record
+        offset: u64,
+    },
+    /// A record that is not the last one of the log file fails its checksum or can not be decoded,
+    /// which keeps the store from being opened
+    CorruptedRecord {
+        /// Offset of the corrupted record
+        offset: u64,
+    },
+}
+
+/// A change made by `KvStore::repair` to a log file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepairAction {
+    /// The incomplete last record of the log file was cut off
+    Truncated {
+        /// Id of the log file
+        log_id: u64,
+        /// The new length of the log file
+        len: u64,
+    },
+    /// The log file was cut off at its first corrupted record, and the part cut off was moved to the quarantine
+    /// directory, or the whole log file if its header is invalid
+    Quarantined {
+        /// Id of the log file
+        log_id: u64,
+        /// The new length of the log file, or 0 if it was moved as a whole
+        len: u64,
+        /// Path of the file holding the part cut off
+        path: PathBuf,
+    },
+}
+
+impl VerifyReport {
+    /// Tells if the store can be opened without losing any command, that is, if no log file has an invalid header
+    /// or a corrupted record. Torn tails are cut off by the store itself, and unknown files are left alone.
+    pub fn is_ok(&self) -> bool {
+        self.log_files.iter().all(|log_file| {
+            log_file
+                .problems
+                .iter()
+                .all(|problem| matches!(problem, LogFileProblem::TornTail { .. }))
+        })
+    }
+}
+
+impl fmt::Display for LogFileProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFileProblem::InvalidHeader => f.write_str("invalid header"),
+            LogFileProblem::TornTail { offset } => write!(f, "torn tail at offset {}", offset),
+            LogFileProblem::CorruptedRecord { offset } => {
+                write!(f, "corrupted record at offset {}", offset)
+            }
+        }
+    }
+}
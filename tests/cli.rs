@@ -151,6 +151,20 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs-server` with a zero setting should exit with a non-zero code, before opening the database
+#[test]
+fn server_cli_invalid_settings() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--compaction-rate", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("value must be greater than zero"));
+    assert!(fs::read_dir(temp_dir.path()).unwrap().next().is_none());
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -212,6 +226,116 @@ fn cli_wrong_engine() {
     }
 }
 
+#[test]
+fn tool_dump_load() {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs_dir = temp_dir.path().join("kvs");
+    let sled_dir = temp_dir.path().join("sled");
+    let records = "{\"key\":\"a\",\"value\":\"1\"}\n\
+                   {\"key\":\"b\",\"value\":\"2\",\"expires_at\":4102444800000}\n\
+                   {\"key\":[255],\"value\":[0,128]}\n";
+    let records_path = temp_dir.path().join("records.jsonl");
+    fs::write(&records_path, records).unwrap();
+
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["load", kvs_dir.to_str().unwrap()])
+        .stdin(File::open(&records_path).unwrap())
+        .assert()
+        .success();
+    let kvs_dump = Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["dump", kvs_dir.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(kvs_dump.status.success());
+    assert_eq!(String::from_utf8(kvs_dump.stdout.clone()).unwrap(), records);
+
+    // A dump of one engine is loaded into the other one
+    let dump_path = temp_dir.path().join("dump.jsonl");
+    fs::write(&dump_path, &kvs_dump.stdout).unwrap();
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["load", sled_dir.to_str().unwrap(), "--engine", "sled"])
+        .stdin(File::open(&dump_path).unwrap())
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["dump", sled_dir.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(records);
+
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["load", kvs_dir.to_str().unwrap(), "--engine", "sled"])
+        .stdin(File::open(&dump_path).unwrap())
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["dump", temp_dir.path().join("empty").to_str().unwrap()])
+        .assert()
+        .failure();
+}
+
+fn server_migrate(from: &str, to: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let records = "{\"key\":\"a\",\"value\":\"1\"}\n\
+                   {\"key\":\"b\",\"value\":\"2\",\"expires_at\":4102444800000}\n";
+    let records_path = temp_dir.path().join("records.jsonl");
+    fs::write(&records_path, records).unwrap();
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["load", ".", "--engine", from])
+        .current_dir(&temp_dir)
+        .stdin(File::open(&records_path).unwrap())
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["migrate", "--from", from, "--to", to])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let config = fs::read_to_string(temp_dir.path().join(".kvs-server-conf.json")).unwrap();
+    assert!(config.contains(to));
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["dump", ".", "--engine", to])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(records);
+
+    // The configuration no longer names the source engine
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["migrate", "--from", from, "--to", to])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    // The data of the source engine is left in place, so migrating back needs it to be removed first
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["migrate", "--from", to, "--to", from])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn server_migrate_kvs_to_sled() {
+    server_migrate("kvs", "sled");
+}
+
+#[test]
+fn server_migrate_sled_to_kvs() {
+    server_migrate("sled", "kvs");
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -1,6 +1,19 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::{
+    CacheStats, CheckpointManifest, CompactionLimits, CompactionPolicy, Durability,
+    GarbageRatioPolicy, KvStore, KvStoreError, KvStoreOptions, KvsCompactor, KvsEngine,
+    KvsSnapshot, LogFileProblem, Op, RepairAction, Result, SegmentStats, SledKvsEngine,
+    QUARANTINE_DIR,
+};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::num::NonZeroU64;
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Barrier, Mutex,
+};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 // use walkdir::WalkDir;
 
@@ -209,3 +222,1505 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn concurrent_get_across_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Many more log files than cached readers, none of them ever compacted
+    let options = KvStoreOptions::new()
+        .max_file_cmds(10)
+        .reader_cache_capacity(4)
+        .compaction_policy(GarbageRatioPolicy::new().min_dead_bytes(u64::MAX));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.compact()?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..16 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..1000 {
+                let key_id = (i * 7 + thread_id) % 200;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+fn log_files(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut files = walkdir::WalkDir::new(dir)
+        .min_depth(1)
+        .max_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path().to_owned())
+        .filter(|p| p.extension().map(|ext| ext == "log").unwrap_or(false))
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+// Should cut off a record that was partially written when the process crashed
+#[test]
+fn recover_from_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = log_files(temp_dir.path()).pop().unwrap();
+    let mut log_file = OpenOptions::new().append(true).open(&log_path)?;
+    // A record header announcing a 100 bytes long payload followed by only 3 bytes of it
+    log_file.write_all(&[100, 0, 0, 0, 1, 2, 3, 4, b'k', b'e', b'y'])?;
+    drop(log_file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should drop the last record when its checksum does not match its content
+#[test]
+fn recover_from_corrupted_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = log_files(temp_dir.path()).pop().unwrap();
+    let mut content = fs::read(&log_path)?;
+    let last = content.len() - 1;
+    content[last] ^= 0xFF;
+    fs::write(&log_path, &content)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should refuse to open a store with a corrupted record in the middle of a log file
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = log_files(temp_dir.path()).pop().unwrap();
+    let mut content = fs::read(&log_path)?;
+    // Flip the last byte of the first record payload
+    let first_record_payload_len =
+        u32::from_le_bytes([content[8], content[9], content[10], content[11]]);
+    content[16 + first_record_payload_len as usize - 1] ^= 0xFF;
+    fs::write(&log_path, &content)?;
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+// Should refuse to open a store whose sealed log file ends with a torn record, instead of cutting off
+// the records after a length field that may be the corrupted one
+#[test]
+fn detect_torn_sealed_log_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = log_files(temp_dir.path()).pop().unwrap();
+    let mut content = fs::read(&log_path)?;
+    content[8..12].copy_from_slice(&1000u32.to_le_bytes());
+    fs::write(&log_path, &content)?;
+    // Seals the corrupted log file
+    let mut header = b"KVSL".to_vec();
+    header.extend_from_slice(&2u32.to_le_bytes());
+    fs::write(temp_dir.path().join("db000000000001.log"), header)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::CorruptedLog { log_id, offset }) => assert_eq!((log_id, offset), (0, 8)),
+        _ => panic!("the torn sealed log file was not detected"),
+    }
+    assert_eq!(fs::read(&log_path)?, content);
+
+    Ok(())
+}
+
+// Should report a corrupted record and a torn tail, and cut both off when repairing the store
+#[test]
+fn verify_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.is_ok());
+    assert_eq!(report.log_files.len(), 1);
+    assert_eq!(report.log_files[0].cmds, 2);
+    assert!(report.log_files[0].problems.is_empty());
+    assert!(KvStore::repair(temp_dir.path())?.is_empty());
+
+    let log_path = log_files(temp_dir.path()).pop().unwrap();
+    let mut content = fs::read(&log_path)?;
+    let first_record_payload_len =
+        u32::from_le_bytes([content[8], content[9], content[10], content[11]]);
+    let second_record_offset = 16 + first_record_payload_len as u64;
+    content[second_record_offset as usize - 1] ^= 0xFF;
+    content.extend_from_slice(&[100, 0, 0, 0, 1, 2, 3, 4]);
+    fs::write(&log_path, &content)?;
+    fs::write(temp_dir.path().join("stray"), b"stray")?;
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(!report.is_ok());
+    assert_eq!(report.log_files[0].cmds, 1);
+    assert_eq!(
+        report.log_files[0].problems,
+        vec![
+            LogFileProblem::CorruptedRecord { offset: 8 },
+            LogFileProblem::TornTail {
+                offset: content.len() as u64 - 8
+            },
+        ]
+    );
+    assert_eq!(report.unknown_files, vec![temp_dir.path().join("stray")]);
+
+    let actions = KvStore::repair(temp_dir.path())?;
+    let quarantine_path = temp_dir.path().join(QUARANTINE_DIR).join(format!(
+        "{}.8",
+        log_path.file_name().unwrap().to_str().unwrap()
+    ));
+    assert_eq!(
+        actions,
+        vec![RepairAction::Quarantined {
+            log_id: 0,
+            len: 8,
+            path: quarantine_path.clone(),
+        }]
+    );
+    assert_eq!(fs::read(&quarantine_path)?, &content[8..]);
+    assert!(KvStore::verify(temp_dir.path())?.is_ok());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should not report the log ids left unused by a compaction as lost log files, unlike a log file lost
+// along with its hint file left behind
+#[test]
+fn verify_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_cmds(10)
+        .compaction_policy(GarbageRatioPolicy::new().min_dead_bytes(200));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("victim".to_owned(), "old".to_owned())?;
+    for key_id in 0..10 {
+        store.set(format!("stable{}", key_id), "value".to_owned())?;
+    }
+    store.compact()?;
+    store.remove("victim".to_owned())?;
+    for iter in 0..20 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+    }
+    // Compacts the second log file alone, leaving a gap after the first one
+    store.compact()?;
+    drop(store);
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.is_ok());
+    assert!(report.missing_log_ids.is_empty());
+    assert!(!report.unused_log_ids.is_empty());
+    assert!(report.unknown_files.is_empty());
+
+    let first_log_file = log_files(temp_dir.path())[0].clone();
+    assert!(first_log_file.with_extension("hint").exists());
+    fs::remove_file(&first_log_file)?;
+    let report = KvStore::verify(temp_dir.path())?;
+    assert_eq!(report.missing_log_ids, vec![0]);
+    assert!(report.unknown_files.is_empty());
+
+    Ok(())
+}
+
+// Should refuse to open a store that is already open, naming the process holding it
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let snapshot = store.snapshot()?;
+    drop(store);
+
+    let is_locked = |res: Result<()>| match res {
+        Err(KvStoreError::DirectoryLocked { pid, .. }) => pid == std::process::id(),
+        _ => false,
+    };
+    assert!(is_locked(KvStore::open(temp_dir.path()).map(|_| ())));
+    assert!(is_locked(SledKvsEngine::open(temp_dir.path()).map(|_| ())));
+    assert!(is_locked(KvStore::verify(temp_dir.path()).map(|_| ())));
+    assert!(is_locked(KvStore::repair(temp_dir.path()).map(|_| ())));
+    assert!(is_locked(KvStore::dump(temp_dir.path()).map(|_| ())));
+
+    // The lock is released along with the last clone of the store
+    drop(snapshot);
+    assert!(KvStore::verify(temp_dir.path())?.unknown_files.is_empty());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should dump a store without cutting off the torn tail of its log file, keeping it locked meanwhile
+#[test]
+fn dump_leaves_store_untouched() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log_path = log_files(temp_dir.path()).pop().unwrap();
+    let mut log_file = OpenOptions::new().append(true).open(&log_path)?;
+    log_file.write_all(&[100, 0, 0, 0, 1, 2, 3, 4, b'k', b'e', b'y'])?;
+    drop(log_file);
+    let content = fs::read(&log_path)?;
+
+    let dump = KvStore::dump(temp_dir.path())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvStoreError::DirectoryLocked { .. })
+    ));
+    let records = dump.collect::<Result<Vec<_>>>()?;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].key, b"key1".to_vec());
+    assert_eq!(fs::read(&log_path)?, content);
+
+    // The lock is released along with the iterator
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+fn concurrent_set_with_durability(durability: Durability) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().durability(durability),
+    )?;
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..50 {
+                store
+                    .set(format!("key{}-{}", thread_id, i), format!("value{}", i))
+                    .unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    store.remove("key0-0".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().durability(durability),
+    )?;
+    assert_eq!(store.get("key0-0".to_owned())?, None);
+    for thread_id in 0..8 {
+        for i in 1..50 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_set_sync_each_write() -> Result<()> {
+    concurrent_set_with_durability(Durability::SyncEachWrite)
+}
+
+#[test]
+fn concurrent_set_group_commit() -> Result<()> {
+    concurrent_set_with_durability(Durability::GroupCommit)
+}
+
+#[test]
+fn concurrent_set_interval_sync() -> Result<()> {
+    concurrent_set_with_durability(Durability::Interval(Duration::from_millis(10)))
+}
+
+#[test]
+fn parse_durability() {
+    assert_eq!("buffered".parse(), Ok(Durability::Buffered));
+    assert_eq!("sync".parse(), Ok(Durability::SyncEachWrite));
+    assert_eq!("group".parse(), Ok(Durability::GroupCommit));
+    assert_eq!(
+        "interval:250".parse(),
+        Ok(Durability::Interval(Duration::from_millis(250)))
+    );
+    assert!("interval:0".parse::<Durability>().is_err());
+    assert!("always".parse::<Durability>().is_err());
+}
+
+// Should roll over to new log files and compact them according to the options
+#[test]
+fn small_file_and_compaction_thresholds() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_cmds(10)
+        .compaction_policy(GarbageRatioPolicy::new().min_dead_bytes(1000));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let mut max_log_files = 0;
+    for iter in 0..20 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        store.compact()?;
+        max_log_files = max_log_files.max(log_files(temp_dir.path()).len());
+    }
+    assert!(max_log_files > 1);
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+
+    Ok(())
+}
+
+fn scan_keys<E: KvsEngine>(engine: &E) -> Result<()> {
+    for key in &[
+        "user/2/name",
+        "user/1/name",
+        "user/10/name",
+        "user/1/age",
+        "zone",
+    ] {
+        engine.set(key.to_string(), format!("{}-value", key))?;
+    }
+    engine.remove("zone".to_owned())?;
+
+    let all = engine
+        .scan(..)?
+        .map(|r| r.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        all,
+        vec!["user/1/age", "user/1/name", "user/10/name", "user/2/name"]
+    );
+
+    let range = engine
+        .scan("user/1/name".to_owned().."user/2".to_owned())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        range,
+        vec![
+            ("user/1/name".to_owned(), "user/1/name-value".to_owned()),
+            ("user/10/name".to_owned(), "user/10/name-value".to_owned()),
+        ]
+    );
+
+    let prefix = engine
+        .scan_prefix("user/1/".to_owned())?
+        .map(|r| r.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(prefix, vec!["user/1/age", "user/1/name"]);
+
+    assert_eq!(engine.scan("b".to_owned().."a".to_owned())?.count(), 0);
+    assert_eq!(engine.scan_prefix("zone".to_owned())?.count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn scan_kvstore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    scan_keys(&store)?;
+
+    // Open from disk again and check the order is rebuilt
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let prefix = store
+        .scan_prefix("user/".to_owned())?
+        .map(|r| r.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        prefix,
+        vec!["user/1/age", "user/1/name", "user/10/name", "user/2/name"]
+    );
+
+    // The index is walked lazily, so the keys written or removed ahead of the scan are seen by it
+    let mut scan = store.scan(..)?.map(|r| r.map(|(key, _)| key));
+    assert_eq!(scan.next().transpose()?, Some("user/1/age".to_owned()));
+    store.set("user/3/name".to_owned(), "user/3/name-value".to_owned())?;
+    store.remove("user/10/name".to_owned())?;
+    assert_eq!(
+        scan.collect::<Result<Vec<_>>>()?,
+        vec!["user/1/name", "user/2/name", "user/3/name"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn scan_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    scan_keys(&engine)
+}
+
+fn binary_keys_and_values<E: KvsEngine>(engine: &E) -> Result<()> {
+    let image = vec![0x89, b'P', b'N', b'G', 0x00, 0xFF, 0xFE];
+    engine.set_bytes(vec![0xFF, 0x00], image.clone())?;
+    engine.set_bytes(vec![0xFF, 0x01], vec![])?;
+    engine.set("text".to_owned(), "value".to_owned())?;
+
+    assert_eq!(engine.get_bytes(vec![0xFF, 0x00])?, Some(image.clone()));
+    assert_eq!(engine.get_bytes(vec![0xFF, 0x01])?, Some(vec![]));
+    assert_eq!(engine.get_bytes(b"text".to_vec())?, Some(b"value".to_vec()));
+
+    // The string api refuses to return bytes that are not valid UTF-8
+    engine.set_bytes(b"image".to_vec(), image.clone())?;
+    assert!(matches!(
+        engine.get("image".to_owned()),
+        Err(KvStoreError::Utf8(_))
+    ));
+
+    let prefix = engine
+        .scan_prefix_bytes(vec![0xFF])?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        prefix,
+        vec![(vec![0xFF, 0x00], image), (vec![0xFF, 0x01], vec![])]
+    );
+
+    engine.remove_bytes(vec![0xFF, 0x00])?;
+    assert_eq!(engine.get_bytes(vec![0xFF, 0x00])?, None);
+    assert!(engine.remove_bytes(vec![0xFF, 0x00]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn binary_keys_and_values_kvstore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    binary_keys_and_values(&store)?;
+
+    // Open from disk again and check the binary data survived
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(vec![0xFF, 0x01])?, Some(vec![]));
+    assert_eq!(store.get_bytes(vec![0xFF, 0x00])?, None);
+    assert_eq!(store.get("text".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn binary_keys_and_values_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    binary_keys_and_values(&engine)
+}
+
+fn write_batch_ops<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("alice".to_owned(), "10".to_owned())?;
+    engine.write_batch(vec![
+        Op::remove("alice"),
+        Op::set("bob", "10"),
+        Op::set("carol", "5"),
+        Op::set("bob", "15"),
+        Op::remove("dave"),
+    ])?;
+    assert_eq!(engine.get("alice".to_owned())?, None);
+    assert_eq!(engine.get("bob".to_owned())?, Some("15".to_owned()));
+    assert_eq!(engine.get("carol".to_owned())?, Some("5".to_owned()));
+    assert_eq!(engine.get("dave".to_owned())?, None);
+
+    engine.write_batch(vec![])?;
+    assert_eq!(engine.scan(..)?.count(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn write_batch_kvstore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    write_batch_ops(&store)?;
+
+    // Open from disk again and check the batch is replayed
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("alice".to_owned())?, None);
+    assert_eq!(store.get("bob".to_owned())?, Some("15".to_owned()));
+    assert_eq!(store.get("carol".to_owned())?, Some("5".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn write_batch_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    write_batch_ops(&engine)
+}
+
+#[test]
+fn torn_batch_is_not_replayed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.write_batch(vec![
+        Op::remove("key1"),
+        Op::set("key2", "value2"),
+        Op::set("key3", "value3"),
+    ])?;
+    drop(store);
+
+    // Cut the last bytes of the batch, as if the machine crashed in the middle of writing it
+    let log_path = log_files(temp_dir.path()).pop().unwrap();
+    let log_file = OpenOptions::new().write(true).open(&log_path)?;
+    let len = log_file.metadata()?.len();
+    log_file.set_len(len - 3)?;
+    drop(log_file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn compaction_keeps_batched_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_cmds(10)
+        .compaction_policy(GarbageRatioPolicy::new().min_dead_bytes(1000));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..20 {
+        let ops = (0..5)
+            .map(|key_id| Op::set(format!("key{}", key_id), format!("{}", iter)))
+            .collect();
+        store.write_batch(ops)?;
+        store.compact()?;
+    }
+    drop(store);
+    // Without compaction the 100 commands would be spread over 10 log files
+    assert!(log_files(temp_dir.path()).len() < 10);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..5 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+
+    Ok(())
+}
+
+fn compare_and_swap_values<E: KvsEngine>(engine: &E) -> Result<()> {
+    // Create only if absent
+    engine.compare_and_swap("leader".to_owned(), None, Some("node1".to_owned()))?;
+    match engine.compare_and_swap("leader".to_owned(), None, Some("node2".to_owned())) {
+        Err(KvStoreError::CompareAndSwapFailed { current }) => {
+            assert_eq!(current, Some(b"node1".to_vec()))
+        }
+        res => panic!("unexpected compare and swap result: {:?}", res),
+    }
+
+    // Swap the expected value
+    engine.compare_and_swap(
+        "leader".to_owned(),
+        Some("node1".to_owned()),
+        Some("node2".to_owned()),
+    )?;
+    assert_eq!(engine.get("leader".to_owned())?, Some("node2".to_owned()));
+
+    // Remove the expected value
+    assert!(engine
+        .compare_and_swap("leader".to_owned(), Some("node1".to_owned()), None)
+        .is_err());
+    engine.compare_and_swap("leader".to_owned(), Some("node2".to_owned()), None)?;
+    assert_eq!(engine.get("leader".to_owned())?, None);
+    match engine.compare_and_swap("leader".to_owned(), Some("node2".to_owned()), None) {
+        Err(KvStoreError::CompareAndSwapFailed { current }) => assert_eq!(current, None),
+        res => panic!("unexpected compare and swap result: {:?}", res),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap_kvstore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    compare_and_swap_values(&store)?;
+    store.compare_and_swap("leader".to_owned(), None, Some("node3".to_owned()))?;
+
+    // Open from disk again and check the swapped values
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("leader".to_owned())?, Some("node3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    compare_and_swap_values(&engine)
+}
+
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    loop {
+                        let current = store.get("counter".to_owned()).unwrap().unwrap();
+                        let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                        match store.compare_and_swap(
+                            "counter".to_owned(),
+                            Some(current),
+                            Some(next),
+                        ) {
+                            Ok(()) => break,
+                            Err(KvStoreError::CompareAndSwapFailed { .. }) => continue,
+                            Err(e) => panic!("{}", e),
+                        }
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+    Ok(())
+}
+
+fn expiring_keys<E: KvsEngine + KvsCompactor>(engine: &E) -> Result<()> {
+    engine.set_with_ttl(
+        "session".to_owned(),
+        "John".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    engine.set_with_ttl(
+        "token".to_owned(),
+        "abc".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    engine.set_with_ttl(
+        "short".to_owned(),
+        "lived".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    // Setting the key again without a ttl makes it permanent
+    engine.set("short".to_owned(), "forever".to_owned())?;
+    assert_eq!(engine.get("session".to_owned())?, Some("John".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get("session".to_owned())?, None);
+    assert_eq!(engine.get("token".to_owned())?, Some("abc".to_owned()));
+    assert_eq!(engine.get("short".to_owned())?, Some("forever".to_owned()));
+    let keys = engine
+        .scan_prefix(String::new())?
+        .map(|r| r.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec!["short".to_owned(), "token".to_owned()]);
+    match engine.remove("session".to_owned()) {
+        Err(KvStoreError::RemoveNonExistentKey) => {}
+        res => panic!("unexpected remove result: {:?}", res),
+    }
+
+    engine.set_with_ttl(
+        "session".to_owned(),
+        "Mary".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    thread::sleep(Duration::from_millis(300));
+    // An expired key is compared as if it did not exist
+    engine.compare_and_swap("session".to_owned(), None, Some("Paul".to_owned()))?;
+    assert_eq!(engine.get("session".to_owned())?, Some("Paul".to_owned()));
+
+    engine.set_with_ttl(
+        "evicted".to_owned(),
+        "soon".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    thread::sleep(Duration::from_millis(300));
+    engine.evict_expired()?;
+    assert_eq!(engine.get("evicted".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn expiring_keys_kvstore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    expiring_keys(&store)?;
+    store.set_with_ttl(
+        "later".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(500),
+    )?;
+
+    // The expiry timestamps survive a restart
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("evicted".to_owned())?, None);
+    assert_eq!(store.get("session".to_owned())?, Some("Paul".to_owned()));
+    assert_eq!(store.get("later".to_owned())?, Some("value".to_owned()));
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(store.get("later".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn expiring_keys_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    expiring_keys(&engine)
+}
+
+fn snapshots<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("a".to_owned(), "1".to_owned())?;
+    engine.set("b".to_owned(), "2".to_owned())?;
+    engine.set("c".to_owned(), "3".to_owned())?;
+    engine.set_with_ttl("d".to_owned(), "4".to_owned(), Duration::from_millis(100))?;
+    let snapshot = engine.snapshot()?;
+
+    engine.set("a".to_owned(), "10".to_owned())?;
+    engine.remove("b".to_owned())?;
+    engine.write_batch(vec![Op::set("c", "30"), Op::set("e", "50")])?;
+    thread::sleep(Duration::from_millis(200));
+
+    assert_eq!(snapshot.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(snapshot.get("b".to_owned())?, Some("2".to_owned()));
+    assert_eq!(snapshot.get("e".to_owned())?, None);
+    // A key that expired after the snapshot was taken is still seen by it
+    assert_eq!(snapshot.get("d".to_owned())?, Some("4".to_owned()));
+    let pairs = snapshot
+        .scan("b".to_owned()..)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("b".to_owned(), "2".to_owned()),
+            ("c".to_owned(), "3".to_owned()),
+            ("d".to_owned(), "4".to_owned()),
+        ]
+    );
+    assert_eq!(snapshot.scan_prefix("a".to_owned())?.count(), 1);
+
+    let pairs = engine.scan("a".to_owned()..)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("a".to_owned(), "10".to_owned()),
+            ("c".to_owned(), "30".to_owned()),
+            ("e".to_owned(), "50".to_owned()),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn snapshots_kvstore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    snapshots(&store)
+}
+
+#[test]
+fn snapshots_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    snapshots(&engine)
+}
+
+#[test]
+fn snapshot_outlives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_cmds(10)
+        .compaction_policy(GarbageRatioPolicy::new().min_dead_bytes(1));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..30 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    let snapshot = store.snapshot()?;
+    for round in 0..3 {
+        for key_id in 0..30 {
+            store.set(format!("key{}", key_id), format!("new{}", round))?;
+        }
+        store.compact()?;
+    }
+
+    // The log files holding the old values are compacted, but not deleted while the snapshot is alive
+    let iter = snapshot.scan_prefix("key".to_owned())?;
+    drop(snapshot);
+    store.compact()?;
+    let values = iter
+        .map(|r| r.map(|(_, value)| value))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(values, vec!["old".to_owned(); 30]);
+    assert_eq!(store.get("key0".to_owned())?, Some("new2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key29".to_owned())?, Some("new2".to_owned()));
+
+    Ok(())
+}
+
+fn checkpoint<E, F>(engine: &E, open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine.set("a".to_owned(), "1".to_owned())?;
+    engine.set("b".to_owned(), "2".to_owned())?;
+    engine.set_with_ttl("c".to_owned(), "3".to_owned(), Duration::from_secs(60))?;
+    engine.set_with_ttl("d".to_owned(), "4".to_owned(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    let manifest = engine.checkpoint(backup_dir.path())?;
+    assert!(!manifest.files.is_empty());
+    assert_eq!(CheckpointManifest::read(backup_dir.path())?, manifest);
+
+    engine.set("a".to_owned(), "10".to_owned())?;
+    engine.remove("b".to_owned())?;
+    // A checkpoint never mixes its files with other ones
+    assert!(matches!(
+        engine.checkpoint(backup_dir.path()),
+        Err(KvStoreError::CheckpointDirNotEmpty { .. })
+    ));
+
+    let restored = open(backup_dir.path())?;
+    let pairs = restored
+        .scan("a".to_owned()..)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("a".to_owned(), "1".to_owned()),
+            ("b".to_owned(), "2".to_owned()),
+            ("c".to_owned(), "3".to_owned()),
+        ]
+    );
+
+    // The restored database is written independently of the one it was taken from
+    restored.set("b".to_owned(), "20".to_owned())?;
+    assert_eq!(engine.get("a".to_owned())?, Some("10".to_owned()));
+    assert_eq!(engine.get("b".to_owned())?, None);
+    assert_eq!(restored.get("a".to_owned())?, Some("1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn checkpoint_kvstore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    checkpoint(&store, |path| KvStore::open(path))?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("10".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn checkpoint_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    checkpoint(&engine, |path| SledKvsEngine::open(path))
+}
+
+#[test]
+fn sled_snapshot_while_writing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open_with_durability(temp_dir.path(), Durability::Buffered)?;
+    let set_all = |engine: &SledKvsEngine, round: usize| {
+        let ops = (0..50)
+            .map(|key_id| Op::set(format!("key{:02}", key_id), format!("{}", round)))
+            .collect();
+        engine.write_batch(ops)
+    };
+    set_all(&engine, 0)?;
+
+    // The snapshots and checkpoints taken while the keys are written see every key of the same batch
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let engine = engine.clone();
+        let stop = stop.clone();
+        thread::spawn(move || -> Result<()> {
+            let mut round = 1;
+            while !stop.load(Ordering::SeqCst) {
+                set_all(&engine, round)?;
+                if round % 2 == 0 {
+                    engine.remove("key07".to_owned())?;
+                }
+                round += 1;
+            }
+            Ok(())
+        })
+    };
+    let check_round = |pairs: Vec<(String, String)>, round: &str| {
+        assert!(pairs.len() == 50 || pairs.len() == 49);
+        assert!(pairs.iter().all(|(_, value)| value == round));
+    };
+    for _ in 0..20 {
+        let snapshot = engine.snapshot()?;
+        let round = snapshot.get("key00".to_owned())?.unwrap();
+        thread::sleep(Duration::from_millis(5));
+        check_round(
+            snapshot
+                .scan_prefix("key".to_owned())?
+                .collect::<Result<Vec<_>>>()?,
+            &round,
+        );
+        assert_eq!(snapshot.get("key49".to_owned())?, Some(round));
+    }
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine.checkpoint(backup_dir.path())?;
+    stop.store(true, Ordering::SeqCst);
+    writer.join().expect("the writer panicked")?;
+
+    let restored = SledKvsEngine::open(backup_dir.path())?;
+    let pairs = restored
+        .scan_prefix("key".to_owned())?
+        .collect::<Result<Vec<_>>>()?;
+    let round = pairs[0].1.clone();
+    check_round(pairs, &round);
+
+    Ok(())
+}
+
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_cmds(10)
+        .compaction_policy(GarbageRatioPolicy::new().min_dead_bytes(1000));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..50 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(200),
+        )?;
+    }
+    store.set("kept".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(300));
+    store.compact()?;
+    drop(store);
+    // The expired keys are not rewritten, so a single log file is left besides the active one
+    assert!(log_files(temp_dir.path()).len() <= 2);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn reads_version_1_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // A set command of the version 1 of the log format, which had no expiration
+    let mut payload = 0u32.to_le_bytes().to_vec();
+    for field in [b"key1", b"val1"].iter() {
+        payload.extend_from_slice(&(field.len() as u64).to_le_bytes());
+        payload.extend_from_slice(&field[..]);
+    }
+    let mut log = b"KVSL".to_vec();
+    log.extend_from_slice(&1u32.to_le_bytes());
+    log.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    log.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    log.extend_from_slice(&payload);
+    fs::write(temp_dir.path().join("db000000000000.log"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("val1".to_owned()));
+    store.set("key2".to_owned(), "val2".to_owned())?;
+    drop(store);
+    // Newer commands go to a new file instead of being appended to the version 1 one
+    assert_eq!(log_files(temp_dir.path()).len(), 2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("val1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("val2".to_owned()));
+
+    Ok(())
+}
+
+// Should read the log files written before they had a header, whose commands are not framed into records
+#[test]
+fn reads_version_0_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = Vec::new();
+    for (tag, fields) in [
+        (0u32, vec!["key1", "val1"]),
+        (0, vec!["key2", "val2"]),
+        (1, vec!["key2"]),
+    ]
+    .iter()
+    {
+        log.extend_from_slice(&tag.to_le_bytes());
+        for field in fields {
+            log.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            log.extend_from_slice(field.as_bytes());
+        }
+    }
+    fs::write(temp_dir.path().join("db000000000000.log"), log)?;
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.is_ok());
+    assert_eq!(report.log_files[0].cmds, 3);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("val1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "val3".to_owned())?;
+    drop(store);
+    assert_eq!(log_files(temp_dir.path()).len(), 2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("val1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("val3".to_owned()));
+
+    Ok(())
+}
+
+fn hint_files(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut files = walkdir::WalkDir::new(dir)
+        .min_depth(1)
+        .max_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path().to_owned())
+        .filter(|p| p.extension().map(|ext| ext == "hint").unwrap_or(false))
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+#[test]
+fn open_from_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Compaction is never triggered, so that every sealed log file is kept
+    let options = KvStoreOptions::new()
+        .max_file_cmds(10)
+        .compaction_policy(GarbageRatioPolicy::new().min_dead_bytes(u64::MAX));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        store.compact()?;
+    }
+    store.write_batch(vec![Op::remove("key1"), Op::set("key2", "batched")])?;
+    store.remove("key3".to_owned())?;
+    store.set_with_ttl(
+        "key4".to_owned(),
+        "expiring".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    for key_id in 50..60 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact()?;
+    drop(store);
+    // Every log file but the current one has a hint file
+    assert_eq!(
+        hint_files(temp_dir.path()).len(),
+        log_files(temp_dir.path()).len() - 1
+    );
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("batched".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+        for key_id in 5..60 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        Ok(())
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    check(&store)?;
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key4".to_owned())?, None);
+    drop(store);
+
+    // Corrupted or stale hint files are ignored and their log files scanned instead
+    let hints = hint_files(temp_dir.path());
+    fs::write(&hints[0], b"garbage")?;
+    OpenOptions::new()
+        .append(true)
+        .open(&hints[1])?
+        .write_all(b"garbage")?;
+    fs::copy(&hints[3], &hints[2])?;
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Writes done while a compaction runs must win over the entries moved by the compaction
+#[test]
+fn compaction_concurrent_with_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_cmds(50)
+        .compaction_policy(GarbageRatioPolicy::new().min_dead_bytes(2000));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "initial".to_owned())?;
+    }
+
+    let barrier = Arc::new(Barrier::new(5));
+    let writers = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || -> Result<()> {
+                barrier.wait();
+                for iter in 0..50 {
+                    for key_id in (thread_id..100).step_by(4) {
+                        if key_id % 10 == 0 && iter == 49 {
+                            store.remove(format!("key{}", key_id))?;
+                        } else {
+                            store.set(format!("key{}", key_id), format!("{}", iter))?;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    barrier.wait();
+    while !writers.iter().all(|writer| writer.is_finished()) {
+        store.compact()?;
+    }
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    store.compact()?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            let expected = if key_id % 10 == 0 {
+                None
+            } else {
+                Some("49".to_owned())
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Should only rewrite the log files with enough garbage, keeping the removals that shadow the log files left out
+#[test]
+fn compaction_picks_garbage_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_cmds(10)
+        .compaction_policy(GarbageRatioPolicy::new().min_dead_bytes(200));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("victim".to_owned(), "old".to_owned())?;
+    for key_id in 0..10 {
+        store.set(format!("stable{}", key_id), "value".to_owned())?;
+    }
+    // Seals the first log file, which is almost all live
+    store.compact()?;
+    let first_log_file = log_files(temp_dir.path())[0].clone();
+
+    store.remove("victim".to_owned())?;
+    for iter in 0..20 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+    }
+    let second_log_file = log_files(temp_dir.path())[1].clone();
+    // Seals the second log file, which is almost all garbage, and compacts it alone
+    store.compact()?;
+    drop(store);
+    assert_eq!(log_files(temp_dir.path())[0], first_log_file);
+    assert!(!log_files(temp_dir.path()).contains(&second_log_file));
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("victim".to_owned())?, None);
+    assert_eq!(store.get("hot".to_owned())?, Some("19".to_owned()));
+    assert_eq!(store.get("stable9".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should keep a removal in place of an expired key evicted from the index, which shadows its older copies
+// in the log files left out of the compaction
+#[test]
+fn compaction_keeps_evicted_keys_removed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_cmds(10)
+        .compaction_policy(GarbageRatioPolicy::new().min_dead_bytes(200));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("victim".to_owned(), "old".to_owned())?;
+    for key_id in 0..10 {
+        store.set(format!("stable{}", key_id), "value".to_owned())?;
+    }
+    // Seals the first log file, which is almost all live
+    store.compact()?;
+    let first_log_file = log_files(temp_dir.path())[0].clone();
+
+    store.set_with_ttl(
+        "victim".to_owned(),
+        "new".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    for iter in 0..20 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+    }
+    thread::sleep(Duration::from_millis(100));
+    store.evict_expired()?;
+    // Seals the second log file, which is almost all garbage, and compacts it alone
+    store.compact()?;
+    drop(store);
+    assert_eq!(log_files(temp_dir.path())[0], first_log_file);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("victim".to_owned())?, None);
+    assert_eq!(store.get("hot".to_owned())?, Some("19".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn compaction_within_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_file_cmds(10);
+    // Seals log files full of garbage, without ever compacting them
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        options
+            .clone()
+            .compaction_policy(GarbageRatioPolicy::new().min_dead_bytes(u64::MAX)),
+    )?;
+    for iter in 0..40 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+        store.compact()?;
+    }
+    assert!(!store.compaction_needed()?.is_needed());
+    drop(store);
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        options.compaction_policy(GarbageRatioPolicy::new().min_dead_bytes(1)),
+    )?;
+    let estimate = store.compaction_needed()?;
+    assert!(estimate.log_files >= 3);
+    assert!(estimate.reclaimable_bytes > estimate.bytes_to_rewrite);
+
+    // The byte budget is smaller than any log file, so only the first one is picked
+    let progress = store.compact_with(&CompactionLimits::new().max_bytes(1))?;
+    assert_eq!(progress.log_files_total, 1);
+    assert!(progress.is_complete());
+    assert!(progress.bytes_read > 0);
+    assert!(store.compaction_progress().is_none());
+    // The log files left out are still waiting for a compaction
+    assert!(store.compaction_needed()?.is_needed());
+
+    let max_bytes_per_sec = NonZeroU64::new(4000).unwrap();
+    let progress =
+        store.compact_with(&CompactionLimits::new().max_bytes_per_sec(max_bytes_per_sec))?;
+    assert!(progress.is_complete());
+    let min_elapsed = Duration::from_secs_f64(
+        (progress.bytes_read + progress.bytes_written) as f64 / max_bytes_per_sec.get() as f64,
+    );
+    assert!(progress.elapsed >= min_elapsed);
+    assert!(!store.compaction_needed()?.is_needed());
+    assert_eq!(store.get("hot".to_owned())?, Some("39".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn time_windows() {
+    use kvs::TimeWindow;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let at =
+        |hours: u64, minutes: u64| UNIX_EPOCH + Duration::from_secs((hours * 60 + minutes) * 60);
+    let night = "22:30-05:00".parse::<TimeWindow>().unwrap();
+    assert_eq!(night.to_string(), "22:30-05:00");
+    assert!(night.contains(at(23, 0)));
+    assert!(night.contains(at(4, 59)));
+    assert!(!night.contains(at(5, 0)));
+    assert!(!night.contains(at(12, 0)));
+    let lunch = "12:00-13:00".parse::<TimeWindow>().unwrap();
+    assert!(lunch.contains(at(24 * 365 + 12, 30)));
+    assert!(!lunch.contains(SystemTime::UNIX_EPOCH));
+    assert!("25:00-01:00".parse::<TimeWindow>().is_err());
+    assert!("12:00".parse::<TimeWindow>().is_err());
+}
+
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_cmds(10)
+        .value_cache_capacity(16 * 1024)
+        .compaction_policy(GarbageRatioPolicy::new().min_dead_bytes(1));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.value_cache_stats(), CacheStats::default());
+
+    store.set("key".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("value1".to_owned()));
+    let stats = store.value_cache_stats();
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+    assert_eq!((stats.entries, stats.bytes), (1, 6));
+
+    // Overwritten and removed values are never served from the cache
+    store.set("key".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.value_cache_stats().entries, 0);
+    assert_eq!(store.get("key".to_owned())?, Some("value2".to_owned()));
+    store.remove("key".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.value_cache_stats().entries, 0);
+
+    // The values moved by a compaction stay cached
+    store.set("key".to_owned(), "value3".to_owned())?;
+    for iter in 0..20 {
+        store.set("garbage".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("value3".to_owned()));
+    store.compact()?;
+    store.compact()?;
+    let hits = store.value_cache_stats().hits;
+    assert_eq!(store.get("key".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.value_cache_stats().hits, hits + 1);
+
+    // The cache never holds more than its capacity
+    let value = "v".repeat(1000);
+    for key_id in 0..100 {
+        store.set(format!("big{}", key_id), value.clone())?;
+        assert_eq!(store.get(format!("big{}", key_id))?, Some(value.clone()));
+    }
+    assert!(store.value_cache_stats().bytes <= 16 * 1024);
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("big{}", key_id))?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+/// Compacts all the sealed log files, recording the ids of the ones it was offered
+#[derive(Debug, Default)]
+struct CompactAll {
+    offered: Arc<Mutex<Vec<u64>>>,
+}
+
+impl CompactionPolicy for CompactAll {
+    fn select(&self, segments: &[SegmentStats]) -> Vec<u64> {
+        let log_ids = segments
+            .iter()
+            .map(|segment| segment.log_id)
+            .collect::<Vec<_>>();
+        self.offered.lock().unwrap().extend(log_ids.iter());
+        log_ids
+    }
+}
+
+#[test]
+fn custom_compaction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = CompactAll::default();
+    let offered = policy.offered.clone();
+    let options = KvStoreOptions::new()
+        .max_file_cmds(10)
+        .compaction_policy(policy);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        store.compact()?;
+    }
+    store.remove("key0".to_owned())?;
+    store.compact()?;
+    drop(store);
+    // Only sealed log files are offered, and each one at most once, since it is compacted right away
+    let mut offered = offered.lock().unwrap().clone();
+    assert!(!offered.is_empty());
+    let offered_len = offered.len();
+    offered.dedup();
+    assert_eq!(offered.len(), offered_len);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key49".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
@@ -1,15 +1,15 @@
 use kvs::{
     thread_pool::{SharedQueueThreadPool, ThreadPool},
-    KvClient, KvServer, KvStore,
+    AsyncKvClient, KvClient, KvClientBuilder, KvClientError, KvPipeline, KvServer,
+    KvServerShutdownTrigger, KvStore, KvsEngine, Op, PipelineReply, RetryPolicy,
 };
 use slog::o;
+use std::{net::TcpStream, thread, thread::JoinHandle, time::Duration};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
 #[test]
 fn compaction() {
-    let server_port = portpicker::pick_unused_port().unwrap();
-    let server_addr = format!("127.0.0.1:{}", server_port);
     let cpu_threads = num_cpus::get_physical() as u32;
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let dir_size = || {
@@ -23,9 +23,8 @@ fn compaction() {
         len.expect("fail to get directory size")
     };
 
-    let mut server = KvServer::new(
+    let (server_addr, server_shutdown_trigger, server_join_handle) = start_server(
         KvStore::open(temp_dir.path()).expect("unable to open database file"),
-        server_addr.as_str(),
         SharedQueueThreadPool::new(cpu_threads).expect(
             format!(
                 "unable to initialize a thread pool with {} threads",
@@ -33,38 +32,24 @@ fn compaction() {
             )
             .as_str(),
         ),
-        slog::Logger::root(slog::Discard, o!("" => "")),
-        None,
-    )
-    .expect("unable to start the kvs server");
-    let server_shutdown_trigger = server.get_shutdown_trigger();
-
-    let server_join_handle = std::thread::spawn(move || {
-        server.run().expect("server stopped with an error");
-    });
-    std::thread::sleep(std::time::Duration::from_secs(1));
+        |server| server,
+    );
 
     let mut current_size = dir_size();
 
-    let client = KvClient::new(server_addr.as_str()).expect("unable to start client");
+    let client = KvClientBuilder::new()
+        .retry_policy(RetryPolicy::new(10))
+        .build(server_addr.as_str())
+        .expect("unable to start client");
     let mut last_iter = 0;
     for iter in 0..1000 {
         last_iter = iter;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             let value = format!("{}", iter);
-            let mut attempts = 1000;
-            loop {
-                if let Err(e) = client.send_cmd_set(key.clone(), value.clone()) {
-                    if attempts > 0 {
-                        attempts -= 1;
-                    } else {
-                        panic!("{}", e.to_string())
-                    }
-                } else {
-                    break;
-                }
-            }
+            client
+                .send_cmd_set_idempotent(key, value, format!("{}-{}", iter, key_id))
+                .unwrap_or_else(|e| panic!("{}", e.to_string()));
         }
 
         let new_size = dir_size();
@@ -77,7 +62,6 @@ fn compaction() {
     }
     // Compaction triggered
     server_shutdown_trigger.trigger();
-    std::thread::sleep(std::time::Duration::from_secs(1));
     server_join_handle
         .join()
         .expect("unable to join server thread");
@@ -85,9 +69,8 @@ fn compaction() {
     drop(server_shutdown_trigger);
 
     // reopen and check content
-    let mut server = KvServer::new(
+    let (server_addr, server_shutdown_trigger, server_join_handle) = start_server(
         KvStore::open(temp_dir.path()).expect("unable to open database file"),
-        server_addr.as_str(),
         SharedQueueThreadPool::new(cpu_threads).expect(
             format!(
                 "unable to initialize a thread pool with {} threads",
@@ -95,34 +78,454 @@ fn compaction() {
             )
             .as_str(),
         ),
-        slog::Logger::root(slog::Discard, o!("" => "")),
-        None,
-    )
-    .expect("unable to start the kvs server");
-    let server_shutdown_trigger = server.get_shutdown_trigger();
-
-    let server_join_handle = std::thread::spawn(move || {
-        server.run().expect("server stopped with an error");
-    });
+        |server| server,
+    );
 
+    let client = KvClient::new(server_addr.as_str()).expect("unable to start client");
     let value = Some(format!("{}", last_iter));
     for key_id in 0..1000 {
         let key = format!("key{}", key_id);
-        let mut attempts = 10;
-        loop {
-            match client.send_cmd_get(key.clone()) {
-                Err(_) if attempts > 0 => attempts -= 1,
-                Err(e) => panic!("{}", e.to_string()),
-                Ok(recvd_val) => {
-                    assert_eq!(recvd_val, value);
-                    break;
-                }
+        match client.send_cmd_get(key) {
+            Err(e) => panic!("{}", e.to_string()),
+            Ok(recvd_val) => assert_eq!(recvd_val, value),
+        }
+    }
+    server_shutdown_trigger.trigger();
+    server_join_handle
+        .join()
+        .expect("unable to join server thread");
+}
+
+#[test]
+fn batch() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let (server_addr, server_shutdown_trigger, server_join_handle) = start_server(
+        KvStore::open(temp_dir.path()).expect("unable to open database file"),
+        SharedQueueThreadPool::new(2).expect("unable to initialize a thread pool with 2 threads"),
+        |server| server,
+    );
+
+    let client = KvClient::new(server_addr.as_str()).expect("unable to start client");
+    client
+        .send_cmd_set("key0".to_owned(), "value0".to_owned())
+        .expect("unable to set a key");
+    let mut ops = (1..100)
+        .map(|key_id| Op::set(format!("key{}", key_id), vec![key_id as u8, 0xFF]))
+        .collect::<Vec<_>>();
+    ops.push(Op::remove("key0"));
+    client.send_batch(ops).expect("unable to send the batch");
+
+    assert_eq!(client.send_cmd_get("key0".to_owned()).unwrap(), None);
+    for key_id in 1..100 {
+        assert_eq!(
+            client
+                .send_cmd_get_bytes(format!("key{}", key_id).into_bytes())
+                .unwrap(),
+            Some(vec![key_id as u8, 0xFF])
+        );
+    }
+
+    server_shutdown_trigger.trigger();
+    server_join_handle
+        .join()
+        .expect("unable to join server thread");
+}
+
+#[test]
+fn pipeline() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // A single thread serves every connection, as they only hold it while their requests are served
+    let (server_addr, server_shutdown_trigger, server_join_handle) = start_server(
+        KvStore::open(temp_dir.path()).expect("unable to open database file"),
+        SharedQueueThreadPool::new(1).expect("unable to initialize a thread pool with 1 thread"),
+        |server| server.idle_timeout(Duration::from_millis(300)),
+    );
+
+    let client = KvClient::new(server_addr.as_str()).expect("unable to start client");
+    let other_client = KvClient::new(server_addr.as_str()).expect("unable to start client");
+    other_client
+        .send_cmd_set("other".to_owned(), "value".to_owned())
+        .expect("unable to send a command over an idle connection");
+
+    // More commands than the pipeline window, so that some responses are read before all requests are sent
+    let mut pipeline = KvPipeline::new();
+    for key_id in 0..200 {
+        pipeline.set(format!("key{}", key_id), format!("value{}", key_id));
+    }
+    pipeline
+        .get("key0")
+        .rm("key1")
+        .rm("key1")
+        .get("key1")
+        .cas("key2", Some(b"value2".to_vec()), Some(b"new".to_vec()))
+        .cas("key3", None, None)
+        .batch(vec![Op::set("key4", "batched"), Op::remove("key5")]);
+    assert_eq!(pipeline.len(), 207);
+    let replies = client
+        .send_pipeline(pipeline)
+        .expect("unable to send the pipeline");
+    assert_eq!(replies.len(), 207);
+    assert!(replies[..200]
+        .iter()
+        .all(|reply| reply.as_ref().unwrap() == &PipelineReply::Done));
+    assert_eq!(
+        replies[200].as_ref().unwrap(),
+        &PipelineReply::Value(Some(b"value0".to_vec()))
+    );
+    assert_eq!(replies[201].as_ref().unwrap(), &PipelineReply::Done);
+    assert!(matches!(replies[202], Err(KvClientError::KeyNotFound)));
+    assert_eq!(replies[203].as_ref().unwrap(), &PipelineReply::Value(None));
+    assert_eq!(replies[204].as_ref().unwrap(), &PipelineReply::Done);
+    assert!(matches!(
+        &replies[205],
+        Err(KvClientError::CompareAndSwapFailed { current: Some(v) }) if v == b"value3"
+    ));
+    assert_eq!(replies[206].as_ref().unwrap(), &PipelineReply::Done);
+    assert_eq!(
+        client.send_cmd_get("key4".to_owned()).unwrap(),
+        Some("batched".to_owned())
+    );
+
+    // The connections closed by the server once idle are opened again
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(
+        client.send_cmd_get("key2".to_owned()).unwrap(),
+        Some("new".to_owned())
+    );
+    assert_eq!(
+        other_client.send_cmd_get("other".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+
+    server_shutdown_trigger.trigger();
+    server_join_handle
+        .join()
+        .expect("unable to join server thread");
+}
+
+#[test]
+fn connection_pool() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let (server_addr, server_shutdown_trigger, server_join_handle) = start_server(
+        KvStore::open(temp_dir.path()).expect("unable to open database file"),
+        SharedQueueThreadPool::new(2).expect("unable to initialize a thread pool with 2 threads"),
+        |server| server.idle_timeout(Duration::from_millis(300)),
+    );
+
+    // More threads than connections, so that some commands wait for a connection to be released
+    let client = KvClientBuilder::new()
+        .pool_size(2)
+        .build(server_addr.as_str())
+        .expect("unable to start client");
+    let run_threads = |round: usize| {
+        crossbeam_utils::thread::scope(|scope| {
+            for thread_id in 0..8 {
+                let client = &client;
+                scope.spawn(move |_| {
+                    for key_id in 0..50 {
+                        let key = format!("key{}-{}", thread_id, key_id);
+                        let value = format!("value{}-{}", round, key_id);
+                        client
+                            .send_cmd_set(key.clone(), value.clone())
+                            .expect("unable to set a key");
+                        assert_eq!(client.send_cmd_get(key).unwrap(), Some(value));
+                    }
+                });
             }
+        })
+        .expect("a client thread panicked");
+    };
+    run_threads(0);
+
+    // The connections of the pool closed by the server once idle are dropped and opened again
+    thread::sleep(Duration::from_secs(1));
+    run_threads(1);
+    assert_eq!(
+        client.send_cmd_get("key7-49".to_owned()).unwrap(),
+        Some("value1-49".to_owned())
+    );
+
+    server_shutdown_trigger.trigger();
+    server_join_handle
+        .join()
+        .expect("unable to join server thread");
+}
+
+#[test]
+fn timeouts_and_retries() {
+    // The settings of 0 are refused when the client is built
+    assert!(matches!(
+        KvClientBuilder::new().pool_size(0).build("127.0.0.1:4000"),
+        Err(KvClientError::ZeroSetting {
+            setting: "pool size"
+        })
+    ));
+    assert!(matches!(
+        KvClientBuilder::new()
+            .read_timeout(Duration::ZERO)
+            .build("127.0.0.1:4000"),
+        Err(KvClientError::ZeroSetting {
+            setting: "read timeout"
+        })
+    ));
+
+    // A server that accepts the connections but never answers
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("unable to open a listener");
+    let hung_addr = listener.local_addr().unwrap().to_string();
+    let client = KvClientBuilder::new()
+        .read_timeout(Duration::from_millis(200))
+        .retry_policy(RetryPolicy::new(2).initial_backoff(Duration::from_millis(20)))
+        .build(hung_addr.as_str())
+        .expect("unable to start client");
+    match client.send_cmd_get("key".to_owned()) {
+        Err(KvClientError::RetriesExhausted { attempts: 3, last }) => {
+            assert!(matches!(*last, KvClientError::ReadTimeout))
         }
+        res => panic!("unexpected result: {:?}", res),
     }
+    // A set without an idempotency key is never retried
+    assert!(matches!(
+        client.send_cmd_set("key".to_owned(), "value".to_owned()),
+        Err(KvClientError::ReadTimeout)
+    ));
+    let client = KvClientBuilder::new()
+        .read_timeout(Duration::from_millis(200))
+        .retry_policy(RetryPolicy::never())
+        .build(hung_addr.as_str())
+        .expect("unable to start client");
+    assert!(matches!(
+        client.send_cmd_get("key".to_owned()),
+        Err(KvClientError::ReadTimeout)
+    ));
+    drop(listener);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (server_addr, server_shutdown_trigger, server_join_handle) = start_server(
+        KvStore::open(temp_dir.path()).expect("unable to open database file"),
+        SharedQueueThreadPool::new(2).expect("unable to initialize a thread pool with 2 threads"),
+        |server| server,
+    );
+
+    // A set sent again with the same idempotency key is applied only once
+    let client = KvClient::new(server_addr.as_str()).expect("unable to start client");
+    client
+        .send_cmd_set_idempotent("key".to_owned(), "first".to_owned(), "id1".to_owned())
+        .unwrap();
+    client
+        .send_cmd_set("key".to_owned(), "second".to_owned())
+        .unwrap();
+    client
+        .send_cmd_set_idempotent("key".to_owned(), "first".to_owned(), "id1".to_owned())
+        .unwrap();
+    assert_eq!(
+        client.send_cmd_get("key".to_owned()).unwrap(),
+        Some("second".to_owned())
+    );
+    client
+        .send_cmd_set_idempotent("key".to_owned(), "third".to_owned(), "id2".to_owned())
+        .unwrap();
+    assert_eq!(
+        client.send_cmd_get("key".to_owned()).unwrap(),
+        Some("third".to_owned())
+    );
+
+    server_shutdown_trigger.trigger();
+    server_join_handle
+        .join()
+        .expect("unable to join server thread");
+}
+
+#[test]
+fn async_client() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let (server_addr, server_shutdown_trigger, server_join_handle) = start_server(
+        KvStore::open(temp_dir.path()).expect("unable to open database file"),
+        SharedQueueThreadPool::new(2).expect("unable to initialize a thread pool with 2 threads"),
+        |server| server.idle_timeout(Duration::from_millis(300)),
+    );
+
+    let client = AsyncKvClient::new(server_addr.as_str()).expect("unable to start client");
+    futures::executor::block_on(async {
+        // Every command waits for its response at the same time, over the single connection of the client
+        let sets =
+            (0..500).map(|key_id| client.set(format!("key{}", key_id), format!("value{}", key_id)));
+        for res in futures::future::join_all(sets).await {
+            res.expect("unable to set a key");
+        }
+        let gets = (0..500).map(|key_id| client.get(format!("key{}", key_id)));
+        for (key_id, res) in futures::future::join_all(gets)
+            .await
+            .into_iter()
+            .enumerate()
+        {
+            assert_eq!(res.unwrap(), Some(format!("value{}", key_id)));
+        }
+        client.remove("key0".to_owned()).await.unwrap();
+        assert!(matches!(
+            client.remove("key0".to_owned()).await,
+            Err(KvClientError::KeyNotFound)
+        ));
+        assert_eq!(client.get("key0".to_owned()).await.unwrap(), None);
+    });
+
+    // The connection closed by the server once idle is opened again by the next command
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(
+        futures::executor::block_on(client.get("key1".to_owned())).unwrap(),
+        Some("value1".to_owned())
+    );
+    drop(client);
+
     server_shutdown_trigger.trigger();
-    std::thread::sleep(std::time::Duration::from_secs(1));
     server_join_handle
         .join()
         .expect("unable to join server thread");
 }
+
+#[test]
+fn slow_clients() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // A single thread, which only executes the requests, while the event loop reads and writes the connections
+    let (server_addr, server_shutdown_trigger, server_join_handle) = start_server(
+        KvStore::open(temp_dir.path()).expect("unable to open database file"),
+        SharedQueueThreadPool::new(1).expect("unable to initialize a thread pool with 1 thread"),
+        |server| server,
+    );
+
+    // Connections sending only a part of a request, and idle ones
+    let mut slow_streams = (0..20)
+        .map(|_| {
+            let mut stream =
+                TcpStream::connect(server_addr.as_str()).expect("unable to connect to the server");
+            std::io::Write::write_all(&mut stream, &[kvs::cp::PROTOCOL_VERSION, 0x00, 0x00])
+                .expect("unable to write to the server");
+            stream
+        })
+        .collect::<Vec<_>>();
+    let idle_streams = (0..200)
+        .map(|_| TcpStream::connect(server_addr.as_str()))
+        .collect::<Result<Vec<_>, _>>()
+        .expect("unable to connect to the server");
+
+    let client = KvClientBuilder::new()
+        .read_timeout(Duration::from_secs(2))
+        .build(server_addr.as_str())
+        .expect("unable to start client");
+    for key_id in 0..100 {
+        client
+            .send_cmd_set(format!("key{}", key_id), format!("value{}", key_id))
+            .expect("unable to set a key");
+    }
+    assert_eq!(
+        client.send_cmd_get("key99".to_owned()).unwrap(),
+        Some("value99".to_owned())
+    );
+
+    // The rest of the requests of the slow connections is still waited for
+    let request = {
+        let mut buf = Vec::new();
+        let msg = kvs::cp::RequestGet::new_message("key0");
+        buf.resize(kvs::cp::ser::calc_len(&msg).unwrap(), 0);
+        kvs::cp::ser::to_bytes(&msg, &mut buf[..]).unwrap();
+        buf
+    };
+    for stream in slow_streams.iter_mut() {
+        std::io::Write::write_all(stream, &request[3..]).expect("unable to write to the server");
+        let mut response = [0u8; kvs::cp::HEADER_SIZE];
+        std::io::Read::read_exact(stream, &mut response).expect("unable to read the response");
+        assert_eq!(response[0], kvs::cp::PROTOCOL_VERSION);
+    }
+    drop(idle_streams);
+
+    server_shutdown_trigger.trigger();
+    server_join_handle
+        .join()
+        .expect("unable to join server thread");
+}
+
+#[test]
+fn backup() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let backup_root = backup_dir.path().join("backups");
+    std::fs::create_dir(&backup_root).expect("unable to create the backup directory");
+
+    let (server_addr, server_shutdown_trigger, server_join_handle) = start_server(
+        KvStore::open(temp_dir.path()).expect("unable to open database file"),
+        SharedQueueThreadPool::new(2).expect("unable to initialize a thread pool with 2 threads"),
+        |server| server.backup_dir(&backup_root),
+    );
+
+    let client = KvClient::new(server_addr.as_str()).expect("unable to start client");
+    client
+        .send_cmd_set("key".to_owned(), "value".to_owned())
+        .unwrap();
+    client.send_cmd_backup("nightly".to_owned()).unwrap();
+    let restored = KvStore::open(backup_root.join("nightly")).expect("unable to open the backup");
+    assert_eq!(
+        restored.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+
+    // The directories out of the backup directory are refused, and nothing is written to them
+    let escaping = [
+        "../escaped".to_owned(),
+        "nested/../../escaped".to_owned(),
+        backup_dir.path().join("escaped").display().to_string(),
+        String::new(),
+    ];
+    for dest_dir in escaping.iter() {
+        assert!(matches!(
+            client.send_cmd_backup(dest_dir.clone()),
+            Err(KvClientError::ServerError)
+        ));
+    }
+    assert!(!backup_dir.path().join("escaped").exists());
+
+    server_shutdown_trigger.trigger();
+    server_join_handle
+        .join()
+        .expect("unable to join server thread");
+}
+
+/// Starts a server of the `engine`, serving the requests with the threads of the `pool` and set up by `configure`,
+/// on an unused port and waits for it to accept connections.
+/// Returns the address of the server, the trigger to shut it down and the handle of its thread.
+fn start_server<F>(
+    engine: KvStore,
+    pool: SharedQueueThreadPool,
+    configure: F,
+) -> (String, KvServerShutdownTrigger, JoinHandle<()>)
+where
+    F: FnOnce(KvServer<KvStore, SharedQueueThreadPool>) -> KvServer<KvStore, SharedQueueThreadPool>,
+{
+    let server_port = portpicker::pick_unused_port().unwrap();
+    let server_addr = format!("127.0.0.1:{}", server_port);
+    let mut server = configure(
+        KvServer::new(
+            engine,
+            server_addr.as_str(),
+            pool,
+            slog::Logger::root(slog::Discard, o!("" => "")),
+            None,
+        )
+        .expect("unable to start the kvs server"),
+    );
+    let server_shutdown_trigger = server.get_shutdown_trigger();
+
+    let server_join_handle = thread::spawn(move || {
+        server.run().expect("server stopped with an error");
+    });
+    for _ in 0..500 {
+        if TcpStream::connect(server_addr.as_str()).is_ok() {
+            return (server_addr, server_shutdown_trigger, server_join_handle);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("the server does not accept connections on {}", server_addr);
+}